@@ -19,40 +19,35 @@ extern crate osmium;
 #[macro_use] extern crate log;
 extern crate pretty_env_logger;
 
-use osmium::http::{handler, server, request, response, header, status};
+use osmium::http::server;
+use osmium::http2::header;
+use osmium::shared::connection_handle::ConnectionHandle;
+use osmium::shared;
 
-struct FileServerHandler;
+struct FileServer;
 
-impl handler::Handler for FileServerHandler {
-    fn process(&self, req: request::Request) -> response::Response {
-        debug!("Got request: {:?}", req);
+impl shared::server_trait::OsmiumServer for FileServer {
+    type Request = shared::Request;
+    type Response = shared::Response;
 
-        let mut headers = header::Headers::new();
-        headers.add(header::HeaderName::CustomHeader("Server".to_owned()), header::HeaderValue::Str("Osmium".to_owned()));
+    fn process(&self, request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
+        debug!("Got request: {:?}", request);
 
-        match req.uri.as_ref() {
+        let mut response = match request.get_uri() {
             "/index.html" => {
-                headers.add(header::HeaderName::ContentLength, header::HeaderValue::Num(95));
-                headers.add(header::HeaderName::CustomHeader("Content-Type".to_owned()), header::HeaderValue::Str("text/html".to_owned()));
-
-                response::Response {
-                    version: req.version,
-                    status: status::HttpStatus::Ok,
-                    headers: headers,
-                    body: Some("<!DOCTYPE html><html><head><title>osmium</title></head><body><h1>hello world</h1></body></html>".to_owned())
-                }
+                let mut response = shared::Response::new(200);
+                response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
+                response.set_body(String::from("<!DOCTYPE html><html><head><title>osmium</title></head><body><h1>hello world</h1></body></html>").into_bytes());
+                response
             },
             _ => {
-                headers.add(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
-
-                response::Response {
-                    version: req.version,
-                    status: status::HttpStatus::NotFound,
-                    headers: headers,
-                    body: None
-                }
+                shared::Response::new(404)
             }
-        }
+        };
+
+        response.get_headers_mut().push(header::HeaderName::CustomHeader(String::from("Server")), header::HeaderValue::Str(String::from("Osmium")));
+
+        response
     }
 }
 
@@ -61,13 +56,11 @@ fn main() {
 
     info!("File server example begining");
 
-    let settings = server::Settings {
-        host: Some("0.0.0.0".to_owned()),
-        port: Some(8000)
-    };
+    let mut settings = shared::server_settings::ServerSettings::default();
+    settings.set_port(8000);
 
     info!("Starting server...");
     info!("Visit localhost:8000/index.html");
 
-    server::run(FileServerHandler, Some(settings));
+    server::Server::new(FileServer, settings).unwrap().start_server().unwrap();
 }
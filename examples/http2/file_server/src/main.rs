@@ -22,7 +22,7 @@ extern crate chrono;
 
 use std::fs::File;
 use std::io::prelude::*;
-use osmium::http2::{self, net, header, stream as streaming, settings};
+use osmium::http2::{self, net, header, settings};
 use osmium::shared::connection_handle::ConnectionHandle;
 use osmium::shared;
 use chrono::{DateTime, TimeZone, NaiveDateTime, Utc, Local};
@@ -30,187 +30,84 @@ use chrono::prelude::*;
 
 struct MyServer;
 
-#[derive(Debug)]
-struct HttpRequest {
-    pub headers: header::Headers,
-    pub body: Option<String>
+fn push_date(response: &mut shared::Response) {
+    let t = chrono::Local::now();
+    response.get_headers_mut().push(header::HeaderName::Date, header::HeaderValue::Str(
+        format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
+    ));
 }
 
-#[derive(Debug)]
-struct HttpResponse {
-    pub headers: header::Headers,
-    pub body: Option<Vec<u8>>
-}
+impl shared::server_trait::OsmiumServer for MyServer {
+    type Request = shared::Request;
+    type Response = shared::Response;
 
-impl From<streaming::StreamRequest> for HttpRequest {
-    fn from(stream_request: streaming::StreamRequest) -> HttpRequest {
-        let payload = if stream_request.payload.is_some() {
-            Some(String::from_utf8(stream_request.payload.unwrap()).unwrap())
+    fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> Self::Response {
+        let path_to_open = if request.get_uri() == "/" {
+            String::from("site/index.html")
         }
         else {
-            None
+            String::from("site") + request.get_uri()
         };
+        let doc = File::open(path_to_open);
 
-        HttpRequest {
-            headers: stream_request.headers,
-            body: payload
-        }
-    }
-}
+        match doc {
+            Ok(mut doc) => {
+                let mut contents = Vec::new();
+                doc.read_to_end(&mut contents).expect("something went wrong reading the file");
 
-impl From<HttpResponse> for streaming::StreamResponse {
-    fn from(http_response: HttpResponse) -> streaming::StreamResponse {
-        streaming::StreamResponse {
-            informational_headers: Vec::new(),
-            headers: http_response.headers,
-            payload: http_response.body,
-            trailer_headers: None
-        }
-    }
-}
+                let mut response = shared::Response::new(200);
+                response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(contents.len() as i32));
+                response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
+                push_date(&mut response);
+                response.set_body(contents);
 
-impl shared::server_trait::OsmiumServer for MyServer {
-    type Request = HttpRequest;
-    type Response = HttpResponse;
+                response
+            },
+            Err(e) => {
+                warn!("error getting file {:?}", e);
 
-    fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> Self::Response {
-        for header in request.headers.iter() {
-            if header.name == header::HeaderName::PseudoPath {
-                match header.value {
-                    header::HeaderValue::Str(ref path) => {
-                        let path_to_open = if path == "/" {
-                            String::from("site/index.html")
-                        }
-                        else {
-                            String::from("site") + path
-                        };
-                        let doc = File::open(path_to_open);
-
-                        match doc {
-                            Ok(mut doc) => {
-                                let mut contents = Vec::new();
-                                doc.read_to_end(&mut contents).expect("something went wrong reading the file");
-
-                                let mut headers = header::Headers::new();
-                                headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(200));
-                                headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(contents.len() as i32));
-                                headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
-
-                                let t = chrono::Local::now();
-                                headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-                                    format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-                                ));
-
-                                return HttpResponse {
-                                    headers: headers,
-                                    body: Some(contents)
-                                };
-                            },
-                            Err(e) => {
-                                warn!("error getting file {:?}", e);
-
-                                let mut headers = header::Headers::new();
-                                headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(404));
-                                headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
-
-                                let t = chrono::Local::now();
-                                headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-                                    format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-                                ));
-
-                                return HttpResponse {
-                                    headers: headers,
-                                    body: None
-                                };
-                            }
-                        }
-                    },
-                    _ => {
-                        let mut headers = header::Headers::new();
-                        headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(404));
-                        headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
-
-                        let t = chrono::Local::now();
-                        headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-                            format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-                        ));
-
-                        return HttpResponse {
-                            headers: headers,
-                            body: None
-                        };
-                    }
-                }
+                let mut response = shared::Response::new(404);
+                response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
+                push_date(&mut response);
+
+                response
             }
         }
-
-        panic!("should have been handled above");
     }
 }
 
-fn handle_index(handle: Box<&mut ConnectionHandle>) -> HttpResponse {
+fn handle_index(handle: Box<&mut ConnectionHandle>) -> shared::Response {
     if handle.is_push_enabled() {
         println!("push is enabled!");
 
-        let mut headers = header::Headers::new();
-        headers.push(header::HeaderName::PseudoMethod, header::HeaderValue::Str(String::from("GET")));
-        headers.push(header::HeaderName::PseudoAuthority, header::HeaderValue::Str(String::from("localhost:8080")));
-        headers.push(header::HeaderName::PseudoScheme, header::HeaderValue::Str(String::from("https")));
-        headers.push(header::HeaderName::PseudoPath, header::HeaderValue::Str(String::from("/cractal_hexagon_geometric_small.jpg")));
-        
-        let t = chrono::Local::now();
-        headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-            format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-        ));
-
-        let request = streaming::StreamRequest {
-            headers: headers,
-            payload: None,
-            trailer_headers: None
-        };
-
-        handle.push_promise(request);
+        handle.push_promise(shared::Request::new("GET", "https", "localhost:8080", "/cractal_hexagon_geometric_small.jpg"));
     }
     else {
         println!("push is disabled");
     }
 
-    let mut headers = header::Headers::new();
-    headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(200));
-    headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(161));
-    headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
-
-    let t = chrono::Local::now();
-    headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-        format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-    ));
+    let mut response = shared::Response::new(200);
+    response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(161));
+    response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
+    push_date(&mut response);
+    response.set_body(String::from("<!DOCTYPE html><html><head><title>test</title></head><body><h1>Osmium served me like a beast</h1><img src=\"/cractal_hexagon_geometric_small.jpg\" /></body></html>").into_bytes());
 
-    HttpResponse {
-        headers: headers,
-        body: Some(String::from("<!DOCTYPE html><html><head><title>test</title></head><body><h1>Osmium served me like a beast</h1><img src=\"/cractal_hexagon_geometric_small.jpg\" /></body></html>").into_bytes())
-    }
+    response
 }
 
-fn handle_img(handle: Box<&mut ConnectionHandle>) -> HttpResponse {
+fn handle_img(handle: Box<&mut ConnectionHandle>) -> shared::Response {
     let mut f = File::open("./cractal_hexagon_geometric_small.jpg").expect("image file not found");
 
     let mut contents = Vec::new();
     f.read_to_end(&mut contents).expect("something went wrong reading the file");
 
-    let mut headers = header::Headers::new();
-    headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(200));
-    headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(contents.len() as i32));
-    headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("image/jpeg")));
+    let mut response = shared::Response::new(200);
+    response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(contents.len() as i32));
+    response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("image/jpeg")));
+    push_date(&mut response);
+    response.set_body(contents);
 
-    let t = chrono::Local::now();
-    headers.push(header::HeaderName::Date, header::HeaderValue::Str(
-        format!("{} GMT", t.format("%a, %d %b %Y %H:%M:%S").to_string())
-    ));
-
-    HttpResponse {
-        headers: headers,
-        body: Some(contents)
-    }
+    response
 }
 
 fn main() {
@@ -229,5 +126,5 @@ fn main() {
     http2_settings.push(settings::SettingsParameter::new(settings::SettingName::SettingsMaxFrameSize, 16384));
     settings.set_http2_settings(http2_settings);
 
-    net::Server::new(MyServer {}, settings).unwrap().start_server();
+    net::Server::new(MyServer {}, settings).unwrap().start_server().unwrap();
 }
@@ -0,0 +1,501 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::sync::Arc;
+use std::marker;
+use std::convert;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::panic;
+use std::time::{Duration, Instant};
+
+// tokio
+use futures::{Stream, Sink, Future};
+use futures::future::{self, loop_fn};
+use futures::sync::mpsc as futures_mpsc;
+use tokio_core::reactor;
+use tokio_io::io as tokio_io;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+// futures-cpupool
+use futures_cpupool::CpuPool;
+
+// osmium
+use http2::stream as streaming;
+use shared::server_trait;
+use shared::server_settings;
+use shared::shutdown;
+use shared::connection_handle::ConnectionHandle;
+use shared::connection_info::{ConnectionInfo, PeerCertificate};
+use shared::push_error;
+use shared::request as shared_request;
+use shared::timeout::Deadline;
+use super::request;
+use super::response;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+enum ConnectionMessage {
+    Request(request::Request),
+    /// The client is waiting to be told to send the body of the request being read.
+    Continue,
+    /// The request being read is invalid. The error is responded to and the connection closed.
+    Error(request::RequestError)
+}
+
+// What the connection is doing, which is shared between its loops on the event loop to tell when it's idle.
+struct Activity {
+    // When bytes were last read or a response was last finished.
+    last_activity: Cell<Instant>,
+    requests_in_progress: Cell<usize>,
+    // Whether the head of a request has been read, which is when the client has started speaking HTTP/1.1.
+    head_received: Cell<bool>
+}
+
+/// HTTP/1.1 has no server push, so the handle given to the application always reports push as disabled.
+struct Http1ConnectionHandle {
+    connection_info: Arc<ConnectionInfo>
+}
+
+impl ConnectionHandle for Http1ConnectionHandle {
+    fn is_push_enabled(&self) -> bool {
+        false
+    }
+
+    fn push_promise(&mut self, _request: shared_request::Request) -> Option<push_error::PushError> {
+        Some(push_error::PushError::PushDisabled)
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.connection_info.get_server_name()
+    }
+
+    fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.connection_info.get_peer_certificate()
+    }
+}
+
+/// Serve HTTP/1.1 requests on a connection until either side closes it.
+///
+/// The network is read and written on the event loop, and requests are processed by the application on
+/// the workers. Requests are processed one at a time in the order they were received, which is the order
+/// the responses to pipelined requests have to be sent in.
+///
+/// `received` holds any bytes which have already been read from the connection, for example while
+/// working out which protocol the client wants to use. Reading stops while the number of requests waiting
+/// to be processed is at the read queue depth from the server settings.
+///
+/// When the server asks the connection to close, nothing more is read. The requests which have already been
+/// read are responded to, then the connection is closed.
+///
+/// A client which hasn't sent the head of its first request by the preface deadline is disconnected.
+pub fn serve<IO, T, R, S>(io: IO, received: Vec<u8>, scheme: &'static str, connection_info: ConnectionInfo, app: Arc<T>, worker_pool: &CpuPool, settings: &server_settings::ServerSettings, preface_deadline: Option<&Deadline>, tracked_connection: shutdown::TrackedConnection, handle: &reactor::Handle)
+    where IO: 'static + AsyncRead + AsyncWrite,
+          T: 'static + server_trait::AsyncOsmiumServer<Request=R, Response=S> + marker::Sync + marker::Send,
+          R: 'static + convert::From<streaming::StreamRequest>,
+          S: 'static + convert::Into<streaming::StreamResponse>
+{
+    let (reader, writer) = io.split();
+
+    let (shutdown_read_tx, shutdown_read_rx) = futures_mpsc::channel::<u8>(1);
+    let (tx, rx) = futures_mpsc::channel::<ConnectionMessage>(settings.get_read_queue_depth());
+
+    let shutdown::TrackedConnection { close_rx, guard } = tracked_connection;
+    let shutdown_read_future = shutdown_read_rx.map(|_| ()).select(close_rx).into_future();
+
+    let activity = Rc::new(Activity {
+        last_activity: Cell::new(Instant::now()),
+        requests_in_progress: Cell::new(0),
+        head_received: Cell::new(false)
+    });
+
+    if let Some(preface_deadline) = preface_deadline {
+        watch_first_request(preface_deadline, activity.clone(), shutdown_read_tx.clone(), handle);
+    }
+
+    if let Some(idle_timeout) = settings.get_idle_timeout() {
+        watch_idle(idle_timeout, activity.clone(), shutdown_read_tx.clone(), handle);
+    }
+
+    let reader_activity = activity.clone();
+    let reader_loop = loop_fn((reader, request::RequestDecoder::new(scheme, settings.get_max_request_body_size()), received, tx, shutdown_read_future), move |(reader, mut decoder, mut received, to_conn_loop, shutdown_read_future)| -> Box<Future<Item=future::Loop<(), _>, Error=()>> {
+        // Pipelined requests may already be buffered, so hand over every complete request before reading again.
+        let decoded = decoder.decode(&mut received);
+        // A request which has been decoded completely leaves the decoder waiting for the next head.
+        if !decoder.is_waiting_for_head() || decoded.as_ref().map(|request| request.is_some()).unwrap_or(false) {
+            reader_activity.head_received.set(true);
+        }
+
+        match decoded {
+            Ok(Some(request)) => {
+                return Box::new(to_conn_loop.send(ConnectionMessage::Request(request)).then(move |result| {
+                    match result {
+                        Ok(to_conn_loop) => Ok(future::Loop::Continue((reader, decoder, received, to_conn_loop, shutdown_read_future))),
+                        Err(_) => Ok(future::Loop::Break(()))
+                    }
+                }));
+            },
+            Ok(None) => {},
+            Err(e) => {
+                // Nothing more can be read, because the start of the next request can't be found.
+                return Box::new(to_conn_loop.send(ConnectionMessage::Error(e)).then(|result| {
+                    if result.is_err() {
+                        debug!("The connection loop has already stopped, can't respond to the bad request");
+                    }
+                    Ok(future::Loop::Break(()))
+                }));
+            }
+        }
+
+        if decoder.take_continue_expected() {
+            return Box::new(to_conn_loop.send(ConnectionMessage::Continue).then(move |result| {
+                match result {
+                    Ok(to_conn_loop) => Ok(future::Loop::Continue((reader, decoder, received, to_conn_loop, shutdown_read_future))),
+                    Err(_) => Ok(future::Loop::Break(()))
+                }
+            }));
+        }
+
+        let activity = reader_activity.clone();
+        Box::new(
+            tokio_io::read(reader, vec![0; READ_CHUNK_SIZE]).select2(shutdown_read_future).then(move |result| {
+                match result {
+                    Ok(future::Either::A(((reader, buf, count), shutdown_read_future))) => {
+                        if count == 0 {
+                            debug!("HTTP/1.1 connection closed by the remote");
+                            return Ok(future::Loop::Break(()));
+                        }
+
+                        trace!("read [{}] bytes", count);
+                        activity.last_activity.set(Instant::now());
+                        received.extend_from_slice(&buf[..count]);
+
+                        Ok(future::Loop::Continue((reader, decoder, received, to_conn_loop, shutdown_read_future)))
+                    },
+                    Ok(future::Either::B((_, _read_future))) => {
+                        debug!("The internal connection has sent the shutdown signal to the network read loop, nothing more will be read.");
+                        Ok(future::Loop::Break(()))
+                    },
+                    Err(future::Either::A((e, _))) => {
+                        info!("Connection terminated by the remote [{}]", e);
+                        Ok(future::Loop::Break(()))
+                    },
+                    Err(future::Either::B(_)) => {
+                        error!("Network read loop lost connection with the internal connection, will stop reading from the network");
+                        Ok(future::Loop::Break(()))
+                    }
+                }
+            })
+        )
+    });
+
+    handle.spawn(reader_loop);
+
+    // Each message is turned into the bytes to send in response, and whether the connection stays open afterwards.
+    // The next message isn't looked at until the previous response is ready, so the responses stay in order.
+    let worker_pool = worker_pool.clone();
+    let connection_info = Arc::new(connection_info);
+    let responses = rx.and_then(move |msg| -> Box<Future<Item=(Vec<u8>, bool), Error=()>> {
+        match msg {
+            ConnectionMessage::Request(request) => {
+                let version = request.get_version().clone();
+                let is_head = request.is_head();
+                let keep_alive = request.is_keep_alive();
+
+                let app = app.clone();
+                let connection_info = connection_info.clone();
+                let activity = activity.clone();
+                activity.requests_in_progress.set(activity.requests_in_progress.get() + 1);
+                let response_future = worker_pool.spawn_fn(move || {
+                    let process_future = future::lazy(move || {
+                        let mut connection_handle = Http1ConnectionHandle {
+                            connection_info: connection_info
+                        };
+                        app.process(request.into_stream_request().into(), Box::new(&mut connection_handle))
+                            .map(|response| -> streaming::StreamResponse { response.into() })
+                    });
+
+                    // The pool raises a panic again wherever its future is polled, which is the event loop, so the
+                    // panic is turned into a failed response while still on the worker.
+                    panic::AssertUnwindSafe(process_future).catch_unwind().then(|result| {
+                        match result {
+                            Ok(response) => response,
+                            Err(_) => {
+                                error!("The application panicked while processing an HTTP/1.1 request");
+                                Err(())
+                            }
+                        }
+                    })
+                });
+
+                Box::new(response_future.then(move |response| {
+                    activity.requests_in_progress.set(activity.requests_in_progress.get() - 1);
+                    activity.last_activity.set(Instant::now());
+
+                    match response {
+                        Ok(response) => {
+                            Ok((response::encode(response, &version, is_head, keep_alive), keep_alive))
+                        },
+                        Err(_) => {
+                            error!("The application failed to produce a response, the connection will be closed");
+                            Ok((response::encode_error(500), false))
+                        }
+                    }
+                }))
+            },
+            ConnectionMessage::Continue => {
+                Box::new(future::ok((response::CONTINUE_RESPONSE.to_vec(), true)))
+            },
+            ConnectionMessage::Error(e) => {
+                info!("Rejecting HTTP/1.1 request, the connection will be closed [{:?}]", e);
+                Box::new(future::ok((response::encode_error(e.get_status()), false)))
+            }
+        }
+    });
+
+    // The stream of responses ends once the read loop has stopped and every request it read has been handled.
+    let send_loop = loop_fn((writer, responses), |(writer, responses)| {
+        responses.into_future().map_err(|_| ()).and_then(|(response, responses)| {
+            match response {
+                Some((response_bytes, keep_alive)) => {
+                    trace!("will push to network [{:?}]", response_bytes);
+                    future::Either::A(
+                        tokio_io::write_all(writer, response_bytes)
+                            .map(move |(writer, _)| {
+                                if keep_alive {
+                                    future::Loop::Continue((writer, responses))
+                                }
+                                else {
+                                    future::Loop::Break(())
+                                }
+                            })
+                            .map_err(|e| {
+                                info!("Write error, will exit connection loop {:?}", e);
+                            })
+                    )
+                },
+                None => {
+                    future::Either::B(future::ok(future::Loop::Break(())))
+                }
+            }
+        })
+    })
+    .then(move |_: Result<(), ()>| {
+        info!("HTTP/1.1 connection loop ended, about to drop connection");
+
+        match shutdown_read_tx.clone().try_send(1) {
+            Ok(_) => {
+                trace!("Shutdown read loop on connection end");
+            },
+            Err(e) => {
+                debug!("Attempted read loop shutdown but the signal failed to send, the loop already shut down {:?}", e);
+            }
+        }
+
+        drop(guard);
+        Ok(())
+    });
+
+    handle.spawn(send_loop);
+}
+
+// A client which connects without sending a request is closed once the deadline passes, the same as one which doesn't
+// send the HTTP/2 connection preface. Once the head of a request has been read, the idle timeout takes over.
+fn watch_first_request(preface_deadline: &Deadline, activity: Rc<Activity>, shutdown_read_tx: futures_mpsc::Sender<u8>, handle: &reactor::Handle) {
+    let timeout = match preface_deadline.start() {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            error!("Failed to start the preface timeout [{}]", e);
+            return;
+        }
+    };
+
+    handle.spawn(timeout.then(move |_| {
+        if !activity.head_received.get() {
+            info!("The HTTP/1.1 client didn't send a request in time, closing the connection");
+            if shutdown_read_tx.clone().try_send(1).is_err() {
+                debug!("The read loop has already been asked to stop");
+            }
+        }
+
+        Ok(())
+    }));
+}
+
+// A keep-alive connection which isn't processing any requests is closed once nothing has been read from it for
+// the idle timeout. The read loop is stopped, so the connection closes as soon as any responses which are ready
+// have been sent. Checking ends once the read loop has stopped for any reason.
+fn watch_idle(idle_timeout: Duration, activity: Rc<Activity>, shutdown_read_tx: futures_mpsc::Sender<u8>, handle: &reactor::Handle) {
+    let loop_handle = handle.clone();
+    let idle_watch = loop_fn(shutdown_read_tx, move |shutdown_read_tx| {
+        // A request which is being processed will be activity once it's finished, so check again later.
+        let deadline = if activity.requests_in_progress.get() == 0 {
+            activity.last_activity.get() + idle_timeout
+        }
+        else {
+            Instant::now() + idle_timeout
+        };
+
+        let timeout = match reactor::Timeout::new_at(deadline, &loop_handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                error!("Failed to start the idle timeout [{}]", e);
+                return future::Either::A(future::ok(future::Loop::Break(())));
+            }
+        };
+
+        let activity = activity.clone();
+        future::Either::B(timeout.map_err(|_| ()).map(move |_| {
+            if shutdown_read_tx.is_closed() {
+                return future::Loop::Break(());
+            }
+
+            if activity.requests_in_progress.get() == 0 && activity.last_activity.get() + idle_timeout <= Instant::now() {
+                info!("The HTTP/1.1 connection has been idle for too long, closing it");
+                if shutdown_read_tx.clone().try_send(1).is_err() {
+                    debug!("The read loop has already been asked to stop");
+                }
+                return future::Loop::Break(());
+            }
+
+            future::Loop::Continue(shutdown_read_tx)
+        }))
+    });
+
+    handle.spawn(idle_watch);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, Http1ConnectionHandle};
+
+    use std::cell::Cell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use futures::Poll;
+    use futures_cpupool::CpuPool;
+    use tokio_core::reactor;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    use shared::{self, server_trait};
+    use shared::connection_handle::ConnectionHandle;
+    use shared::connection_info::ConnectionInfo;
+    use shared::push_error::PushError;
+    use shared::request::Request;
+    use shared::server_settings::ServerSettings;
+    use shared::shutdown::OpenConnections;
+    use shared::timeout::Deadline;
+    use shared::transport::memory::MemoryTransport;
+
+    struct PanickingServer;
+
+    impl server_trait::OsmiumServer for PanickingServer {
+        type Request = shared::Request;
+        type Response = shared::Response;
+
+        fn process(&self, _request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
+            panic!("the application failed");
+        }
+    }
+
+    #[test]
+    pub fn application_panic_is_internal_server_error() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let open_connections = OpenConnections::new();
+        let (transport, output) = MemoryTransport::new(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+
+        serve(transport, Vec::new(), "http", ConnectionInfo::new(), Arc::new(PanickingServer), &CpuPool::new(1), &ServerSettings::default(), None, open_connections.track(), &event_loop.handle());
+
+        // The event loop carries on, and responds once the worker has caught the panic.
+        let start = Instant::now();
+        while output.borrow().is_empty() && start.elapsed() < Duration::from_secs(10) {
+            event_loop.turn(Some(Duration::from_millis(10)));
+        }
+
+        assert!(output.borrow().starts_with(b"HTTP/1.1 500 "));
+    }
+
+    // A client which connects and then never sends anything. Dropping the transport is closing the connection.
+    struct SilentTransport {
+        closed: Rc<Cell<bool>>
+    }
+
+    impl Read for SilentTransport {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing has been sent"))
+        }
+    }
+
+    impl Write for SilentTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for SilentTransport {}
+
+    impl AsyncWrite for SilentTransport {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    impl Drop for SilentTransport {
+        fn drop(&mut self) {
+            self.closed.set(true);
+        }
+    }
+
+    #[test]
+    pub fn close_connection_without_request_at_preface_deadline() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let open_connections = OpenConnections::new();
+        let closed = Rc::new(Cell::new(false));
+        let preface_deadline = Deadline::new(Duration::from_millis(50), event_loop.handle());
+
+        let start = Instant::now();
+        serve(SilentTransport { closed: closed.clone() }, Vec::new(), "http", ConnectionInfo::new(), Arc::new(PanickingServer), &CpuPool::new(1), &ServerSettings::default(), Some(&preface_deadline), open_connections.track(), &event_loop.handle());
+
+        while !closed.get() && start.elapsed() < Duration::from_secs(10) {
+            event_loop.turn(Some(Duration::from_millis(10)));
+        }
+
+        assert!(closed.get());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    pub fn push_promise_is_refused() {
+        let mut handle = Http1ConnectionHandle {
+            connection_info: Arc::new(ConnectionInfo::new())
+        };
+
+        assert!(!handle.is_push_enabled());
+        match handle.push_promise(Request::new("GET", "http", "localhost", "/pushed")) {
+            Some(PushError::PushDisabled) => {},
+            _ => panic!("expected the push promise to be refused")
+        }
+    }
+}
@@ -10,3 +10,13 @@
 
 // You should have received a copy of the GNU General Public License
 // along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// An HTTP/1.1 server which runs the same applications as the HTTP/2 server. Requests are converted
+// to the form the HTTP/2 server hands to applications, so an `OsmiumServer` implementation does
+// not need to know which protocol a client used.
+
+pub mod status;
+pub mod request;
+pub mod response;
+pub mod connection;
+pub mod server;
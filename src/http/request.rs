@@ -0,0 +1,638 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::mem;
+
+// httparse
+use httparse;
+
+// osmium
+use http2::header;
+use http2::stream as streaming;
+use http_version::HttpVersion;
+
+const MAX_HEADERS: usize = 64;
+
+/// Requests with a head larger than this are rejected rather than buffered indefinitely.
+pub const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+// A chunk size line is a hex number and optional extensions, anything this long is not sensible.
+const MAX_CHUNK_SIZE_LINE: usize = 1024;
+
+/// Headers which only have meaning for the HTTP/1.1 connection they were sent on. These are not
+/// passed on to the application, so that a request looks the same whether it was received over
+/// HTTP/1.1 or HTTP/2 (8.1.2.2). The host header is passed on as the :authority instead.
+pub const CONNECTION_SPECIFIC_HEADERS: &[&str] = &["connection", "upgrade", "http2-settings", "keep-alive", "proxy-connection", "transfer-encoding", "te", "host"];
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The bytes received are not a valid HTTP/1.1 request.
+    Malformed,
+    /// The request line and headers do not fit in `MAX_REQUEST_HEAD_SIZE`.
+    HeadTooLarge,
+    /// The body uses a transfer coding other than chunked, which the server can't decode.
+    UnsupportedTransferEncoding,
+    /// The body is larger than the maximum request body size from the server settings.
+    BodyTooLarge
+}
+
+impl RequestError {
+    /// The status of the response which tells the client why its request was rejected.
+    pub fn get_status(&self) -> u16 {
+        match *self {
+            RequestError::Malformed => 400,
+            RequestError::HeadTooLarge => 431,
+            RequestError::UnsupportedTransferEncoding => 501,
+            RequestError::BodyTooLarge => 413
+        }
+    }
+}
+
+/// A request which has been completely read from an HTTP/1.1 connection.
+#[derive(Debug)]
+pub struct Request {
+    version: HttpVersion,
+    keep_alive: bool,
+    is_head: bool,
+    stream_request: streaming::StreamRequest
+}
+
+impl Request {
+    pub fn get_version(&self) -> &HttpVersion {
+        &self.version
+    }
+
+    /// Whether the connection can be used for another request once this one has been responded to.
+    pub fn is_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Responses to HEAD requests must not have a body.
+    pub fn is_head(&self) -> bool {
+        self.is_head
+    }
+
+    pub fn into_stream_request(self) -> streaming::StreamRequest {
+        self.stream_request
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DecoderState {
+    /// Waiting for a complete request line and headers.
+    Head,
+    /// Reading a body which was sent with a content length. Holds the number of bytes still to read.
+    Body(usize),
+    /// Reading the size line of the next chunk.
+    ChunkSize,
+    /// Reading the data of a chunk. Holds the number of bytes still to read, when that reaches zero the
+    /// line ending after the data is expected.
+    ChunkData(usize),
+    /// Reading the trailer section which ends a chunked body.
+    Trailers
+}
+
+/// Reads requests from the bytes received on an HTTP/1.1 connection.
+///
+/// Bytes are consumed as they are decoded, so the decoder can be given everything which has been
+/// read from the network. Any bytes after a complete request are left for the next call, which is
+/// how pipelined requests are handled.
+pub struct RequestDecoder {
+    scheme: &'static str,
+    max_body_size: usize,
+    state: DecoderState,
+    request: Option<Request>,
+    body: Vec<u8>,
+    continue_expected: bool
+}
+
+impl RequestDecoder {
+    /// Bodies larger than `max_body_size` are rejected rather than buffered.
+    pub fn new(scheme: &'static str, max_body_size: usize) -> Self {
+        RequestDecoder {
+            scheme: scheme,
+            max_body_size: max_body_size,
+            state: DecoderState::Head,
+            request: None,
+            body: Vec::new(),
+            continue_expected: false
+        }
+    }
+
+    /// Whether the decoder is still waiting for the request line and headers of the next request.
+    pub fn is_waiting_for_head(&self) -> bool {
+        match self.state {
+            DecoderState::Head => true,
+            _ => false
+        }
+    }
+
+    /// Try to decode a request from the front of `buf`.
+    ///
+    /// Yields None if more bytes are needed. Once an error has been returned the connection can't
+    /// be read any further, because the start of the next request can't be found.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Request>, RequestError> {
+        loop {
+            match self.state {
+                DecoderState::Head => {
+                    let head = match parse_head(buf, self.scheme)? {
+                        Some(head) => head,
+                        None => {
+                            if buf.len() > MAX_REQUEST_HEAD_SIZE {
+                                return Err(RequestError::HeadTooLarge);
+                            }
+
+                            return Ok(None);
+                        }
+                    };
+
+                    if let BodyLength::Fixed(content_length) = head.body_length {
+                        if content_length > self.max_body_size {
+                            return Err(RequestError::BodyTooLarge);
+                        }
+                    }
+
+                    buf.drain(..head.length);
+                    self.request = Some(head.request);
+
+                    self.state = match head.body_length {
+                        BodyLength::Fixed(0) => {
+                            return Ok(Some(self.finish()));
+                        },
+                        BodyLength::Fixed(content_length) => DecoderState::Body(content_length),
+                        BodyLength::Chunked => DecoderState::ChunkSize
+                    };
+
+                    self.continue_expected = head.expects_continue;
+                },
+                DecoderState::Body(remaining) => {
+                    if buf.len() < remaining {
+                        self.state = DecoderState::Body(remaining - buf.len());
+                        self.body.extend(buf.drain(..));
+                        return Ok(None);
+                    }
+
+                    self.body.extend(buf.drain(..remaining));
+                    return Ok(Some(self.finish()));
+                },
+                DecoderState::ChunkSize => {
+                    match httparse::parse_chunk_size(buf) {
+                        Ok(httparse::Status::Complete((consumed, chunk_size))) => {
+                            // The chunks are added up, because there's no limit on how many the client can send.
+                            if chunk_size > (self.max_body_size - self.body.len()) as u64 {
+                                return Err(RequestError::BodyTooLarge);
+                            }
+
+                            buf.drain(..consumed);
+
+                            self.state = if chunk_size == 0 {
+                                DecoderState::Trailers
+                            }
+                            else {
+                                DecoderState::ChunkData(chunk_size as usize)
+                            };
+                        },
+                        Ok(httparse::Status::Partial) => {
+                            if buf.len() > MAX_CHUNK_SIZE_LINE {
+                                return Err(RequestError::Malformed);
+                            }
+
+                            return Ok(None);
+                        },
+                        Err(_) => {
+                            return Err(RequestError::Malformed);
+                        }
+                    }
+                },
+                DecoderState::ChunkData(0) => {
+                    if buf.len() < 2 {
+                        return Ok(None);
+                    }
+
+                    if &buf[..2] != b"\r\n" {
+                        return Err(RequestError::Malformed);
+                    }
+
+                    buf.drain(..2);
+                    self.state = DecoderState::ChunkSize;
+                },
+                DecoderState::ChunkData(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+
+                    let available = if buf.len() < remaining { buf.len() } else { remaining };
+                    self.body.extend(buf.drain(..available));
+                    self.state = DecoderState::ChunkData(remaining - available);
+                },
+                DecoderState::Trailers => {
+                    let (consumed, trailer_headers) = {
+                        let mut raw_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+
+                        match httparse::parse_headers(buf, &mut raw_headers) {
+                            Ok(httparse::Status::Complete((consumed, raw_trailers))) => {
+                                let mut trailer_headers = header::Headers::new();
+                                for raw_trailer in raw_trailers.iter() {
+                                    let value = match String::from_utf8(raw_trailer.value.to_vec()) {
+                                        Ok(value) => value,
+                                        Err(_) => {
+                                            return Err(RequestError::Malformed);
+                                        }
+                                    };
+
+                                    trailer_headers.push(header::HeaderName::from(raw_trailer.name), header::HeaderValue::Str(value.trim().to_owned()));
+                                }
+
+                                (consumed, trailer_headers)
+                            },
+                            Ok(httparse::Status::Partial) => {
+                                if buf.len() > MAX_REQUEST_HEAD_SIZE {
+                                    return Err(RequestError::HeadTooLarge);
+                                }
+
+                                return Ok(None);
+                            },
+                            Err(_) => {
+                                return Err(RequestError::Malformed);
+                            }
+                        }
+                    };
+
+                    buf.drain(..consumed);
+
+                    if !trailer_headers.is_empty() {
+                        if let Some(ref mut request) = self.request {
+                            request.stream_request.trailer_headers = Some(trailer_headers);
+                        }
+                    }
+
+                    return Ok(Some(self.finish()));
+                }
+            }
+        }
+    }
+
+    /// Whether the client is waiting for a 100 (Continue) response before it sends the body of the
+    /// request currently being decoded (RFC 7231 5.1.1). This only yields true once per request.
+    pub fn take_continue_expected(&mut self) -> bool {
+        let continue_expected = self.continue_expected;
+        self.continue_expected = false;
+        continue_expected
+    }
+
+    fn finish(&mut self) -> Request {
+        self.state = DecoderState::Head;
+        self.continue_expected = false;
+
+        let mut request = self.request.take().expect("a request head has been decoded");
+
+        let body = mem::replace(&mut self.body, Vec::new());
+        if !body.is_empty() {
+            request.stream_request.payload = Some(body);
+        }
+
+        request
+    }
+}
+
+/// Build the headers of a request in the form which the HTTP/2 server gives to the application (8.1.2.3).
+///
+/// The header names should already be lower case. Connection specific headers are dropped and the host
+/// header is used for the :authority.
+pub fn to_http2_headers(method: &str, path: &str, scheme: &str, fields: &[(String, String)]) -> header::Headers {
+    let mut headers = header::Headers::new();
+
+    headers.push(header::HeaderName::PseudoMethod, header::HeaderValue::Str(String::from(method)));
+    headers.push(header::HeaderName::PseudoScheme, header::HeaderValue::Str(String::from(scheme)));
+    headers.push(header::HeaderName::PseudoPath, header::HeaderValue::Str(String::from(path)));
+
+    if let Some(&(_, ref host)) = fields.iter().find(|&&(ref name, _)| name == "host") {
+        headers.push(header::HeaderName::PseudoAuthority, header::HeaderValue::Str(host.trim().to_owned()));
+    }
+
+    for &(ref name, ref value) in fields.iter() {
+        if !CONNECTION_SPECIFIC_HEADERS.contains(&name.as_str()) {
+            headers.push(header::HeaderName::from(name.as_str()), header::HeaderValue::Str(value.trim().to_owned()));
+        }
+    }
+
+    headers
+}
+
+/// Parse the value of a Content-Length header, which must be a non-negative number written only with digits
+/// (RFC 7230 3.3.2).
+pub fn parse_content_length(value: &str) -> Option<usize> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse::<usize>().ok()
+}
+
+enum BodyLength {
+    Fixed(usize),
+    Chunked
+}
+
+struct RequestHead {
+    request: Request,
+    length: usize,
+    body_length: BodyLength,
+    expects_continue: bool
+}
+
+fn parse_head(buf: &[u8], scheme: &str) -> Result<Option<RequestHead>, RequestError> {
+    let mut raw_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut raw_headers);
+
+    let length = match request.parse(buf) {
+        Ok(httparse::Status::Complete(length)) => length,
+        Ok(httparse::Status::Partial) => {
+            return Ok(None);
+        },
+        Err(e) => {
+            debug!("Failed to parse HTTP/1.1 request [{:?}]", e);
+            return Err(RequestError::Malformed);
+        }
+    };
+
+    let mut fields = Vec::with_capacity(request.headers.len());
+    for raw_header in request.headers.iter() {
+        match String::from_utf8(raw_header.value.to_vec()) {
+            Ok(value) => {
+                fields.push((raw_header.name.to_lowercase(), value));
+            },
+            Err(_) => {
+                return Err(RequestError::Malformed);
+            }
+        }
+    }
+
+    let mut connection_close = false;
+    let mut connection_keep_alive = false;
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut expects_continue = false;
+
+    for &(ref name, ref value) in fields.iter() {
+        match name.as_str() {
+            "connection" => {
+                for token in value.split(',') {
+                    let token = token.trim().to_lowercase();
+                    if token == "close" {
+                        connection_close = true;
+                    }
+                    else if token == "keep-alive" {
+                        connection_keep_alive = true;
+                    }
+                }
+            },
+            "content-length" => {
+                let length = match parse_content_length(value) {
+                    Some(length) => length,
+                    None => {
+                        return Err(RequestError::Malformed);
+                    }
+                };
+
+                // (RFC 7230 3.3.2) Differing content lengths can't be resolved.
+                if content_length.is_some() && content_length != Some(length) {
+                    return Err(RequestError::Malformed);
+                }
+
+                content_length = Some(length);
+            },
+            "transfer-encoding" => {
+                if value.trim().to_lowercase() != "chunked" {
+                    return Err(RequestError::UnsupportedTransferEncoding);
+                }
+
+                chunked = true;
+            },
+            "expect" => {
+                expects_continue = value.trim().to_lowercase() == "100-continue";
+            },
+            _ => {}
+        }
+    }
+
+    let version = HttpVersion::from(request.version.unwrap());
+
+    // HTTP/1.1 connections are persistent unless the client says otherwise, HTTP/1.0 connections
+    // have to ask to be kept alive (RFC 7230 6.3).
+    let keep_alive = !connection_close && (version == HttpVersion::Http11 || connection_keep_alive);
+
+    // (RFC 7230 3.3.3) A message with both could be framed differently by a proxy in front of the server, which
+    // would let the rest of the body be read as another request. So it's rejected rather than letting
+    // Transfer-Encoding override Content-Length.
+    if chunked && content_length.is_some() {
+        return Err(RequestError::Malformed);
+    }
+
+    let body_length = if chunked {
+        BodyLength::Chunked
+    }
+    else {
+        BodyLength::Fixed(content_length.unwrap_or(0))
+    };
+
+    let method = request.method.unwrap();
+
+    Ok(Some(RequestHead {
+        request: Request {
+            version: version,
+            keep_alive: keep_alive,
+            is_head: method == "HEAD",
+            stream_request: streaming::StreamRequest {
+                headers: to_http2_headers(method, request.path.unwrap(), scheme, &fields),
+                payload: None,
+                trailer_headers: None
+            }
+        },
+        length: length,
+        body_length: body_length,
+        expects_continue: expects_continue
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestDecoder, RequestError};
+    use http2::header;
+    use http_version::HttpVersion;
+
+    const MAX_BODY_SIZE: usize = 1024;
+
+    #[test]
+    pub fn decode_get() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"GET /index.html HTTP/1.1\r\nHost: localhost:8080\r\nAccept: */*\r\n\r\n".to_vec();
+
+        let request = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(&HttpVersion::Http11, request.get_version());
+        assert!(request.is_keep_alive());
+        assert!(!request.is_head());
+
+        let stream_request = request.into_stream_request();
+        // :method, :scheme, :path, :authority and accept.
+        assert_eq!(5, stream_request.headers.len());
+        assert!(stream_request.headers.iter().any(|h| h.name == header::HeaderName::PseudoAuthority && h.value == header::HeaderValue::Str(String::from("localhost:8080"))));
+        assert!(stream_request.payload.is_none());
+    }
+
+    #[test]
+    pub fn decode_pipelined() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\nGET /c HT".to_vec();
+
+        let first = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Some(b"hello".to_vec()), first.into_stream_request().payload);
+
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+
+        // The third request is incomplete and has to wait for more bytes.
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"TP/1.1\r\n\r\n");
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    pub fn decode_chunked_with_trailers() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n".to_vec();
+
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(b"6;ext=1\r\n world\r\n0\r\nChecksum: abc\r\n\r\n");
+        let stream_request = decoder.decode(&mut buf).unwrap().unwrap().into_stream_request();
+
+        assert_eq!(Some(b"hello world".to_vec()), stream_request.payload);
+        assert_eq!(1, stream_request.trailer_headers.unwrap().len());
+        // The transfer-encoding header only applies to this connection.
+        assert_eq!(3, stream_request.headers.len());
+    }
+
+    #[test]
+    pub fn decode_expect_continue() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"PUT /file HTTP/1.1\r\nContent-Length: 3\r\nExpect: 100-continue\r\n\r\n".to_vec();
+
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(decoder.take_continue_expected());
+        assert!(!decoder.take_continue_expected());
+
+        buf.extend_from_slice(b"abc");
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    pub fn decode_http10_keep_alive() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+
+        let mut buf = b"GET / HTTP/1.0\r\n\r\n".to_vec();
+        assert!(!decoder.decode(&mut buf).unwrap().unwrap().is_keep_alive());
+
+        let mut buf = b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n".to_vec();
+        assert!(decoder.decode(&mut buf).unwrap().unwrap().is_keep_alive());
+
+        let mut buf = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n".to_vec();
+        assert!(!decoder.decode(&mut buf).unwrap().unwrap().is_keep_alive());
+    }
+
+    #[test]
+    pub fn decode_rejects_unknown_transfer_encoding() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n".to_vec();
+
+        match decoder.decode(&mut buf) {
+            Err(RequestError::UnsupportedTransferEncoding) => {},
+            _ => {
+                panic!("expected unsupported transfer encoding");
+            }
+        }
+    }
+
+    #[test]
+    pub fn decode_rejects_oversized_content_length() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST / HTTP/1.1\r\nContent-Length: 1025\r\nExpect: 100-continue\r\n\r\n".to_vec();
+
+        // The request is rejected before the client is asked to send the body.
+        match decoder.decode(&mut buf) {
+            Err(RequestError::BodyTooLarge) => {},
+            _ => {
+                panic!("expected body too large");
+            }
+        }
+        assert_eq!(413, RequestError::BodyTooLarge.get_status());
+    }
+
+    #[test]
+    pub fn decode_rejects_oversized_chunked_body() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+
+        // Each chunk fits, but together they're too large.
+        for _ in 0..4 {
+            buf.extend_from_slice(b"100\r\n");
+            buf.extend_from_slice(&[b'a'; 0x100]);
+            buf.extend_from_slice(b"\r\n");
+        }
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"1\r\n");
+        match decoder.decode(&mut buf) {
+            Err(RequestError::BodyTooLarge) => {},
+            _ => {
+                panic!("expected body too large");
+            }
+        }
+    }
+
+    #[test]
+    pub fn decode_rejects_content_length_with_transfer_encoding() {
+        let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+        let mut buf = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".to_vec();
+
+        match decoder.decode(&mut buf) {
+            Err(RequestError::Malformed) => {},
+            _ => {
+                panic!("expected malformed");
+            }
+        }
+    }
+
+    #[test]
+    pub fn decode_rejects_content_length_which_is_not_digits() {
+        for content_length in &["+5", "-5", "5 5", "0x5", ""] {
+            let mut decoder = RequestDecoder::new("http", MAX_BODY_SIZE);
+            let mut buf = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello", content_length).into_bytes();
+
+            match decoder.decode(&mut buf) {
+                Err(RequestError::Malformed) => {},
+                _ => {
+                    panic!("expected malformed for [{}]", content_length);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// osmium
+use http2::header;
+use http2::stream as streaming;
+use http_version::HttpVersion;
+use super::status;
+
+/// Tells a client which sent `Expect: 100-continue` that it should send the request body.
+pub const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// The server decides how the message is framed and whether the connection persists, so these are
+// not copied from the application's response.
+const FRAMING_HEADERS: &[&str] = &["connection", "keep-alive", "transfer-encoding", "upgrade"];
+
+/// Serialise a response to be sent on an HTTP/1.1 connection.
+///
+/// The status line is built from the :status header. The body is sent with a content length unless
+/// there are trailers, which can only be sent at the end of a chunked body. Trailers are dropped for
+/// HTTP/1.0 clients because they don't understand chunked encoding.
+pub fn encode(response: streaming::StreamResponse, request_version: &HttpVersion, is_head: bool, keep_alive: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    // (RFC 7231 6.2) Interim responses must not be sent to HTTP/1.0 clients.
+    if *request_version != HttpVersion::Http10 {
+        for informational_headers in response.informational_headers.iter() {
+            write_status_line(&mut buf, get_status(informational_headers).unwrap_or(100));
+            write_headers(&mut buf, informational_headers, false);
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+
+    let status = match get_status(&response.headers) {
+        Some(status) => status,
+        None => {
+            error!("The response does not have a valid :status header, will respond with an internal server error");
+            500
+        }
+    };
+
+    write_status_line(&mut buf, status);
+
+    // (RFC 7230 3.3) These responses never have a body, whatever their headers say.
+    let has_body = !is_head && status >= 200 && status != 204 && status != 304;
+    let chunked = has_body && response.trailer_headers.is_some() && *request_version != HttpVersion::Http10;
+
+    let has_content_length = write_headers(&mut buf, &response.headers, chunked);
+
+    if !keep_alive {
+        buf.extend_from_slice(b"Connection: close\r\n");
+    }
+    else if *request_version == HttpVersion::Http10 {
+        buf.extend_from_slice(b"Connection: keep-alive\r\n");
+    }
+
+    let payload = response.payload.unwrap_or(Vec::new());
+
+    if chunked {
+        buf.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+
+        if !payload.is_empty() {
+            buf.extend_from_slice(format!("{:x}\r\n", payload.len()).as_bytes());
+            buf.extend_from_slice(&payload);
+            buf.extend_from_slice(b"\r\n");
+        }
+
+        buf.extend_from_slice(b"0\r\n");
+        if let Some(ref trailer_headers) = response.trailer_headers {
+            write_headers(&mut buf, trailer_headers, true);
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+    else {
+        // The length is needed to find the end of the response on a persistent connection. A response to
+        // a HEAD request may still have one, as long as the application provided the body it would have sent.
+        if !has_content_length && (has_body || (is_head && !payload.is_empty())) {
+            buf.extend_from_slice(format!("Content-Length: {}\r\n", payload.len()).as_bytes());
+        }
+
+        buf.extend_from_slice(b"\r\n");
+
+        if has_body {
+            buf.extend_from_slice(&payload);
+        }
+    }
+
+    buf
+}
+
+/// A response to a request which could not be read. The connection is always closed after it is sent.
+pub fn encode_error(status: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    write_status_line(&mut buf, status);
+    buf.extend_from_slice(b"Content-Length: 0\r\nConnection: close\r\n\r\n");
+
+    buf
+}
+
+fn get_status(headers: &header::Headers) -> Option<u16> {
+    let status_header = match headers.iter().find(|h| h.name == header::HeaderName::PseudoStatus) {
+        Some(status_header) => status_header,
+        None => {
+            return None;
+        }
+    };
+
+    let status = match status_header.value {
+        header::HeaderValue::Num(status) => status as u16,
+        header::HeaderValue::Str(ref status) => {
+            match status.parse::<u16>() {
+                Ok(status) => status,
+                Err(_) => {
+                    return None;
+                }
+            }
+        }
+    };
+
+    if status >= 100 && status < 1000 {
+        Some(status)
+    }
+    else {
+        None
+    }
+}
+
+fn write_status_line(buf: &mut Vec<u8>, status: u16) {
+    buf.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status, status::reason_phrase(status)).as_bytes());
+}
+
+// Writes every header which can be sent as it is and reports whether one of them was the content length.
+fn write_headers(buf: &mut Vec<u8>, headers: &header::Headers, skip_content_length: bool) -> bool {
+    let mut has_content_length = false;
+
+    for header in headers.iter() {
+        let name: String = header.name.clone().into();
+        let lower_case_name = name.to_lowercase();
+
+        if name.starts_with(':') || FRAMING_HEADERS.contains(&lower_case_name.as_str()) {
+            continue;
+        }
+
+        if lower_case_name == "content-length" {
+            // (RFC 7230 3.3.2) A content length must not be sent with a transfer encoding.
+            if skip_content_length {
+                continue;
+            }
+
+            has_content_length = true;
+        }
+
+        buf.extend_from_slice(format!("{}: {}\r\n", name, header.value).as_bytes());
+    }
+
+    has_content_length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, encode_error};
+    use http2::header;
+    use http2::stream as streaming;
+    use http_version::HttpVersion;
+
+    fn new_response(status: i32, payload: Option<Vec<u8>>, trailer_headers: Option<header::Headers>) -> streaming::StreamResponse {
+        let mut headers = header::Headers::new();
+        headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(status));
+        headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/plain")));
+
+        streaming::StreamResponse {
+            informational_headers: Vec::new(),
+            headers: headers,
+            payload: payload,
+            trailer_headers: trailer_headers
+        }
+    }
+
+    #[test]
+    pub fn encode_with_content_length() {
+        let encoded = encode(new_response(200, Some(b"hello".to_vec()), None), &HttpVersion::Http11, false, true);
+
+        assert_eq!(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello".to_vec(), encoded);
+    }
+
+    #[test]
+    pub fn encode_chunked_with_trailers() {
+        let mut trailer_headers = header::Headers::new();
+        trailer_headers.push(header::HeaderName::from("checksum"), header::HeaderValue::Str(String::from("abc")));
+
+        let encoded = encode(new_response(200, Some(b"hello".to_vec()), Some(trailer_headers)), &HttpVersion::Http11, false, true);
+
+        assert_eq!(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nchecksum: abc\r\n\r\n".to_vec(), encoded);
+    }
+
+    #[test]
+    pub fn encode_head_without_body() {
+        let encoded = encode(new_response(200, Some(b"hello".to_vec()), None), &HttpVersion::Http10, true, false);
+
+        assert_eq!(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\nContent-Length: 5\r\n\r\n".to_vec(), encoded);
+    }
+
+    #[test]
+    pub fn encode_bad_request() {
+        assert_eq!(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(), encode_error(400));
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::sync::Arc;
+use std::marker;
+use std::convert;
+use std::io;
+
+// tokio
+use futures::{Stream, Future};
+use futures::future;
+use tokio_core;
+
+// osmium
+use http2::stream as streaming;
+use shared::server_trait;
+use shared::server_settings;
+use shared::listener;
+use shared::shutdown;
+use shared::server_handle;
+use shared::connection_info::ConnectionInfo;
+use shared::timeout::Deadline;
+use super::connection;
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// This server only accepts cleartext connections.
+    SecurityNotSupported,
+    InvalidServerSettings(server_settings::SettingsError),
+    /// The server couldn't listen on the configured address, or its event loop couldn't be created.
+    StartFailed(io::Error)
+}
+
+/// A server for HTTP/1.1 clients, which processes requests with the same application trait as the HTTP/2 server.
+pub struct Server<T, R, S>
+    where T: server_trait::AsyncOsmiumServer<Request=R, Response=S>,
+          R: convert::From<streaming::StreamRequest>,
+          S: convert::Into<streaming::StreamResponse>
+{
+    app: Arc<T>,
+    server_settings: server_settings::ServerSettings,
+    shutdown_handle: shutdown::ShutdownHandle,
+    shutdown_receiver: Option<shutdown::ShutdownReceiver>
+}
+
+impl<T, R, S> Server<T, R, S>
+    where T: 'static + server_trait::AsyncOsmiumServer<Request=R, Response=S> + marker::Sync + marker::Send,
+          R: 'static + convert::From<streaming::StreamRequest>,
+          S: 'static + convert::Into<streaming::StreamResponse>
+{
+    pub fn new(app: T, server_settings: server_settings::ServerSettings) -> Result<Self, ServerError> {
+        if server_settings.get_listeners().iter().any(|listener| listener.is_secure()) {
+            return Err(ServerError::SecurityNotSupported);
+        }
+
+        server_settings.validate().map_err(ServerError::InvalidServerSettings)?;
+
+        let (shutdown_handle, shutdown_receiver) = shutdown::new_shutdown_channel();
+
+        Ok(Server {
+            app: Arc::new(app),
+            server_settings: server_settings,
+            shutdown_handle: shutdown_handle,
+            shutdown_receiver: Some(shutdown_receiver)
+        })
+    }
+
+    /// Get a handle which can be used to shut the server down gracefully once it has been started.
+    pub fn shutdown_handle(&self) -> shutdown::ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// Run the server until it is shut down using a shutdown handle. If the server can't listen on one of its
+    /// addresses it fails straight away instead.
+    pub fn start_server(self) -> Result<(), ServerError> {
+        // tokio event loop
+        let event_loop = tokio_core::reactor::Core::new().map_err(ServerError::StartFailed)?;
+
+        // create the listeners for incoming connections
+        let listeners = listener::bind(&self.server_settings, &event_loop.handle()).map_err(ServerError::StartFailed)?;
+
+        self.run(event_loop, listeners);
+        Ok(())
+    }
+
+    /// Start the server on a background thread. This returns once the server is listening, with a handle
+    /// which has the addresses it is listening on and can be used to stop it.
+    pub fn spawn_server(self) -> Result<server_handle::ServerHandle, ServerError> {
+        let listeners = listener::bind_all(&self.server_settings).map_err(ServerError::StartFailed)?;
+        let shutdown_handle = self.shutdown_handle();
+
+        server_handle::spawn(listeners, shutdown_handle, move |event_loop, listeners| {
+            self.run(event_loop, listeners);
+        }).map_err(ServerError::StartFailed)
+    }
+
+    fn run(mut self, mut event_loop: tokio_core::reactor::Core, listeners: Vec<listener::Listener>) {
+        let handle = event_loop.handle();
+
+        let worker_pool = listener::new_worker_pool(&self.server_settings);
+
+        let shutdown_receiver = self.shutdown_receiver.take().unwrap();
+        let open_connections = shutdown::OpenConnections::new();
+
+        let preface_deadline = self.server_settings.get_preface_timeout().map(|timeout| Deadline::new(timeout, handle.clone()));
+
+        let server_instance = &self;
+        let worker_pool = &worker_pool;
+        let open_connections = &open_connections;
+        let handle = &handle;
+        let preface_deadline = &preface_deadline;
+        let accepts: Vec<_> = listeners.into_iter().map(|listener| {
+            listener.incoming(handle).for_each(move |(socket, _remote_addr)| {
+                debug!("Starting HTTP/1.1 connection on {}", _remote_addr);
+
+                if let Err(e) = listener::configure_connection(&socket, &server_instance.server_settings) {
+                    error!("Failed to configure the connection socket [{}]", e);
+                }
+
+                connection::serve(socket, Vec::new(), "http", ConnectionInfo::new(), server_instance.app.clone(), worker_pool, &server_instance.server_settings, preface_deadline.as_ref(), open_connections.track(), handle);
+
+                Ok(())
+            })
+            .or_else(|e| {
+                // The other listeners carry on.
+                error!("A listener has stopped accepting connections [{}]", e);
+                Ok::<(), io::Error>(())
+            })
+        }).collect();
+
+        let server = future::join_all(accepts).map(|_| ());
+
+        // move the incoming connection stream onto the event loop
+        shutdown::run_until_shutdown(&mut event_loop, server, shutdown_receiver, &open_connections);
+    }
+}
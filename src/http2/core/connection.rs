@@ -16,36 +16,50 @@
 // along with Osmium. If not, see <http://www.gnu.org/licenses/>.
 
 // std
-use std::collections::{VecDeque, hash_map, HashMap};
-use std::convert;
+use std::cmp;
+use std::collections::{VecDeque, HashMap};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 // osmium
 use http2::frame as framing;
 use http2::error;
 use http2::stream::{self as streaming, StreamId, CONNECTION_CONTROL_STREAM_ID};
 use http2::hpack::context as hpack_context;
-use shared::server_trait;
 use http2::settings;
 use http2::net::shutdown_signal;
+use http2::net::h2handshake;
 use http2::core::connection_frame_state;
 use http2::core::stream_blocker;
 use http2::core::connection_shared_state;
 use http2::core::flow_control;
 use http2::frame::check as frame_checking;
 
-pub struct Connection<'a> {
+// (3.2) The request which is upgraded from HTTP/1.1 is always on stream 1.
+const UPGRADE_STREAM_ID: StreamId = 0x1;
+
+/// A request which has been fully received and can be passed to the application.
+pub struct ReadyRequest {
+    pub stream_id: StreamId,
+    pub request: streaming::StreamRequest,
+    /// Whether the application may push promise in response to this request.
+    pub push_enabled: bool,
+    /// How many more streams may be pushed before reaching the client's limit on concurrent streams, if it has one.
+    pub max_push_promises: Option<usize>
+}
+
+pub struct Connection {
     send_frames: VecDeque<Vec<u8>>,
     frame_state_validator: connection_frame_state::ConnectionFrameStateValidator,
 
-    hpack_send_context: hpack_context::SendContext<'a>,
-    hpack_recv_context: hpack_context::RecvContext<'a>,
+    hpack_send_context: hpack_context::SendContext,
+    hpack_recv_context: hpack_context::RecvContext,
 
     streams: HashMap<StreamId, streaming::Stream>,
     stream_blocker: stream_blocker::StreamBlocker,
 
-    promised_streams_queue: VecDeque<StreamId>,
+    ready_requests: VecDeque<ReadyRequest>,
 
     connection_shared_state: Rc<RefCell<connection_shared_state::ConnectionSharedState>>,
 
@@ -54,19 +68,44 @@ pub struct Connection<'a> {
     shutdown_initiated: bool,
     shutdown_signaller: shutdown_signal::ShutdownSignaller,
 
+    // The last stream identifier sent on a GOAWAY to gracefully shut down the connection.
+    go_away_last_stream_id: Option<StreamId>,
+
+    upgrade_pending: bool,
+
+    // A stream which is over the limit on concurrent streams. It's refused once its header block has been received,
+    // so that the header compression context stays in sync. Header blocks can't be interleaved, so there's only one.
+    refusing_stream: Option<StreamId>,
+
+    // The local settings which have been sent but not acknowledged yet, oldest first. The client acknowledges them in
+    // the order they were sent, and they only take effect once it has (6.5.3). Until then the previous settings are
+    // enforced, starting from the defaults because the handshake sent the first ones.
+    pending_local_settings: VecDeque<settings::Settings>,
+    // How many local settings frames the client has acknowledged, which identifies the next one it will acknowledge.
+    local_settings_acknowledged: usize,
+
+    // The pings which haven't been acknowledged yet, oldest first, with when they were sent.
+    pending_pings: VecDeque<([u8; 8], Instant)>,
+    next_ping_payload: u64,
+    round_trip_time: Option<Duration>,
+
     send_window: u32,
     receive_window: u32
 }
 
-impl<'a> Connection<'a> {
+impl Connection {
     pub fn new(
-        hpack_send_context: hpack_context::SendContext<'a>,
-        hpack_recv_context: hpack_context::RecvContext<'a>,
+        hpack_send_context: hpack_context::SendContext,
+        hpack_recv_context: hpack_context::RecvContext,
         initial_local_settings: settings::Settings,
         initial_remote_settings_frame: framing::settings::SettingsFrame,
+        upgrade_request: Option<h2handshake::UpgradeRequest>,
         shutdown_signaller: shutdown_signal::ShutdownSignaller
-    ) -> Connection<'a>
+    ) -> Connection
     {
+        let mut pending_local_settings = VecDeque::new();
+        pending_local_settings.push_back(initial_local_settings);
+
         let mut new_con = Connection {
             send_frames: VecDeque::new(),
             frame_state_validator: connection_frame_state::ConnectionFrameStateValidator::new(),
@@ -74,27 +113,71 @@ impl<'a> Connection<'a> {
             hpack_recv_context: hpack_recv_context,
             streams: HashMap::new(),
             stream_blocker: stream_blocker::StreamBlocker::new(),
-            promised_streams_queue: VecDeque::new(),
-            connection_shared_state: Rc::new(RefCell::new(connection_shared_state::ConnectionSharedState::new(initial_local_settings))),
+            ready_requests: VecDeque::new(),
+            connection_shared_state: Rc::new(RefCell::new(connection_shared_state::ConnectionSharedState::new(settings::Settings::spec_default()))),
             highest_remote_initiated_stream_identifier: 0,
             shutdown_initiated: false,
             shutdown_signaller: shutdown_signaller,
+            go_away_last_stream_id: None,
+            upgrade_pending: false,
+            refusing_stream: None,
+            pending_local_settings: pending_local_settings,
+            local_settings_acknowledged: 0,
+            pending_pings: VecDeque::new(),
+            next_ping_payload: 0,
+            round_trip_time: None,
             send_window: settings::INITIAL_FLOW_CONTROL_WINDOW_SIZE,
             receive_window: settings::INITIAL_FLOW_CONTROL_WINDOW_SIZE
         };
 
-        // TODO The ONLY time when ack is not required is when a 101 switching protocols is sent.
-        // Switching to true for now, and need to tidy up later.
+        if let Some(upgrade_request) = upgrade_request {
+            // (3.2.1) The 101 switching protocols response is an implicit acknowledgement of the
+            // settings in the upgrade request, so they are applied without sending an acknowledge.
+            new_con.apply_settings(upgrade_request.settings_frame, false);
+
+            // (3.2) The HTTP/1.1 request that is sent prior to upgrade is assigned a stream identifier 
+            // of 1 with default priority values. Stream 1 is implicitly "half-closed" from the client 
+            // toward the server, since the request is completed as an HTTP/1.1 request.
+            new_con.highest_remote_initiated_stream_identifier = UPGRADE_STREAM_ID;
+            new_con.streams.insert(
+                UPGRADE_STREAM_ID,
+                streaming::Stream::new_upgrade(UPGRADE_STREAM_ID, new_con.connection_shared_state.clone(), upgrade_request.request)
+            );
+            new_con.upgrade_pending = true;
+        }
+
+        // The settings which followed the client connection preface always need to be acknowledged.
         new_con.apply_settings(initial_remote_settings_frame, true);
 
         new_con
     }
 
-    pub fn recv<T, R, S>(&mut self, frame: framing::Frame, app: &T)
-        where T: server_trait::OsmiumServer<Request=R, Response=S>,
-              R: convert::From<streaming::StreamRequest>,
-              S: convert::Into<streaming::StreamResponse>
-    {
+    /// Reset the streams whose request body grows larger than this, rather than receiving the rest of it.
+    pub fn set_max_request_body_size(&mut self, max_request_body_size: usize) {
+        self.connection_shared_state.borrow_mut().max_request_body_size = Some(max_request_body_size);
+    }
+
+    /// Responds to the request which was upgraded from HTTP/1.1, if there was one. This must be called 
+    /// before any frames are received on the connection.
+    pub fn execute_upgrade(&mut self) {
+        if !self.upgrade_pending || self.shutdown_initiated {
+            return;
+        }
+        self.upgrade_pending = false;
+
+        let ready_request = {
+            let stream = self.streams.get_mut(&UPGRADE_STREAM_ID).unwrap();
+
+            stream.recv_upgrade();
+            stream.fetch_ready_request()
+        };
+
+        if let Some(request) = ready_request {
+            self.queue_ready_request(UPGRADE_STREAM_ID, request);
+        }
+    }
+
+    pub fn recv(&mut self, frame: framing::Frame) {
         log_conn_frame!("Receive frame", frame);
 
         // This is slightly untidy, and is essentially a side effect of not having exceptions in Rust. The read write loop in the
@@ -154,10 +237,7 @@ impl<'a> Connection<'a> {
                 match ping_frame_result {
                     Ok(ping_frame) => {
                         if framing::ping::is_acknowledge(frame.header.flags) {
-                            // TODO the server has no way of managing the connection thread. That is, the thread is only
-                            // active when frames are received which means the connection is active and there's no point
-                            // sending a ping.
-                            error!("can't handle ping response");
+                            self.recv_ping_acknowledge(ping_frame.get_payload());
                         }
                         else {
                             // TODO add a second constructor method which builds a response.
@@ -186,7 +266,7 @@ impl<'a> Connection<'a> {
                     return;
                 }
 
-                self.move_to_stream(frame_type, frame, app);
+                self.move_to_stream(frame_type, frame);
             },
             framing::FrameType::Continuation => {
                 // (6.2) A CONTINUATION frame which is not associated with a stream is a connection error of type PROTOCOL_ERROR
@@ -198,7 +278,7 @@ impl<'a> Connection<'a> {
                     return;
                 }
 
-                self.move_to_stream(frame_type, frame, app);
+                self.move_to_stream(frame_type, frame);
             },
             framing::FrameType::Data => {
                 if streaming::is_connection_control_stream_id(frame.header.stream_id) {
@@ -210,7 +290,7 @@ impl<'a> Connection<'a> {
                 }
 
                 self.handle_flow_control_for_recv(frame.header.length);
-                self.move_to_stream(frame_type, frame, app);
+                self.move_to_stream(frame_type, frame);
             },
             framing::FrameType::WindowUpdate => {
                 if streaming::is_connection_control_stream_id(frame.header.stream_id) {
@@ -231,7 +311,8 @@ impl<'a> Connection<'a> {
                     }
                 }
                 else {
-                    self.move_to_stream(frame_type, frame, app);
+                    self.move_to_stream(frame_type, frame);
+                    self.try_unblock_streams();
                 }
             },
             framing::FrameType::Priority => {
@@ -257,11 +338,16 @@ impl<'a> Connection<'a> {
                     return;
                 }
 
-                // TODO handle decode error
-                let settings_frame = framing::settings::SettingsFrame::new(&frame.header, &mut frame.payload.into_iter()).unwrap();
+                let settings_frame = match framing::settings::SettingsFrame::new(&frame.header, &mut frame.payload.into_iter()) {
+                    Ok(settings_frame) => settings_frame,
+                    Err(e) => {
+                        self.shutdown_connection(e);
+                        return;
+                    }
+                };
 
                 if settings_frame.is_acknowledge() {
-                    // TODO handle ack received
+                    self.recv_settings_acknowledge();
                 }
                 else {
                     self.apply_settings(settings_frame, true);
@@ -270,10 +356,11 @@ impl<'a> Connection<'a> {
             framing::FrameType::GoAway => {
                 let go_away_frame = framing::go_away::GoAwayFrame::new(&frame.header, &mut frame.payload.into_iter());
 
-                println!("go away frame received from client {:?}", go_away_frame);
+                info!("Go away frame received from client, nothing more will be read {:?}", go_away_frame);
 
-                // TODO handle connection shutdown.
-                panic!("will crash; did not expect go away from client");
+                // TODO handle connection shutdown properly. The client won't open any more streams, but the 
+                // requests which are already being processed can still be responded to.
+                self.shutdown_signaller.signal_shutdown();
             },
             framing::FrameType::ResetStream => {
                 if streaming::is_connection_control_stream_id(frame.header.stream_id) {
@@ -284,148 +371,49 @@ impl<'a> Connection<'a> {
                     return;
                 }
 
-                self.move_to_stream(frame_type, frame, app);
+                self.move_to_stream(frame_type, frame);
             }
-            _ => {
-                panic!("can't handle that frame type yet {:?}", frame_type);
+            framing::FrameType::PushPromise => {
+                // (8.2) A client cannot push. Thus, servers MUST treat the receipt of a PUSH_PROMISE frame as a 
+                // connection error (Section 5.4.1) of type PROTOCOL_ERROR.
+                self.shutdown_connection(error::HttpError::ConnectionError(
+                    error::ErrorCode::ProtocolError,
+                    error::ErrorName::CannotPushToServer
+                ));
             }
         }
     }
 
-    pub fn execute_promised<T, R, S>(&mut self, app: &T) -> bool
-        where T: server_trait::OsmiumServer<Request=R, Response=S>, 
-              R: convert::From<streaming::StreamRequest>,
-              S: convert::Into<streaming::StreamResponse>
-    {
-        if self.shutdown_initiated {
-            info!("Connection is shutting down, so any remaining promises will be ignored");
-            // TODO document this boolean return value.
-            // Tell the caller there are no more promises to execute.
-            return false;
-        }
-
-        if let Some(promised_stream_id) = self.promised_streams_queue.pop_back() {
-            let mut temp_streams = Vec::new();
-            {
-                let stream = self.streams.entry(promised_stream_id);
-
-                match stream {
-                    hash_map::Entry::Occupied(mut stream) => {
-                        let stream = stream.get_mut();
-
-                        stream.recv_promised(&mut self.hpack_send_context, app);
-
-                        while let Some((promised_stream_id, stream_request)) = stream.fetch_push_promise() {
-                            let promise_stream = streaming::Stream::new_promise(promised_stream_id, self.connection_shared_state.clone(), stream_request);
+    fn move_to_stream(&mut self, frame_type: framing::FrameType, frame: framing::Frame) {
+        let stream_id = frame.header.stream_id;
+        let is_reset_stream = frame_type == framing::FrameType::ResetStream;
 
-                            temp_streams.push((promised_stream_id, promise_stream));
-                            self.promised_streams_queue.push_front(promised_stream_id);
-                        }
+        let result = self.do_move_to_stream(frame_type, stream_id, frame);
 
-                        // TODO duplicated code
-                        // Fetch any send frames which have been generated on the stream.
-                        let mut is_blocked = self.stream_blocker.is_blocking(promised_stream_id);
-                        let stream_frames = stream.fetch_send_frames();
-                        let mut stream_frame_iter = stream_frames.into_iter().rev();
-                        while let Some(frame) = stream_frame_iter.next() {
-                            match frame.get_frame_type() {
-                                framing::FrameType::Data => {
-                                    if is_blocked {
-                                        self.stream_blocker.block_frame(promised_stream_id, frame);
-                                    }
-                                    else {
-                                        // TODO which is the correct type? because it is a size, it really should be unsigned.
-                                        if frame.get_length() as u32 > self.send_window {
-                                            // Must not send, block the stream.
-                                            self.stream_blocker.block_frame(promised_stream_id, frame);
-
-                                            is_blocked = true;
-                                        }
-                                        else {
-                                            // The frame should be sent, so update the send window.
-                                            self.send_window -= frame.get_length() as u32;
-                                            self.send_frames.push_front(
-                                                Box::new(frame).compress_frame(promised_stream_id)
-                                            );
-                                        }
-                                    }
-                                },
-                                framing::FrameType::Headers => {
-                                    if is_blocked {
-                                        self.stream_blocker.block_frame(promised_stream_id, frame);
-                                    }
-                                    else {
-                                        // Not blocked so just send.
-                                        self.send_frames.push_front(
-                                            Box::new(frame).compress_frame(promised_stream_id)
-                                        );
-                                    }
-                                },
-                                _ => {
-                                    // Not a controlled frame, just send.
-                                    self.send_frames.push_front(
-                                        Box::new(frame).compress_frame(promised_stream_id)
-                                    );
-                                }
-                            }
-                        }
-                    },
-                    hash_map::Entry::Vacant(_) => {
-                        panic!("expected reserved stream, but nothing was found");
-                    }
-                }
-            }
-            
-            while let Some((promised_stream_id, promised_stream)) = temp_streams.pop() {
-                self.streams.insert(promised_stream_id, promised_stream);
-            }
-            
-            true
-        }
-        else {
-            false
+        // (6.4) Nothing more can be sent on a stream once it has been reset, so the frames which are waiting for
+        // the send window are dropped. A stream which has finished generating its response is closed already, and
+        // ignores the RST_STREAM, but its data still mustn't be sent.
+        if is_reset_stream || self.streams.get(&stream_id).map(|stream| stream.is_reset()).unwrap_or(false) {
+            self.stream_blocker.drop_stream(stream_id);
         }
-    }
-
-    fn move_to_stream<T, R, S>(&mut self, frame_type: framing::FrameType, frame: framing::Frame, app: &T)
-        where T: server_trait::OsmiumServer<Request=R, Response=S>,
-              R: convert::From<streaming::StreamRequest>,
-              S: convert::Into<streaming::StreamResponse>
-    {
-        let stream_id = frame.header.stream_id;
 
-        let mut temp_streams = match self.do_move_to_stream(frame_type, stream_id, frame, app) {
-            Ok(temp_streams) => temp_streams,
-            Err(err) => {
-                match err {
-                    error::HttpError::ConnectionError(code, msg) => {
-                        self.shutdown_connection(error::HttpError::ConnectionError(
-                            code, msg
-                        ));
-                        return;
-                    },
-                    error::HttpError::StreamError(code, _) => {
-                        // TODO make sure this gets logged somewhere, because the message has to be discarded.
-                        let reset_stream_frame = framing::reset_stream::ResetStreamFrameCompressModel::new(code as u32);
-                        self.push_send_frame(Box::new(reset_stream_frame), stream_id);
-                        return;
-                    }
+        if let Err(err) = result {
+            match err {
+                error::HttpError::ConnectionError(code, msg) => {
+                    self.shutdown_connection(error::HttpError::ConnectionError(
+                        code, msg
+                    ));
+                },
+                error::HttpError::StreamError(code, _) => {
+                    // TODO make sure this gets logged somewhere, because the message has to be discarded.
+                    let reset_stream_frame = framing::reset_stream::ResetStreamFrameCompressModel::new(code as u32);
+                    self.push_send_frame(Box::new(reset_stream_frame), stream_id);
                 }
             }
-        };
-
-        while let Some((promised_stream_id, promised_stream)) = temp_streams.pop() {
-            self.streams.insert(promised_stream_id, promised_stream);
         }
     }
 
-    pub fn do_move_to_stream<T, R, S>(&mut self, frame_type: framing::FrameType, stream_id: streaming::StreamId, frame: framing::Frame, app: &T) -> Result<Vec<(streaming::StreamId, streaming::Stream)>, error::HttpError> 
-        where T: server_trait::OsmiumServer<Request=R, Response=S>,
-              R: convert::From<streaming::StreamRequest>,
-              S: convert::Into<streaming::StreamResponse>
-    {
-        let mut temp_streams = Vec::new();
-
+    pub fn do_move_to_stream(&mut self, frame_type: framing::FrameType, stream_id: streaming::StreamId, frame: framing::Frame) -> Result<(), error::HttpError> {
         // Ensure there is always a stream with the current identifier.
         if !self.streams.contains_key(&stream_id) {
             // (5.1.1) Streams initiated by a client MUST use odd-numbered stream identifiers
@@ -445,79 +433,110 @@ impl<'a> Connection<'a> {
 
             self.highest_remote_initiated_stream_identifier = stream_id;
 
+            // (5.1.2) The stream still has to be received so that the header compression context stays in sync.
+            if let Some(max_concurrent_streams) = self.connection_shared_state.borrow().local_settings.max_concurrent_streams {
+                if self.count_active_streams(true) >= max_concurrent_streams as usize {
+                    self.refusing_stream = Some(stream_id);
+                }
+            }
+
             self.streams.insert(
                 stream_id,
                 streaming::Stream::new(stream_id, self.connection_shared_state.clone())
             );
         }
 
-        let stream = self.streams.get_mut(&stream_id).unwrap();
+        let is_end_headers = match frame_type {
+            framing::FrameType::Headers => framing::headers::is_end_headers(frame.header.flags),
+            framing::FrameType::Continuation => framing::continuation::is_end_headers(frame.header.flags),
+            _ => false
+        };
+
+        let refuse_stream = is_end_headers && self.refusing_stream == Some(stream_id);
+        if refuse_stream {
+            self.refusing_stream = None;
+        }
+
+        let (ready_request, stream_frames) = {
+            let stream = self.streams.get_mut(&stream_id).unwrap();
 
-        let stream_response = stream.recv(
-            framing::StreamFrame {
-                // TODO constructor for converting the header.
-                header: framing::StreamFrameHeader {
-                    length: frame.header.length,
-                    frame_type: frame_type,
-                    flags: frame.header.flags
+            let stream_response = stream.recv(
+                framing::StreamFrame {
+                    // TODO constructor for converting the header.
+                    header: framing::StreamFrameHeader {
+                        length: frame.header.length,
+                        frame_type: frame_type,
+                        flags: frame.header.flags
+                    },
+                    payload: frame.payload
                 },
-                payload: frame.payload
-            },
-            &mut self.hpack_send_context,
-            &mut self.hpack_recv_context,
-            app
-        );
+                &mut self.hpack_recv_context
+            );
 
-        // ----> stray todo
-        // TODO would be really helpful if the line number and file was logged everywhere! :)
+            // ----> stray todo
+            // TODO would be really helpful if the line number and file was logged everywhere! :)
 
-        // Because stream errors might affect the connection state, they aren't handled on the stream.
-        // The internal error representation is returned from the stream to be processed here.
-        if let Some(err) = stream_response {
-            return Err(err);
-        }
+            // Because stream errors might affect the connection state, they aren't handled on the stream.
+            // The internal error representation is returned from the stream to be processed here.
+            if let Some(err) = stream_response {
+                return Err(err);
+            }
 
-        // For each push promise, creates a new stream which is in the reserved state and queues that new stream
-        // for processing later.
-        while let Some((promised_stream_id, stream_request)) = stream.fetch_push_promise() {
-            let promise_stream = streaming::Stream::new_promise(promised_stream_id, self.connection_shared_state.clone(), stream_request);
+            if refuse_stream {
+                stream.reset_local();
+            }
+
+            (stream.fetch_ready_request(), stream.fetch_send_frames())
+        };
 
-            temp_streams.push((promised_stream_id, promise_stream));
-            self.promised_streams_queue.push_front(promised_stream_id);
+        if refuse_stream {
+            // (8.1.4) The client knows that a refused stream wasn't processed, so it can retry the request.
+            debug!("Refusing stream [{}] which would exceed the limit on concurrent streams", stream_id);
+            let reset_stream_frame = framing::reset_stream::ResetStreamFrameCompressModel::new(error::ErrorCode::RefusedStream as u32);
+            self.push_send_frame(Box::new(reset_stream_frame), stream_id);
+            return Ok(());
         }
 
-        // The below is essentially reconstructing part of a response, starting from the frame which exceeds the 
-        // send window up to the end of the response. 
-        // It could be made more efficient by keeping the response in a block when fetching it from the stream. However,
-        // this impacts the server's ability to multiplex and doesn't allow other flow controlled frame types to be 
-        // added in the future.
+        self.queue_stream_send_frames(stream_id, stream_frames);
+
+        info!("Blocked streams {:?}", self.stream_blocker.get_unblock_priorities());
+
+        if let Some(request) = ready_request {
+            match self.go_away_last_stream_id {
+                Some(last_stream_id) if stream_id > last_stream_id => {
+                    // (6.8) The stream was opened after the GOAWAY was sent, so it won't be processed. The headers
+                    // still had to be decoded to keep the compression context in sync.
+                    debug!("Refusing stream [{}] which was opened after the connection started shutting down", stream_id);
+                    let reset_stream_frame = framing::reset_stream::ResetStreamFrameCompressModel::new(error::ErrorCode::RefusedStream as u32);
+                    self.push_send_frame(Box::new(reset_stream_frame), stream_id);
+                },
+                _ => {
+                    self.queue_ready_request(stream_id, request);
+                }
+            }
+        }
 
-        // TODO the code below could easily be split out into another function?
+        Ok(())
+    }
 
-        // Fetch any send frames which have been generated on the stream.
+    // Queues frames which have been generated on a stream, holding back any which flow control doesn't allow to be sent yet.
+    // Frames are kept in order, so once one frame is held back every later frame for the stream is held back behind it.
+    // It could be made more efficient by keeping the response in a block when fetching it from the stream. However,
+    // this impacts the server's ability to multiplex and doesn't allow other flow controlled frame types to be 
+    // added in the future.
+    fn queue_stream_send_frames(&mut self, stream_id: StreamId, stream_frames: Vec<Box<framing::CompressibleHttpFrame>>) {
         let mut is_blocked = self.stream_blocker.is_blocking(stream_id);
-        let stream_frames = stream.fetch_send_frames();
-        let mut stream_frame_iter = stream_frames.into_iter().rev();
-        while let Some(frame) = stream_frame_iter.next() {
+        for frame in stream_frames {
             match frame.get_frame_type() {
                 framing::FrameType::Data => {
                     if is_blocked {
                         self.stream_blocker.block_frame(stream_id, frame);
                     }
-                    else {
-                        if frame.get_length() as u32 > self.send_window {
-                            // Must not send, block the stream.
-                            self.stream_blocker.block_frame(stream_id, frame);
+                    else if let Some(remaining_frame) = self.send_data_frame(stream_id, frame) {
+                        // Must not send the rest, block the stream.
+                        self.stream_blocker.block_frame(stream_id, remaining_frame);
 
-                            is_blocked = true;
-                        }
-                        else {
-                            // The frame should be sent, so update the send window.
-                            self.send_window -= frame.get_length() as u32;
-                            self.send_frames.push_front(
-                                Box::new(frame).compress_frame(stream_id)
-                            );
-                        }
+                        is_blocked = true;
                     }
                 },
                 framing::FrameType::Headers => {
@@ -526,23 +545,116 @@ impl<'a> Connection<'a> {
                     }
                     else {
                         // Not blocked so just send.
-                        self.send_frames.push_front(
-                            Box::new(frame).compress_frame(stream_id)
-                        );
+                        self.push_send_frame(frame, stream_id);
                     }
                 },
                 _ => {
                     // Not a controlled frame, just send.
-                    self.send_frames.push_front(
-                        Box::new(frame).compress_frame(stream_id)
-                    );
+                    self.push_send_frame(frame, stream_id);
                 }
             }
         }
+    }
 
-        info!("Blocked streams {:?}", self.stream_blocker.get_unblock_priorities());
+    fn queue_ready_request(&mut self, stream_id: StreamId, request: streaming::StreamRequest) {
+        // (8.2) Promised requests must be made on a stream which the client initiated.
+        let push_enabled = stream_id % 2 == 1 && self.connection_shared_state.borrow().remote_settings.enable_push;
+
+        self.ready_requests.push_back(ReadyRequest {
+            stream_id: stream_id,
+            request: request,
+            push_enabled: push_enabled,
+            max_push_promises: if push_enabled { self.get_max_push_promises() } else { Some(0) }
+        });
+    }
+
+    // (5.1.2) Streams which are open or half-closed count toward the limit on concurrent streams set by the
+    // endpoint which didn't initiate them. Clients initiate odd numbered streams and the server even numbered ones.
+    fn count_active_streams(&self, client_initiated: bool) -> usize {
+        self.streams.iter()
+            .filter(|&(stream_id, stream)| (stream_id % 2 == 1) == client_initiated && stream.is_active())
+            .count()
+    }
+
+    // The client limits how many streams the server may push. Promised streams don't count until their response
+    // starts, but that happens as soon as the application has produced it, so they are counted too.
+    fn get_max_push_promises(&self) -> Option<usize> {
+        self.connection_shared_state.borrow().remote_settings.max_concurrent_streams.map(|max_concurrent_streams| {
+            let reserved = self.streams.iter().filter(|&(stream_id, stream)| stream_id % 2 == 0 && stream.is_reserved()).count();
+            (max_concurrent_streams as usize).saturating_sub(self.count_active_streams(false) + reserved)
+        })
+    }
 
-        Ok(temp_streams)
+    /// Take the next request which is ready to be processed. The caller passes it to the application and
+    /// hands the result back with `recv_response`. Requests on different streams are independent, so 
+    /// they may be processed concurrently.
+    pub fn pull_request(&mut self) -> Option<ReadyRequest> {
+        self.ready_requests.pop_front()
+    }
+
+    /// Send the application's response on the stream it was produced for, along with any push promises
+    /// which were made while producing it. Each promised request is then ready to be processed.
+    pub fn recv_response(&mut self, stream_id: StreamId, push_promises: Vec<streaming::StreamRequest>, response: Result<streaming::StreamResponse, ()>) {
+        if self.shutdown_initiated {
+            info!("The connection is shutting down, so the response for stream [{}] will be discarded", stream_id);
+            return;
+        }
+
+        let response = match response {
+            Ok(response) => response,
+            Err(_) => {
+                error!("The application failed to produce a response for stream [{}], will reset the stream", stream_id);
+                // The stream is closed by the reset, so it no longer counts toward the limits on concurrent streams.
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.reset_local();
+                }
+                self.stream_blocker.drop_stream(stream_id);
+                let reset_stream_frame = framing::reset_stream::ResetStreamFrameCompressModel::new(error::ErrorCode::InternalError as u32);
+                self.push_send_frame(Box::new(reset_stream_frame), stream_id);
+                return;
+            }
+        };
+
+        // Other responses may have pushed streams since the application was told how many it could push.
+        let mut push_promises = push_promises;
+        if let Some(max_push_promises) = self.get_max_push_promises() {
+            if push_promises.len() > max_push_promises {
+                debug!("Dropping push promises for stream [{}] which would exceed the client's limit on concurrent streams", stream_id);
+                push_promises.truncate(max_push_promises);
+            }
+        }
+
+        let mut promised = Vec::new();
+        let stream_frames = match self.streams.get_mut(&stream_id) {
+            Some(stream) => {
+                stream.send_response(response, push_promises, &mut self.hpack_send_context);
+
+                while let Some(promise) = stream.fetch_push_promise() {
+                    promised.push(promise);
+                }
+
+                stream.fetch_send_frames()
+            },
+            None => {
+                debug!("Discarding response for unknown stream [{}]", stream_id);
+                return;
+            }
+        };
+
+        self.queue_stream_send_frames(stream_id, stream_frames);
+
+        // Create a reserved stream for each promise. The promised requests are synthetic, so they are ready 
+        // to be processed straight away.
+        for (promised_stream_id, stream_request) in promised {
+            let mut promise_stream = streaming::Stream::new_promise(promised_stream_id, self.connection_shared_state.clone(), stream_request);
+
+            promise_stream.recv_promised();
+            if let Some(request) = promise_stream.fetch_ready_request() {
+                self.queue_ready_request(promised_stream_id, request);
+            }
+
+            self.streams.insert(promised_stream_id, promise_stream);
+        }
     }
 
     /// N.B. GoAway frames sent directly to this method will not end the connection. Use `shutdown_connection` instead.
@@ -575,12 +687,149 @@ impl<'a> Connection<'a> {
         self.push_send_frame(Box::new(go_away), CONNECTION_CONTROL_STREAM_ID);
     }
 
+    /// Close the connection because its transport doesn't meet the security requirements for HTTP/2 (9.2.1).
+    pub fn reject_inadequate_security(&mut self, error_name: error::ErrorName) {
+        self.shutdown_connection(error::HttpError::ConnectionError(error::ErrorCode::InadequateSecurity, error_name));
+    }
+
+    /// Close the connection if the client still hasn't acknowledged a local settings frame (6.5.3). The frames are
+    /// numbered in the order they were sent, starting from 0 for the one sent during the handshake.
+    pub fn settings_acknowledge_timeout(&mut self, sequence_number: usize) {
+        if self.shutdown_initiated || sequence_number < self.local_settings_acknowledged {
+            return;
+        }
+
+        info!("The settings were not acknowledged in time, closing the connection");
+        self.shutdown_connection(error::HttpError::ConnectionError(error::ErrorCode::SettingsTimeout, error::ErrorName::SettingsNotAcknowledged));
+    }
+
+    /// Send the client changes to the local settings, which take effect once it has acknowledged them (6.5.3). The
+    /// changes apply on top of the most recent settings which were sent. Yields the sequence number of the settings
+    /// frame, so that the acknowledgement can be timed out.
+    pub fn update_local_settings(&mut self, changes: &[settings::SettingsParameter]) -> Result<usize, error::HttpError> {
+        let mut local_settings = match self.pending_local_settings.back() {
+            Some(pending_local_settings) => pending_local_settings.clone(),
+            None => self.connection_shared_state.borrow().local_settings.clone()
+        };
+        local_settings.apply_changes(changes)?;
+
+        let mut settings_frame = framing::settings::SettingsFrameCompressModel::new();
+        for setting in changes {
+            settings_frame.add_parameter(setting.get_name(), setting.get_value());
+        }
+        self.push_send_frame(Box::new(settings_frame), CONNECTION_CONTROL_STREAM_ID);
+
+        self.pending_local_settings.push_back(local_settings);
+
+        Ok(self.local_settings_acknowledged + self.pending_local_settings.len() - 1)
+    }
+
+    /// Close the connection because nothing has happened on it for too long. The client is told which
+    /// streams were processed, so it knows which requests it can retry on a new connection.
+    pub fn idle_timeout(&mut self) {
+        if self.shutdown_initiated {
+            return;
+        }
+
+        info!("The connection has been idle for too long, closing it");
+        self.shutdown_connection(error::HttpError::ConnectionError(error::ErrorCode::NoError, error::ErrorName::IdleTimeout));
+    }
+
+    /// Start a graceful shutdown. The client is sent a GOAWAY naming the last stream it opened, which
+    /// tells it that no new streams will be processed. The streams up to that one are allowed to finish.
+    pub fn graceful_shutdown(&mut self) {
+        if self.shutdown_initiated || self.go_away_last_stream_id.is_some() {
+            return;
+        }
+
+        let last_stream_id = self.highest_remote_initiated_stream_identifier;
+        self.go_away_last_stream_id = Some(last_stream_id);
+
+        let go_away = framing::go_away::GoAwayFrameCompressModel::new(
+            last_stream_id,
+            error::HttpError::ConnectionError(
+                error::ErrorCode::NoError,
+                error::ErrorName::ServerShuttingDown
+            )
+        );
+
+        self.push_send_frame(Box::new(go_away), CONNECTION_CONTROL_STREAM_ID);
+    }
+
+    /// Send a PING to check that the client is still there (6.7). If too many of the pings which were sent
+    /// before haven't been acknowledged, the client is assumed to be gone and the connection is closed instead.
+    pub fn ping(&mut self, max_missed_pings: usize) {
+        if self.shutdown_initiated {
+            return;
+        }
+
+        if self.pending_pings.len() >= max_missed_pings {
+            info!("[{}] pings were not acknowledged, closing the connection", self.pending_pings.len());
+            self.shutdown_connection(error::HttpError::ConnectionError(error::ErrorCode::NoError, error::ErrorName::PingNotAcknowledged));
+            return;
+        }
+
+        // The payload only has to be unique among the pings which are waiting for an acknowledgement.
+        let mut payload = [0; 8];
+        for (i, octet) in payload.iter_mut().enumerate() {
+            *octet = (self.next_ping_payload >> (56 - 8 * i)) as u8;
+        }
+        self.next_ping_payload = self.next_ping_payload.wrapping_add(1);
+
+        let mut ping = framing::ping::PingFrameCompressModel::new();
+        ping.set_ping_payload(payload);
+
+        self.pending_pings.push_back((payload, Instant::now()));
+        self.push_send_frame(Box::new(ping), CONNECTION_CONTROL_STREAM_ID);
+    }
+
+    // Acknowledgements arrive in the order the pings were sent, so any pings sent before the one being acknowledged
+    // won't be acknowledged now.
+    fn recv_ping_acknowledge(&mut self, payload: [u8; 8]) {
+        match self.pending_pings.iter().position(|&(pending_payload, _)| pending_payload == payload) {
+            Some(position) => {
+                let (_, sent) = self.pending_pings.drain(..position + 1).last().unwrap();
+                self.round_trip_time = Some(sent.elapsed());
+                trace!("Ping round trip time {:?}", self.round_trip_time);
+            },
+            None => {
+                debug!("Received an acknowledgement for a ping which wasn't sent");
+            }
+        }
+    }
+
+    /// The time it took for the most recent ping to be acknowledged.
+    pub fn get_round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time
+    }
+
+    /// Whether the connection has been closed because of an error or a timeout. Nothing more will be received.
+    pub fn is_shutdown_initiated(&self) -> bool {
+        self.shutdown_initiated
+    }
+
+    /// Whether a graceful shutdown has finished. That is, every request which was accepted before the GOAWAY
+    /// has been received and all of the frames for the responses which have been given to the connection 
+    /// have been queued to send. Responses which are still being produced have to be tracked by the caller.
+    pub fn is_shutdown_complete(&self) -> bool {
+        match self.go_away_last_stream_id {
+            Some(last_stream_id) => {
+                self.ready_requests.is_empty()
+                    && !self.stream_blocker.has_blocked_frames()
+                    && !self.streams.iter().any(|(stream_id, stream)| *stream_id <= last_stream_id && stream.is_receiving())
+            },
+            None => false
+        }
+    }
+
     // TODO do a fetch all like in stream?
     pub fn pull_frame(&mut self) -> Option<Vec<u8>> {
         self.send_frames.pop_front()
     }
 
     fn apply_settings(&mut self, settings_frame: framing::settings::SettingsFrame, send_acknowledge: bool) {
+        let mut send_windows_increased = false;
+
         for setting in settings_frame.get_parameters() {
             match setting.get_name() {
                 &settings::SettingName::SettingsHeaderTableSize => {
@@ -627,20 +876,38 @@ impl<'a> Connection<'a> {
                     }
                 },
                 &settings::SettingName::SettingsMaxConcurrentStreams => {
-                    // TODO need to refuse to open new streams if it would exceed the remote limit (for a server this is just limiting the number of push promises)
-                    // TODO need to send reset stream with stream refused if the client exceeds the limit we've set. If the client continues to try to open streams
-                    // very quickly while open streams are sill being processed then we can send reset with enhance your calm :)
+                    // This limits how many streams can be pushed. The client's streams are limited by the local settings.
+                    // TODO If the client continues to try to open streams very quickly while open streams are still being
+                    // processed then we can send reset with enhance your calm :)
                     self.connection_shared_state.borrow_mut().remote_settings.max_concurrent_streams = Some(setting.get_value());
                 },
                 &settings::SettingName::SettingsInitialWindowSize => {
                     let val = setting.get_value();
 
                     if val <= settings::MAXIMUM_FLOW_CONTROL_WINDOW_SIZE {
+                        // This is the window size that new streams will use.
+                        let previous_val = self.connection_shared_state.borrow().remote_settings.initial_window_size;
                         self.connection_shared_state.borrow_mut().remote_settings.initial_window_size = val;
 
-                        // This is the window size that new streams will use.
+                        // (6.9.2) The send windows of the existing streams change by the difference, which can leave
+                        // them negative. The connection's window is only changed by WINDOW_UPDATE frames.
+                        let delta = val as i64 - previous_val as i64;
+                        let mut apply_result = Ok(());
+                        for stream in self.streams.values_mut() {
+                            apply_result = stream.apply_initial_window_size_delta(delta);
+                            if apply_result.is_err() {
+                                break;
+                            }
+                        }
 
-                        // TODO this also affects some existing streams, see (6.9.2) and (6.9.3)
+                        if let Err(e) = apply_result {
+                            self.shutdown_connection(e);
+                            return;
+                        }
+
+                        if delta > 0 {
+                            send_windows_increased = true;
+                        }
                     }
                     else {
                         // (6.5.2) Values above the maximum flow-control window size of 231-1 MUST be treated as a 
@@ -695,6 +962,36 @@ impl<'a> Connection<'a> {
     
             self.push_send_frame(Box::new(settings_acknowledge), CONNECTION_CONTROL_STREAM_ID);
         }
+
+        // Streams which were waiting for their send window might be able to continue.
+        if send_windows_increased {
+            self.try_unblock_streams();
+        }
+    }
+
+    // The client acknowledges settings in the order they were sent, so the oldest pending ones take effect.
+    fn recv_settings_acknowledge(&mut self) {
+        match self.pending_local_settings.pop_front() {
+            Some(local_settings) => {
+                trace!("The local settings were acknowledged {:?}", local_settings);
+
+                // (6.9.2) The client adjusts its send windows when it acknowledges the change, so the receive windows
+                // are adjusted to match.
+                let previous_initial_window_size = self.connection_shared_state.borrow().local_settings.initial_window_size;
+                let delta = local_settings.initial_window_size as i64 - previous_initial_window_size as i64;
+                if delta != 0 {
+                    for stream in self.streams.values_mut() {
+                        stream.apply_local_initial_window_size_delta(delta);
+                    }
+                }
+
+                self.connection_shared_state.borrow_mut().local_settings = local_settings;
+                self.local_settings_acknowledged += 1;
+            },
+            None => {
+                debug!("Received a settings acknowledgement when none was expected");
+            }
+        }
     }
 
     fn handle_flow_control_for_recv(&mut self, size: u32) {
@@ -721,27 +1018,687 @@ impl<'a> Connection<'a> {
         let mut unblock_priorities = self.stream_blocker.get_unblock_priorities();
 
         while let Some(stream_id) = unblock_priorities.pop_back() {
-            let next_send_size = self.stream_blocker.get_next_send_size(stream_id);
+            // (6.4) A stream which has been reset can't have anything more sent on it.
+            if self.streams.get(&stream_id).map(|stream| stream.is_reset()).unwrap_or(true) {
+                self.stream_blocker.drop_stream(stream_id);
+                continue;
+            }
 
-            match next_send_size {
-                Some(size) => {
-                    if (size as u32) < self.send_window {
-                        // TODO group these operations together? they're done in several places.
-                        self.send_window -= size as u32;
+            // Send as much of the stream as the windows allow. A frame which doesn't fit holds back the ones
+            // after it, so that the stream's frames stay in order.
+            while let Some(next_send_size) = self.stream_blocker.get_next_send_size(stream_id) {
+                if next_send_size > 0 && self.get_available_send_window(stream_id) == 0 {
+                    break;
+                }
 
-                        let send_frame = self.stream_blocker.get_next_frame(stream_id).unwrap();
+                let send_frame = self.stream_blocker.get_next_frame(stream_id).unwrap();
+                match send_frame.get_frame_type() {
+                    framing::FrameType::Data => {
+                        if let Some(remaining_frame) = self.send_data_frame(stream_id, send_frame) {
+                            self.stream_blocker.requeue_frame(stream_id, remaining_frame);
+                            break;
+                        }
+                    },
+                    _ => {
                         self.push_send_frame(send_frame, stream_id);
                     }
-                    else {
-                        continue;
-                    }
-                },
-                None => {
-                    // TODO If fetch size failed then it is possible that the block has been cleared and
-                    // can be cleaned up.
-                    continue;
                 }
             }
+
+            if !self.stream_blocker.is_blocking(stream_id) {
+                self.stream_blocker.drop_stream(stream_id);
+            }
+        }
+    }
+
+    // (6.9.1) Data can only be sent if both the connection and the stream have room for it in their send windows.
+    // Sends as much of a data frame as the send windows allow, splitting it if they're too small for all of it (6.9.1).
+    // Yields whatever couldn't be sent.
+    fn send_data_frame(&mut self, stream_id: StreamId, frame: Box<framing::CompressibleHttpFrame>) -> Option<Box<framing::CompressibleHttpFrame>> {
+        let size = frame.get_length() as u32;
+        let available_send_window = self.get_available_send_window(stream_id);
+
+        if size <= available_send_window {
+            self.consume_send_windows(stream_id, size);
+            self.push_send_frame(frame, stream_id);
+            return None;
+        }
+
+        if available_send_window == 0 {
+            return Some(frame);
+        }
+
+        let (send_frame, remaining_frame) = framing::data::split(frame, available_send_window as usize);
+        let send_frame: Box<framing::CompressibleHttpFrame> = Box::new(send_frame);
+        self.consume_send_windows(stream_id, send_frame.get_length() as u32);
+        self.push_send_frame(send_frame, stream_id);
+
+        Some(Box::new(remaining_frame))
+    }
+
+    // The most data which can be sent on the stream, which is limited by both the connection and the stream windows.
+    fn get_available_send_window(&self, stream_id: StreamId) -> u32 {
+        let stream_send_window = self.streams.get(&stream_id).map(|stream| stream.get_send_window()).unwrap_or(0);
+
+        cmp::min(self.send_window as i64, cmp::max(stream_send_window, 0) as i64) as u32
+    }
+
+    fn consume_send_windows(&mut self, stream_id: StreamId, size: u32) {
+        self.send_window -= size;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.consume_send_window(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connection;
+
+    use futures::sync::mpsc as futures_mpsc;
+    use http2::error::{ErrorCode, ErrorName};
+    use http2::frame as framing;
+    use http2::header;
+    use http2::hpack;
+    use http2::net::shutdown_signal::ShutdownSignaller;
+    use http2::settings;
+    use http2::stream as streaming;
+    use shared::request::Request;
+
+    const SETTINGS_ACKNOWLEDGE: [u8; 9] = [0, 0, 0, 0x4, 0x1, 0, 0, 0, 0];
+
+    // A GET for / which ends the stream, with the pseudo headers from the static table.
+    fn get_request(stream_id: u8) -> Vec<u8> {
+        vec![0, 0, 3, 0x1, 0x5, 0, 0, 0, stream_id, 0x82, 0x86, 0x84]
+    }
+
+    // A request which is followed by data frames.
+    fn post_request(stream_id: u8) -> Vec<u8> {
+        vec![0, 0, 3, 0x1, 0x4, 0, 0, 0, stream_id, 0x83, 0x86, 0x84]
+    }
+
+    fn data_frame(stream_id: u8, length: usize) -> Vec<u8> {
+        let mut frame = vec![(length >> 16) as u8, (length >> 8) as u8, length as u8, 0x0, 0x0, 0, 0, 0, stream_id];
+        frame.resize(framing::FRAME_HEADER_SIZE + length, 0);
+        frame
+    }
+
+    fn ok_response() -> streaming::StreamResponse {
+        let mut headers = header::Headers::new();
+        headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(200));
+
+        streaming::StreamResponse {
+            informational_headers: Vec::new(),
+            headers: headers,
+            payload: None,
+            trailer_headers: None
+        }
+    }
+
+    fn ok_response_with_payload(length: usize) -> streaming::StreamResponse {
+        let mut response = ok_response();
+        response.payload = Some(vec![0; length]);
+        response
+    }
+
+    fn initial_window_size_settings(initial_window_size: u32) -> Vec<u8> {
+        let size = initial_window_size;
+        vec![0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 0x4, (size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]
+    }
+
+    // Yields the type of each frame the connection sent along with its stream identifier.
+    fn sent_frames(connection: &mut Connection) -> Vec<(u8, u32)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = connection.pull_frame() {
+            frames.push((frame[3], framing::decompress_frame_header(frame).stream_id));
+        }
+        frames
+    }
+
+    fn new_connection(local_settings: settings::Settings) -> Connection {
+        let hpack = hpack::HPack::new();
+        let (shutdown_read_tx, _) = futures_mpsc::channel(1);
+
+        let mut connection = Connection::new(
+            hpack.new_send_context(),
+            hpack.new_recv_context(),
+            local_settings,
+            framing::settings::SettingsFrame::new_noop(),
+            None,
+            ShutdownSignaller::new(shutdown_read_tx)
+        );
+
+        // Drop the acknowledgement of the client's settings.
+        connection.pull_frame().unwrap();
+
+        connection
+    }
+
+    fn recv(connection: &mut Connection, frame: &[u8]) {
+        connection.recv(framing::Frame {
+            header: framing::decompress_frame_header(frame.to_vec()),
+            payload: frame[framing::FRAME_HEADER_SIZE..].to_vec()
+        });
+    }
+
+    // Yields the error code of the GOAWAY which the connection sent, if it sent one.
+    fn go_away_error_code(connection: &mut Connection) -> Option<u32> {
+        while let Some(frame) = connection.pull_frame() {
+            if frame[3] == 0x7 {
+                return Some(((frame[13] as u32) << 24) + ((frame[14] as u32) << 16) + ((frame[15] as u32) << 8) + frame[16] as u32);
+            }
+        }
+
+        None
+    }
+
+    fn large_frame_settings() -> settings::Settings {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.max_frame_size = 2 * settings::INITIAL_MAX_FRAME_SIZE;
+        local_settings
+    }
+
+    // A frame of an unknown type, which the connection ignores if it isn't too large.
+    fn unknown_frame(length: usize) -> Vec<u8> {
+        let mut frame = vec![(length >> 16) as u8, (length >> 8) as u8, length as u8, 0xfa, 0, 0, 0, 0, 0];
+        frame.resize(framing::FRAME_HEADER_SIZE + length, 0);
+        frame
+    }
+
+    #[test]
+    pub fn enforce_default_max_frame_size_until_acknowledged() {
+        let mut connection = new_connection(large_frame_settings());
+
+        recv(&mut connection, &unknown_frame(settings::INITIAL_MAX_FRAME_SIZE as usize + 1));
+
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), go_away_error_code(&mut connection));
+    }
+
+    #[test]
+    pub fn enforce_max_frame_size_once_acknowledged() {
+        let mut connection = new_connection(large_frame_settings());
+
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+        recv(&mut connection, &unknown_frame(settings::INITIAL_MAX_FRAME_SIZE as usize + 1));
+        assert_eq!(None, go_away_error_code(&mut connection));
+
+        recv(&mut connection, &unknown_frame(2 * settings::INITIAL_MAX_FRAME_SIZE as usize + 1));
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), go_away_error_code(&mut connection));
+    }
+
+    // Sends a ping and yields its payload.
+    fn send_ping(connection: &mut Connection, max_missed_pings: usize) -> Vec<u8> {
+        connection.ping(max_missed_pings);
+
+        let ping = connection.pull_frame().unwrap();
+        assert_eq!((0x6, 0x0), (ping[3], ping[4]));
+        ping[framing::FRAME_HEADER_SIZE..].to_vec()
+    }
+
+    fn ping_acknowledge(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, 0, 8, 0x6, 0x1, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    pub fn measure_round_trip_time() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+        assert_eq!(None, connection.get_round_trip_time());
+
+        let payload = send_ping(&mut connection, 3);
+        recv(&mut connection, &ping_acknowledge(&payload));
+
+        assert!(connection.get_round_trip_time().is_some());
+        // An acknowledgement isn't acknowledged.
+        assert_eq!(None, connection.pull_frame());
+    }
+
+    #[test]
+    pub fn ignore_unknown_ping_acknowledge() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        let first_payload = send_ping(&mut connection, 2);
+        let second_payload = send_ping(&mut connection, 2);
+        assert!(first_payload != second_payload);
+
+        recv(&mut connection, &ping_acknowledge(&[0xff; 8]));
+        assert_eq!(None, connection.get_round_trip_time());
+
+        // Acknowledging the later ping means the earlier one won't be, so neither is missing any more.
+        recv(&mut connection, &ping_acknowledge(&second_payload));
+        assert!(connection.get_round_trip_time().is_some());
+        recv(&mut connection, &ping_acknowledge(&first_payload));
+
+        send_ping(&mut connection, 2);
+        send_ping(&mut connection, 2);
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn close_when_pings_not_acknowledged() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        send_ping(&mut connection, 2);
+        send_ping(&mut connection, 2);
+        assert!(!connection.is_shutdown_initiated());
+
+        connection.ping(2);
+        assert_eq!(Some(u32::from(ErrorCode::NoError)), go_away_error_code(&mut connection));
+        assert!(connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn close_idle_connection() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+        connection.recv_response(1, Vec::new(), Ok(ok_response()));
+        sent_frames(&mut connection);
+
+        connection.idle_timeout();
+        let go_away = connection.pull_frame().unwrap();
+        assert_eq!(0x7, go_away[3]);
+        // The client is told that the stream it opened was processed.
+        assert_eq!(1, go_away[12]);
+        assert_eq!(u32::from(ErrorCode::NoError), ((go_away[15] as u32) << 8) + go_away[16] as u32);
+        assert!(connection.is_shutdown_initiated());
+
+        // It has already been closed, so it isn't closed again.
+        connection.idle_timeout();
+        assert_eq!(None, connection.pull_frame());
+    }
+
+    #[test]
+    pub fn reject_inadequate_security() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        connection.reject_inadequate_security(ErrorName::ProhibitedCipherSuite);
+        assert_eq!(Some(u32::from(ErrorCode::InadequateSecurity)), go_away_error_code(&mut connection));
+        assert!(connection.is_shutdown_initiated());
+
+        // Nothing the client sends afterwards is processed.
+        recv(&mut connection, &get_request(1));
+        assert!(connection.pull_request().is_none());
+    }
+
+    #[test]
+    pub fn close_when_settings_not_acknowledged() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        connection.settings_acknowledge_timeout(0);
+
+        assert_eq!(Some(u32::from(ErrorCode::SettingsTimeout)), go_away_error_code(&mut connection));
+    }
+
+    #[test]
+    pub fn ignore_timeout_for_acknowledged_settings() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+        connection.settings_acknowledge_timeout(0);
+
+        assert_eq!(None, go_away_error_code(&mut connection));
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn updated_settings_apply_once_acknowledged() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+
+        let changes = [settings::SettingsParameter::new(settings::SettingName::SettingsMaxFrameSize, 2 * settings::INITIAL_MAX_FRAME_SIZE)];
+        assert_eq!(1, connection.update_local_settings(&changes).unwrap());
+
+        let settings_frame = connection.pull_frame().unwrap();
+        assert_eq!(0x4, settings_frame[3]);
+        assert_eq!(0x0, settings_frame[4]);
+
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+        recv(&mut connection, &unknown_frame(settings::INITIAL_MAX_FRAME_SIZE as usize + 1));
+        assert_eq!(None, go_away_error_code(&mut connection));
+    }
+
+    #[test]
+    pub fn time_out_updated_settings() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        let changes = [settings::SettingsParameter::new(settings::SettingName::SettingsMaxConcurrentStreams, 10)];
+        assert_eq!(1, connection.update_local_settings(&changes).unwrap());
+
+        // Only the settings from the handshake are acknowledged.
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+        connection.settings_acknowledge_timeout(0);
+        assert!(!connection.is_shutdown_initiated());
+
+        connection.settings_acknowledge_timeout(1);
+        assert_eq!(Some(u32::from(ErrorCode::SettingsTimeout)), go_away_error_code(&mut connection));
+    }
+
+    #[test]
+    pub fn reject_invalid_settings_update() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        let changes = [settings::SettingsParameter::new(settings::SettingName::SettingsEnablePush, 2)];
+        assert!(connection.update_local_settings(&changes).is_err());
+        assert_eq!(None, connection.pull_frame());
+    }
+
+    #[test]
+    pub fn enforce_stream_receive_window_once_acknowledged() {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.initial_window_size = 10;
+        let mut connection = new_connection(local_settings);
+
+        // The client can use the default window until it acknowledges the settings.
+        recv(&mut connection, &post_request(1));
+        recv(&mut connection, &data_frame(1, 11));
+        // The connection and the stream windows are both updated.
+        assert_eq!(vec![(0x8, 0), (0x8, 1)], sent_frames(&mut connection));
+
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+
+        // The stream which was already open has its window reduced too.
+        recv(&mut connection, &data_frame(1, 10));
+        assert_eq!(vec![(0x8, 0), (0x8, 1)], sent_frames(&mut connection));
+        recv(&mut connection, &data_frame(1, 11));
+        // The data still counts towards the connection window.
+        assert_eq!(0x8, connection.pull_frame().unwrap()[3]);
+        let reset_stream = connection.pull_frame().unwrap();
+        assert_eq!((0x3, 1), (reset_stream[3], framing::decompress_frame_header(reset_stream.clone()).stream_id));
+        assert_eq!(u32::from(ErrorCode::FlowControlError), reset_stream[12] as u32);
+
+        recv(&mut connection, &post_request(3));
+        recv(&mut connection, &data_frame(3, 11));
+        assert_eq!(vec![(0x8, 0), (0x3, 3)], sent_frames(&mut connection));
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn refuse_streams_over_local_limit() {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.max_concurrent_streams = Some(1);
+        let mut connection = new_connection(local_settings);
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+
+        recv(&mut connection, &get_request(1));
+        recv(&mut connection, &get_request(3));
+
+        // The reset carries REFUSED_STREAM.
+        let reset_stream = connection.pull_frame().unwrap();
+        assert_eq!((0x3, 3), (reset_stream[3], framing::decompress_frame_header(reset_stream.clone()).stream_id));
+        assert_eq!(u32::from(ErrorCode::RefusedStream), ((reset_stream[11] as u32) << 8) + reset_stream[12] as u32);
+
+        assert_eq!(1, connection.pull_request().unwrap().stream_id);
+        assert!(connection.pull_request().is_none());
+        assert!(!connection.is_shutdown_initiated());
+
+        // Once the first stream has been responded to there is room for another.
+        connection.recv_response(1, Vec::new(), Ok(ok_response()));
+        connection.pull_frame().unwrap();
+        recv(&mut connection, &get_request(5));
+        assert_eq!(5, connection.pull_request().unwrap().stream_id);
+    }
+
+    #[test]
+    pub fn refuse_streams_over_local_limit_after_header_block() {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.max_concurrent_streams = Some(1);
+        let mut connection = new_connection(local_settings);
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+
+        recv(&mut connection, &get_request(1));
+
+        // The header block continues, and adds `x: y` to the dynamic table.
+        recv(&mut connection, &[0, 0, 2, 0x1, 0x0, 0, 0, 0, 3, 0x82, 0x86]);
+        assert_eq!(None, connection.pull_frame());
+        recv(&mut connection, &[0, 0, 6, 0x9, 0x4, 0, 0, 0, 3, 0x84, 0x40, 0x1, b'x', 0x1, b'y']);
+
+        let reset_stream = connection.pull_frame().unwrap();
+        assert_eq!((0x3, 3), (reset_stream[3], framing::decompress_frame_header(reset_stream.clone()).stream_id));
+        assert_eq!(u32::from(ErrorCode::RefusedStream), reset_stream[12] as u32);
+
+        // The request body which was already on its way is discarded, only the connection window is updated.
+        recv(&mut connection, &data_frame(3, 10));
+        assert_eq!(vec![(0x8, 0)], sent_frames(&mut connection));
+
+        connection.pull_request().unwrap();
+        connection.recv_response(1, Vec::new(), Ok(ok_response()));
+        connection.pull_frame().unwrap();
+
+        // The next request refers to the header which was added by the refused stream.
+        recv(&mut connection, &[0, 0, 4, 0x1, 0x5, 0, 0, 0, 5, 0x82, 0x86, 0x84, 0xbe]);
+        assert_eq!(5, connection.pull_request().unwrap().stream_id);
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn failed_responses_do_not_count_toward_local_limit() {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.max_concurrent_streams = Some(1);
+        let mut connection = new_connection(local_settings);
+        recv(&mut connection, &SETTINGS_ACKNOWLEDGE);
+
+        // Each stream is reset with INTERNAL_ERROR when its response fails, and the next one is still accepted.
+        for stream_id in vec![1, 3, 5, 7] {
+            recv(&mut connection, &get_request(stream_id));
+            assert_eq!(stream_id as u32, connection.pull_request().unwrap().stream_id);
+
+            connection.recv_response(stream_id as u32, Vec::new(), Err(()));
+
+            let reset_stream = connection.pull_frame().unwrap();
+            assert_eq!((0x3, stream_id as u32), (reset_stream[3], framing::decompress_frame_header(reset_stream.clone()).stream_id));
+            assert_eq!(u32::from(ErrorCode::InternalError), reset_stream[12] as u32);
+            assert_eq!(None, connection.pull_frame());
+        }
+    }
+
+    #[test]
+    pub fn failed_push_responses_do_not_count_toward_remote_limit() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        // The client allows one concurrent pushed stream.
+        recv(&mut connection, &[0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 0x3, 0, 0, 0, 1]);
+        sent_frames(&mut connection);
+
+        for stream_id in vec![1, 3, 5] {
+            recv(&mut connection, &get_request(stream_id));
+            let ready_request = connection.pull_request().unwrap();
+            assert_eq!(Some(1), ready_request.max_push_promises);
+
+            connection.recv_response(stream_id as u32, vec![Request::new("GET", "https", "localhost", "/a").into()], Ok(ok_response()));
+
+            // The promised stream fails, which frees its place.
+            let promised_stream_id = connection.pull_request().unwrap().stream_id;
+            connection.recv_response(promised_stream_id, Vec::new(), Err(()));
+            assert_eq!(vec![(0x5, stream_id as u32), (0x1, stream_id as u32), (0x3, promised_stream_id)], sent_frames(&mut connection));
+        }
+    }
+
+    #[test]
+    pub fn limit_push_promises_to_remote_limit() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        // The client allows one concurrent pushed stream.
+        recv(&mut connection, &[0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 0x3, 0, 0, 0, 1]);
+        recv(&mut connection, &get_request(1));
+        assert_eq!(vec![(0x4, 0)], sent_frames(&mut connection));
+
+        let ready_request = connection.pull_request().unwrap();
+        assert_eq!(Some(1), ready_request.max_push_promises);
+
+        // Both promises are made as if another response had used up the limit in the meantime, so only one is kept.
+        let push_promises = vec![
+            Request::new("GET", "https", "localhost", "/a").into(),
+            Request::new("GET", "https", "localhost", "/b").into()
+        ];
+        connection.recv_response(1, push_promises, Ok(ok_response()));
+
+        assert_eq!(vec![(0x5, 1), (0x1, 1)], sent_frames(&mut connection));
+        assert_eq!(2, connection.pull_request().unwrap().stream_id);
+
+        // The pushed stream hasn't been responded to yet, so there's no room for more.
+        recv(&mut connection, &get_request(3));
+        assert_eq!(Some(0), connection.pull_request().unwrap().max_push_promises);
+    }
+
+    #[test]
+    pub fn initial_window_size_increase_unblocks_existing_streams() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &initial_window_size_settings(10));
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+
+        // Only part of the data fits in the stream's send window.
+        connection.recv_response(1, Vec::new(), Ok(ok_response_with_payload(100)));
+        assert_eq!(vec![(0x4, 0), (0x1, 1), (0x0, 1)], sent_frames(&mut connection));
+
+        recv(&mut connection, &initial_window_size_settings(200));
+        assert_eq!(vec![(0x4, 0), (0x0, 1)], sent_frames(&mut connection));
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn initial_window_size_decrease_blocks_existing_streams() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+        recv(&mut connection, &initial_window_size_settings(0));
+
+        connection.recv_response(1, Vec::new(), Ok(ok_response_with_payload(100)));
+        assert_eq!(vec![(0x4, 0), (0x1, 1)], sent_frames(&mut connection));
+
+        // The stream is closed once the response has been generated, but the window update still releases the data.
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 1, 0, 0, 0, 100]);
+        assert_eq!(vec![(0x0, 1)], sent_frames(&mut connection));
+    }
+
+    #[test]
+    pub fn request_body_spans_data_frames() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &post_request(1));
+        recv(&mut connection, &[0, 0, 3, 0x0, 0x0, 0, 0, 0, 1, 1, 2, 3]);
+        recv(&mut connection, &[0, 0, 2, 0x0, 0x0, 0, 0, 0, 1, 4, 5]);
+        recv(&mut connection, &[0, 0, 1, 0x0, 0x1, 0, 0, 0, 1, 6]);
+
+        let ready_request = connection.pull_request().unwrap();
+        assert_eq!(Some(vec![1, 2, 3, 4, 5, 6]), ready_request.request.payload);
+    }
+
+    #[test]
+    pub fn reset_stream_when_request_body_too_large() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+        connection.set_max_request_body_size(4);
+
+        recv(&mut connection, &post_request(1));
+        recv(&mut connection, &[0, 0, 3, 0x0, 0x0, 0, 0, 0, 1, 1, 2, 3]);
+        sent_frames(&mut connection);
+        recv(&mut connection, &[0, 0, 2, 0x0, 0x0, 0, 0, 0, 1, 4, 5]);
+
+        // The connection window may be topped up as well, but the stream is reset.
+        let mut reset_stream = None;
+        while let Some(frame) = connection.pull_frame() {
+            if frame[3] == 0x3 {
+                reset_stream = Some(frame);
+            }
         }
+        let reset_stream = reset_stream.unwrap();
+        assert_eq!(1, framing::decompress_frame_header(reset_stream.clone()).stream_id);
+        assert_eq!(u32::from(ErrorCode::Cancel), reset_stream[12] as u32);
+
+        // The rest of the body is discarded and the request is never processed.
+        recv(&mut connection, &[0, 0, 1, 0x0, 0x1, 0, 0, 0, 1, 6]);
+        assert!(connection.pull_request().is_none());
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn drop_blocked_data_when_stream_reset() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &initial_window_size_settings(10));
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+
+        connection.recv_response(1, Vec::new(), Ok(ok_response_with_payload(100)));
+        assert_eq!(vec![(0x4, 0), (0x1, 1), (0x0, 1)], sent_frames(&mut connection));
+
+        // The client resets the stream while the rest of the data is waiting for the send window.
+        recv(&mut connection, &[0, 0, 4, 0x3, 0, 0, 0, 0, 1, 0, 0, 0, 0x8]);
+        recv(&mut connection, &initial_window_size_settings(200));
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 1, 0, 0, 0, 100]);
+        assert_eq!(vec![(0x4, 0)], sent_frames(&mut connection));
+
+        // Nothing is left to send, so a graceful shutdown doesn't wait for the stream.
+        connection.graceful_shutdown();
+        assert!(connection.is_shutdown_complete());
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    // Yields the length and flags of each data frame the connection sent.
+    fn sent_data_frames(connection: &mut Connection) -> Vec<(u32, u8)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = connection.pull_frame() {
+            if frame[3] == 0x0 {
+                frames.push((framing::decompress_frame_header(frame.clone()).length, frame[4]));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    pub fn split_data_to_fit_stream_send_window() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &initial_window_size_settings(1));
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+
+        // The window is never raised to fit the whole payload.
+        connection.recv_response(1, Vec::new(), Ok(ok_response_with_payload(10)));
+        assert_eq!(vec![(1, 0x0)], sent_data_frames(&mut connection));
+
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 1, 0, 0, 0, 3]);
+        assert_eq!(vec![(3, 0x0)], sent_data_frames(&mut connection));
+
+        // The last part ends the stream.
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 1, 0, 0, 0, 100]);
+        assert_eq!(vec![(6, 0x1)], sent_data_frames(&mut connection));
+        assert!(!connection.is_shutdown_initiated());
+    }
+
+    #[test]
+    pub fn split_data_to_fit_connection_send_window() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &get_request(1));
+        connection.pull_request().unwrap();
+        connection.recv_response(1, Vec::new(), Ok(ok_response_with_payload(settings::INITIAL_FLOW_CONTROL_WINDOW_SIZE as usize - 1)));
+        connection.pull_frame().unwrap();
+        connection.pull_frame().unwrap();
+
+        recv(&mut connection, &get_request(3));
+        connection.pull_request().unwrap();
+        connection.recv_response(3, Vec::new(), Ok(ok_response_with_payload(10)));
+        assert_eq!(vec![(1, 0x0)], sent_data_frames(&mut connection));
+
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 0, 0, 0, 0, 100]);
+        assert_eq!(vec![(9, 0x1)], sent_data_frames(&mut connection));
+    }
+
+    #[test]
+    pub fn initial_window_size_change_must_not_overflow_send_windows() {
+        let mut connection = new_connection(settings::Settings::spec_default());
+
+        recv(&mut connection, &get_request(1));
+        recv(&mut connection, &[0, 0, 4, 0x8, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        recv(&mut connection, &initial_window_size_settings(settings::MAXIMUM_FLOW_CONTROL_WINDOW_SIZE));
+
+        assert_eq!(Some(u32::from(ErrorCode::FlowControlError)), go_away_error_code(&mut connection));
     }
 }
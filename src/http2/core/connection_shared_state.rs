@@ -23,10 +23,12 @@ pub struct ConnectionSharedState {
     pub remote_settings: settings::Settings,
     pub local_settings: settings::Settings,
     next_server_created_stream_id: StreamId,
-    // If streams were ever made concurrent it would be VITAL that this is locked. It is used to communicate to
-    // the client which streams have started processing, or at least the highest numbered one. That means no more
-    // streams may start processing once this has been sent.
-    highest_started_processing_stream_id: StreamId
+    // It is used to communicate to the client which streams have started processing, or at least the highest numbered
+    // one. That means no more streams may start processing once this has been sent. Streams are processed concurrently,
+    // but they are only marked as started and handed to the workers by the connection thread, so this doesn't need a lock.
+    highest_started_processing_stream_id: StreamId,
+    // The largest request body which a stream will receive, if there's a limit.
+    pub max_request_body_size: Option<usize>
 }
 
 impl ConnectionSharedState {
@@ -35,7 +37,8 @@ impl ConnectionSharedState {
             remote_settings: settings::Settings::spec_default(),
             local_settings: local_settings,
             next_server_created_stream_id: 2,
-            highest_started_processing_stream_id: 0
+            highest_started_processing_stream_id: 0,
+            max_request_body_size: None
         }
     }
 
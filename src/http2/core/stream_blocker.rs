@@ -24,7 +24,8 @@ use std::collections::hash_map;
 use http2::frame as framing;
 use http2::stream::StreamId;
 
-// TODO this never cleans up.
+// Holds the frames which flow control doesn't allow to be sent yet. A stream's queue is removed once it has been
+// emptied, or when the stream is reset and its frames must not be sent.
 pub struct StreamBlocker {
     blocked_streams: HashMap<StreamId, VecDeque<Box<framing::CompressibleHttpFrame>>>,
     priority: VecDeque<StreamId>
@@ -63,8 +64,31 @@ impl StreamBlocker {
         }
     }
 
+    /// Put back a frame which was taken with `get_next_frame`, so that it's the next one to be sent.
+    pub fn requeue_frame(&mut self, stream_id: StreamId, frame: Box<framing::CompressibleHttpFrame>) {
+        if let Some(queue) = self.blocked_streams.get_mut(&stream_id) {
+            queue.push_back(frame);
+        }
+    }
+
+    /// Whether the stream has frames waiting, which any new frames for the stream have to be queued behind.
     pub fn is_blocking(&self, stream_id: StreamId) -> bool {
-        self.blocked_streams.contains_key(&stream_id)
+        match self.blocked_streams.get(&stream_id) {
+            Some(queue) => !queue.is_empty(),
+            None => false
+        }
+    }
+
+    /// Whether there are any frames still waiting for the flow control window.
+    pub fn has_blocked_frames(&self) -> bool {
+        self.blocked_streams.values().any(|queue| !queue.is_empty())
+    }
+
+    /// Forget the stream's frames, which will never be sent. Does nothing if the stream isn't blocked.
+    pub fn drop_stream(&mut self, stream_id: StreamId) {
+        if self.blocked_streams.remove(&stream_id).is_some() {
+            self.priority.retain(|blocked_stream_id| *blocked_stream_id != stream_id);
+        }
     }
 
     pub fn get_unblock_priorities(&self) -> VecDeque<StreamId> {
@@ -113,6 +113,7 @@ pub enum ErrorName {
     InvalidMaxFrameSize,
     InvalidInitialWindowSize,
     ConnectionFlowControlWindowNotRespected,
+    StreamFlowControlWindowNotRespected,
     SettingsAcknowledgementWithNonZeroPayloadLength,
     SettingsFramePayloadSizeNotAMultipleOfSix,
     FramePayloadLargerThanSettingsValue,
@@ -121,12 +122,21 @@ pub enum ErrorName {
     EvenStreamIdentiferOnClientInitiatedStream,
     ResetStreamFrameWithInvalidSize,
     WindowUpdateWouldCauseSendWindowToExceedLimit,
+    InitialWindowSizeChangeWouldCauseSendWindowToExceedLimit,
     InvalidFrameLengthForConnectionWindowUpdateFrame,
     NonLowerCaseHeaderNameIsRejectedAsMalformed,
     MalformedRequestHasDuplicatePseudoHeaderPath,
     MalformedRequestHasDuplicatePseudoHeaderMethod,
     MalformedRequestHasDuplicatePseudoHeaderScheme,
-    MalformedRequestHasMissingRequiredPseudoHeader
+    MalformedRequestHasMissingRequiredPseudoHeader,
+    ServerShuttingDown,
+    ProhibitedTlsVersion,
+    ProhibitedCipherSuite,
+    ExpectedSettingsFrameAfterPreface,
+    SettingsNotAcknowledged,
+    IdleTimeout,
+    PingNotAcknowledged,
+    RequestBodyTooLarge
 }
 
 impl From<ErrorName> for Vec<u8> {
@@ -177,6 +187,9 @@ impl From<ErrorName> for Vec<u8> {
             ErrorName::ConnectionFlowControlWindowNotRespected => {
                 "connection flow control window not respected"
             },
+            ErrorName::StreamFlowControlWindowNotRespected => {
+                "stream flow control window not respected"
+            },
             ErrorName::SettingsAcknowledgementWithNonZeroPayloadLength => {
                 "settings acknowledge frame received with non-zero payload length"
             },
@@ -201,6 +214,9 @@ impl From<ErrorName> for Vec<u8> {
             ErrorName::WindowUpdateWouldCauseSendWindowToExceedLimit => {
                 "Window update would cause send window to exceed limit"
             },
+            ErrorName::InitialWindowSizeChangeWouldCauseSendWindowToExceedLimit => {
+                "Initial window size change would cause send window to exceed limit"
+            },
             ErrorName::InvalidFrameLengthForConnectionWindowUpdateFrame => {
                 "Invalid frame length for connection window update frame"
             },
@@ -218,6 +234,30 @@ impl From<ErrorName> for Vec<u8> {
             }
             ErrorName::MalformedRequestHasMissingRequiredPseudoHeader => {
                 "Malformed request has missing required pseudo header"
+            },
+            ErrorName::ServerShuttingDown => {
+                "The server is shutting down"
+            },
+            ErrorName::ProhibitedTlsVersion => {
+                "HTTP/2 requires TLS 1.2 or above"
+            },
+            ErrorName::ProhibitedCipherSuite => {
+                "The negotiated cipher suite is prohibited for HTTP/2"
+            },
+            ErrorName::ExpectedSettingsFrameAfterPreface => {
+                "The connection preface must be followed by a SETTINGS frame"
+            },
+            ErrorName::SettingsNotAcknowledged => {
+                "The server's settings were not acknowledged in time"
+            },
+            ErrorName::IdleTimeout => {
+                "The connection was idle for too long"
+            },
+            ErrorName::PingNotAcknowledged => {
+                "Too many pings were not acknowledged"
+            },
+            ErrorName::RequestBodyTooLarge => {
+                "The request body is larger than the server accepts"
             }
         }.to_owned().as_bytes().to_vec()
     }
@@ -46,9 +46,10 @@ pub fn check_conn_window_update(
     }
 }
 
+// The send window of a stream can be negative after the initial window size has been reduced (6.9.2).
 pub fn check_stream_window_update(
     decoded_frame: Result<window_update::WindowUpdateFrame, error::HttpError>,
-    send_window: u32
+    send_window: i32
 ) -> Result<window_update::WindowUpdateFrame, error::HttpError>
 {
     match decoded_frame {
@@ -61,7 +62,7 @@ pub fn check_stream_window_update(
                     error::ErrorName::ZeroWindowSizeIncrement
                 ))
             }
-            else if settings::MAXIMUM_FLOW_CONTROL_WINDOW_SIZE - frame.get_window_size_increment() < send_window {
+            else if send_window as i64 + frame.get_window_size_increment() as i64 > settings::MAXIMUM_FLOW_CONTROL_WINDOW_SIZE as i64 {
                 Err(error::HttpError::StreamError(
                     error::ErrorCode::FlowControlError,
                     error::ErrorName::WindowUpdateWouldCauseSendWindowToExceedLimit
@@ -16,6 +16,7 @@
 // along with Osmium. If not, see <http://www.gnu.org/licenses/>.
 
 // std
+use std::cmp;
 use std::vec::IntoIter;
 
 // osmium
@@ -103,6 +104,32 @@ impl CompressibleHttpFrame for DataFrameCompressModel {
     }
 }
 
+/// Split a data frame so that the first part carries at most `size` octets of data and the second part carries the rest.
+/// Only the second part ends the stream, if the frame did. Any padding is dropped.
+pub fn split(frame: Box<CompressibleHttpFrame>, size: usize) -> (DataFrameCompressModel, DataFrameCompressModel) {
+    assert_eq!(FrameType::Data, frame.get_frame_type(), "only data frames can be split");
+
+    let flags = frame.get_flags();
+    let mut payload = frame.get_payload();
+
+    if flags & FLAG_PADDED == FLAG_PADDED {
+        let pad_length = payload[0] as usize;
+        let data_length = payload.len() - 1 - pad_length;
+        payload = payload[1..(1 + data_length)].to_vec();
+    }
+
+    let split_at = cmp::min(size, payload.len());
+    let remaining_payload = payload.split_off(split_at);
+
+    let mut first = DataFrameCompressModel::new(false);
+    first.set_payload(payload);
+
+    let mut second = DataFrameCompressModel::new(flags & FLAG_END_STREAM == FLAG_END_STREAM);
+    second.set_payload(remaining_payload);
+
+    (first, second)
+}
+
 pub struct DataFrame {
     payload: Vec<u8>,
     end_stream: bool
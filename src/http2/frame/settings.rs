@@ -86,7 +86,7 @@ impl CompressibleHttpFrame for SettingsFrameCompressModel {
         let mut result = Vec::new();
 
         for setting in self.parameters.into_iter() {
-            let name = setting.name as u16;
+            let name = u16::from(setting.name);
             result.push((name >> 8) as u8);
             result.push(name as u8);
 
@@ -207,8 +207,29 @@ fn example_payload() {
     // This is the settings payload the curl sends on with an http2 upgrade request.
     let payload = vec![0, 3, 0, 0, 0, 100, 0, 4, 64, 0, 0, 0, 0, 2, 0, 0, 0, 0];
 
-    let decoded = SettingsFrame::new(&header, &mut payload.into_iter());
+    let decoded = SettingsFrame::new(&header, &mut payload.into_iter()).unwrap();
 
-    // TODO assert.
-    println!("{:?}", decoded);
+    let parameters: Vec<(u16, u32)> = decoded.get_parameters().iter()
+        .map(|parameter| (u16::from(parameter.get_name().clone()), parameter.get_value()))
+        .collect();
+    assert_eq!(vec![(0x3, 100), (0x4, 0x40000000), (0x2, 0)], parameters);
+}
+
+#[test]
+fn encode_setting_identifiers() {
+    let identifiers = vec![
+        (settings::SettingName::SettingsHeaderTableSize, 0x1),
+        (settings::SettingName::SettingsEnablePush, 0x2),
+        (settings::SettingName::SettingsMaxConcurrentStreams, 0x3),
+        (settings::SettingName::SettingsInitialWindowSize, 0x4),
+        (settings::SettingName::SettingsMaxFrameSize, 0x5),
+        (settings::SettingName::SettingsMaxHeaderListSize, 0x6)
+    ];
+
+    for (name, identifier) in identifiers {
+        let mut settings_frame = SettingsFrameCompressModel::new();
+        settings_frame.add_parameter(name, 0x01020304);
+
+        assert_eq!(vec![0x0, identifier, 0x1, 0x2, 0x3, 0x4], Box::new(settings_frame).get_payload());
+    }
 }
@@ -18,6 +18,7 @@
 // std
 use std::fmt;
 use std::slice;
+use std::vec;
 use http2::hpack::header_trait;
 use http2::hpack::table;
 
@@ -104,6 +105,15 @@ impl Headers {
     }
 }
 
+impl IntoIterator for Headers {
+    type Item = Header;
+    type IntoIter = vec::IntoIter<Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.headers.into_iter()
+    }
+}
+
 // Convert `HeaderName` enum values to string for serialisation 
 // and so that the enum type can be pseudo-used as a hash key.
 impl From<HeaderName> for String {
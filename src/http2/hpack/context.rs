@@ -15,6 +15,10 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
 
+// std
+use std::sync::Arc;
+
+// osmium
 use http2::hpack::table;
 use http2::hpack::table::Field;
 use http2::hpack;
@@ -27,19 +31,18 @@ use http2::hpack;
 /// The two tables are accesed in a single address space, as defined in hpack section 2.3.3.
 /// The context structure provides an interface to this single address space.
 ///
-/// Note that this context has a reference to a single instance static table which is required
-/// to live at least as long as this context instance. The dynamic table however belongs to 
-/// this context.
-pub struct Context<'a> {
-    static_table: &'a table::Table,
+/// Note that the static table is a single instance which is shared by every context. The dynamic
+/// table however belongs to this context.
+pub struct Context {
+    static_table: Arc<table::Table>,
     dynamic_table: table::Table
 }
 
 // TODO Field type needs to go and be replaced by Header types.
 
-pub trait ContextTrait<'a> {
+pub trait ContextTrait {
     /// Create a new context object with a reference to the given static table.
-    fn new(static_table: &'a table::Table) -> Self;
+    fn new(static_table: Arc<table::Table>) -> Self;
 
     /// Inserts a header into the dynamic table. As per section 2.3.3, insertion is at the front
     /// of the dynamic table. Equivalently, the insertion point is after the end of the static table.
@@ -75,9 +78,9 @@ pub trait ContextTrait<'a> {
     fn set_max_size(&mut self, max_size: usize);
 }
 
-impl<'a> ContextTrait<'a> for Context<'a> {
+impl ContextTrait for Context {
     /// Create a new context object with a reference to the given static table.
-    fn new(static_table: &'a table::Table) -> Self {
+    fn new(static_table: Arc<table::Table>) -> Self {
         Context {
             static_table: static_table,
             dynamic_table: table::Table::new()
@@ -167,14 +170,14 @@ impl<'a> ContextTrait<'a> for Context<'a> {
     }
 }
 
-pub struct SendContext<'a> {
-    inner: Context<'a>,
+pub struct SendContext {
+    inner: Context,
 
     send_size_update: bool,
     size_update: u32
 }
 
-impl<'a> SendContext<'a> {
+impl SendContext {
     /// Informs the context of a change to SETTINGS_HEADER_TABLE_SIZE, see http2 6.5.2
     pub fn inform_max_size_setting_changed(&mut self, max_size_setting: u32) {
         // TODO handle multiple max size setting updates between header encodes.
@@ -196,8 +199,8 @@ impl<'a> SendContext<'a> {
     }
 }
 
-impl<'a> ContextTrait<'a> for SendContext<'a> {
-    fn new(static_table: &'a table::Table) -> Self {
+impl ContextTrait for SendContext {
+    fn new(static_table: Arc<table::Table>) -> Self {
         SendContext {
             inner: Context::new(static_table),
 
@@ -227,12 +230,12 @@ impl<'a> ContextTrait<'a> for SendContext<'a> {
     }
 }
 
-pub struct RecvContext<'a> {
-    inner: Context<'a>
+pub struct RecvContext {
+    inner: Context
 }
 
-impl<'a> ContextTrait<'a> for RecvContext<'a> {
-    fn new(static_table: &'a table::Table) -> Self {
+impl ContextTrait for RecvContext {
+    fn new(static_table: Arc<table::Table>) -> Self {
         RecvContext {
             inner: Context::new(static_table)
         }
@@ -25,6 +25,10 @@ pub mod pack;
 pub mod flags;
 pub mod header_trait;
 
+// std
+use std::sync::Arc;
+
+// osmium
 use self::table::{Table, Field};
 use self::context::{SendContext, RecvContext, ContextTrait};
 
@@ -37,7 +41,7 @@ pub const STATIC_TABLE_LENGTH: usize = 61;
 pub struct HPack {
     /// The single static table instance to be shared by all contexts 
     /// provided by this `HPack` instance.
-    static_table: Table
+    static_table: Arc<Table>
 }
 
 impl HPack {
@@ -112,18 +116,18 @@ impl HPack {
         assert_eq!(STATIC_TABLE_LENGTH, static_table.len(), "static table should have 61 entries");
 
         HPack {
-            static_table: static_table
+            static_table: Arc::new(static_table)
         }
     }
 
     /// Get a new `SendContext` instance.
     pub fn new_send_context(&self) -> SendContext {
-        SendContext::new(&self.static_table)
+        SendContext::new(self.static_table.clone())
     }
 
     /// Get a new `RecvContext` instance.
     pub fn new_recv_context(&self) -> RecvContext {
-        RecvContext::new(&self.static_table)
+        RecvContext::new(self.static_table.clone())
     }
 }
 
@@ -212,7 +216,7 @@ mod tests {
     }
 
     // Note that the field name is converted to lower case. See http2::header for more.
-    fn assert_table_entry<'a, T: ContextTrait<'a>>(context: &T, index: usize, name: &str, value: &str) {
+    fn assert_table_entry<T: ContextTrait>(context: &T, index: usize, name: &str, value: &str) {
         let dynamic_table_entry = context.get(index);
         assert!(dynamic_table_entry.is_some());
         let field = dynamic_table_entry.unwrap();
@@ -220,7 +224,7 @@ mod tests {
         assert_eq!(value, &field.value);
     }
 
-    fn assert_contexts_equal<'a, 'b, T: ContextTrait<'a>, S: ContextTrait<'b>>(expected_context: &T, actual_context: &S) {
+    fn assert_contexts_equal<T: ContextTrait, S: ContextTrait>(expected_context: &T, actual_context: &S) {
         let mut index = STATIC_TABLE_LENGTH;
 
         // Checking the sizes first, as it makes it more likely the method will exit on an assert rather
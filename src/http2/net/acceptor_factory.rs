@@ -15,56 +15,801 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium. If not, see <http://www.gnu.org/licenses/>.
 
-use openssl::pkcs12::{Pkcs12, ParsedPkcs12};
-use openssl::ssl::{SslMethod, SslAcceptor, SslAcceptorBuilder};
+use openssl::error::ErrorStack;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::ssl::{self, SslMethod, SslAcceptor, SslAcceptorBuilder, SslContext, SslContextBuilder};
+use openssl::x509::{X509, X509Name, X509Ref};
+use openssl::nid;
+use futures::{Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_openssl::{SslAcceptorExt, SslStream};
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
-use std::io::Read;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex, RwLock};
 
-use shared::server_settings::SecuritySettings;
+use http2::error;
+use shared::connection_info::{ConnectionInfo, PeerCertificate};
+use shared::server_settings::{SecuritySettings, CertificateFormat, ClientVerification, TlsPolicy, TlsVersion};
+use shared::transport::{Transport, TlsAcceptor};
 
-const ALPN_PROTOCOLS: &[&[u8]] = &[&[0x68, 0x32]];
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
 
+// In order of preference. Clients which can't speak HTTP/2 are served HTTP/1.1 on the same port.
+const ALPN_PROTOCOLS: &[&[u8]] = &[ALPN_H2, ALPN_HTTP_1_1];
+
+/// The reasons the certificate and private key in the security settings can't be used.
+#[derive(Debug)]
+pub enum SecurityError {
+    /// A file couldn't be read. This has the path of the file.
+    ReadFailed(String, io::Error),
+    /// The file isn't a PKCS #12 archive.
+    InvalidPkcs12(ErrorStack),
+    /// The PKCS #12 archive couldn't be decrypted, which usually means the password is wrong.
+    IncorrectPassword(ErrorStack),
+    /// PEM and DER certificates need the path of their private key.
+    MissingPrivateKey,
+    /// The certificate file couldn't be parsed in the configured format.
+    InvalidCertificate(ErrorStack),
+    /// The PEM file doesn't contain any certificates.
+    EmptyCertificateChain,
+    /// The private key file couldn't be parsed in the configured format, or its passphrase is wrong.
+    InvalidPrivateKey(ErrorStack),
+    /// The certificate and private key were read but couldn't be used together, for example because the
+    /// key doesn't belong to the certificate.
+    InvalidIdentity(ErrorStack),
+    /// The certificate for a server name couldn't be loaded. This has the server name and the reason.
+    InvalidServerNameCertificate(String, Box<SecurityError>),
+    /// Client certificates can't be verified without the client CAs.
+    MissingClientCa,
+    /// The client CA file couldn't be parsed as PEM certificates, or doesn't contain any.
+    InvalidClientCa(Option<ErrorStack>),
+    /// The cipher list doesn't select any cipher suites which OpenSSL supports.
+    InvalidCipherList(ErrorStack)
+}
+
+/// Makes the acceptors for a TLS listener. Clones share the same certificate, so reloading it through any clone
+/// changes the certificate which every clone uses for new connections.
+#[derive(Clone)]
 pub struct AcceptorFactory {
-    identity: ParsedPkcs12
+    security_settings: SecuritySettings,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+    // A hash of the contents of the certificate files when they were last loaded.
+    fingerprint: Arc<Mutex<Option<u64>>>
+}
+
+/// Certificate files which have been read again, but aren't being used for new connections yet.
+pub struct ReloadedCertificate {
+    fingerprint: Option<u64>,
+    acceptor: Result<SslAcceptor, SecurityError>
+}
+
+struct Identity {
+    private_key: PKey,
+    certificate: X509,
+    chain: Vec<X509>
 }
 
 impl AcceptorFactory {
-    pub fn new(security_settings: &SecuritySettings) -> Self {
-        // TODO tidy up comments
+    /// Load the certificate and private key, and check that they can be used to accept connections.
+    pub fn new(security_settings: &SecuritySettings) -> Result<Self, SecurityError> {
+        let fingerprint = files_fingerprint(security_settings);
+        let acceptor = build_acceptor(security_settings)?;
+
+        Ok(AcceptorFactory {
+            security_settings: security_settings.clone(),
+            acceptor: Arc::new(RwLock::new(acceptor)),
+            fingerprint: Arc::new(Mutex::new(fingerprint))
+        })
+    }
+
+    pub fn make_acceptor(&self) -> SslAcceptor
+    {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    pub fn is_enforce_http2_tls_requirements(&self) -> bool {
+        self.security_settings.is_enforce_http2_tls_requirements()
+    }
+
+    /// Load the certificate files again, and use them for new connections. Connections which have already been
+    /// accepted keep the certificate they were accepted with. If the files can't be loaded the current certificate
+    /// is kept.
+    pub fn reload(&self) -> Result<(), SecurityError> {
+        let reloaded = ReloadedCertificate {
+            fingerprint: files_fingerprint(&self.security_settings),
+            acceptor: build_acceptor(&self.security_settings)
+        };
 
-        // In this example we retrieve our keypair and certificate chain from a PKCS #12 archive,
-        // but but they can also be retrieved from, for example, individual PEM- or DER-formatted
-        // files. See the documentation for the `PKey` and `X509` types for more details.
-        let mut file = File::open(security_settings.get_ssl_cert_path()).expect("ssl certificate not found");
-        let mut pkcs12 = vec![];
-        file.read_to_end(&mut pkcs12).unwrap();
-        let pkcs12 = Pkcs12::from_der(&pkcs12).unwrap();
-        let identity = pkcs12.parse(security_settings.get_ssl_cert_pass()).unwrap(); // this is set when you create the password with openssl
+        self.use_reloaded(reloaded)
+    }
 
-        AcceptorFactory {
-            identity: identity
+    /// Reload the certificate files if the contents of any of them have changed since they were last loaded.
+    /// Yields whether the certificate was reloaded.
+    ///
+    /// A failed reload isn't attempted again until the files change again, because a certificate and its
+    /// private key may be replaced one after the other.
+    pub fn reload_if_modified(&self) -> Result<bool, SecurityError> {
+        match self.load_if_modified() {
+            Some(reloaded) => self.use_reloaded(reloaded).map(|_| true),
+            None => Ok(false)
         }
     }
 
-    pub fn make_acceptor(&self) -> SslAcceptor
+    /// Read the certificate files again if the contents of any of them have changed since they were last loaded,
+    /// without using them yet. The files are read with blocking I/O, so this shouldn't be called on the event loop.
+    pub fn load_if_modified(&self) -> Option<ReloadedCertificate> {
+        let fingerprint = files_fingerprint(&self.security_settings);
+        if fingerprint.is_none() || fingerprint == *self.fingerprint.lock().unwrap() {
+            return None;
+        }
+
+        Some(ReloadedCertificate {
+            fingerprint: fingerprint,
+            acceptor: build_acceptor(&self.security_settings)
+        })
+    }
+
+    /// Use certificate files which were read with `load_if_modified` for new connections. If they couldn't be
+    /// loaded, the current certificate is kept.
+    pub fn use_reloaded(&self, reloaded: ReloadedCertificate) -> Result<(), SecurityError> {
+        *self.fingerprint.lock().unwrap() = reloaded.fingerprint;
+
+        let acceptor = reloaded.acceptor?;
+        *self.acceptor.write().unwrap() = acceptor;
+
+        Ok(())
+    }
+}
+
+impl TlsAcceptor for AcceptorFactory {
+    fn accept(&self, transport: Box<Transport>) -> Box<Future<Item = Box<Transport>, Error = io::Error>> {
+        let enforce_http2_tls_requirements = self.is_enforce_http2_tls_requirements();
+
+        Box::new(
+            self.make_acceptor().accept_async(transport)
+            .map_err(|e| {
+                // The stream is dropped here, only the reason the handshake failed is kept.
+                let e = match e {
+                    ssl::HandshakeError::SetupFailure(e) => io::Error::new(io::ErrorKind::Other, e),
+                    ssl::HandshakeError::Failure(stream) | ssl::HandshakeError::Interrupted(stream) => {
+                        io::Error::new(io::ErrorKind::Other, stream.error().to_string())
+                    }
+                };
+                info!("TLS handshake failed [{}]", e);
+                e
+            })
+            .map(move |stream| {
+                Box::new(OpensslTransport { stream, enforce_http2_tls_requirements }) as Box<Transport>
+            })
+        )
+    }
+}
+
+/// A connection which OpenSSL has completed the TLS handshake on.
+struct OpensslTransport {
+    stream: SslStream<Box<Transport>>,
+    enforce_http2_tls_requirements: bool
+}
+
+impl Transport for OpensslTransport {
+    fn is_secure(&self) -> bool {
+        true
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.stream.get_ref().ssl().selected_alpn_protocol().map(|protocol| protocol.to_vec())
+    }
+
+    fn connection_info(&self) -> ConnectionInfo {
+        let mut connection_info = ConnectionInfo::new();
+        let ssl = self.stream.get_ref().ssl();
+
+        if let Some(server_name) = ssl.servername() {
+            connection_info.set_server_name(String::from(server_name));
+        }
+
+        // A certificate which failed verification would have stopped the handshake, but check anyway.
+        if let (Some(peer_certificate), None) = (ssl.peer_certificate(), ssl.verify_result()) {
+            connection_info.set_peer_certificate(to_peer_certificate(&peer_certificate));
+        }
+
+        connection_info
+    }
+
+    fn http2_security_error(&self) -> Option<error::ErrorName> {
+        if !self.enforce_http2_tls_requirements {
+            return None;
+        }
+
+        let ssl = self.stream.get_ref().ssl();
+
+        if is_prohibited_tls_version(ssl.version()) {
+            return Some(error::ErrorName::ProhibitedTlsVersion);
+        }
+
+        if ssl.current_cipher().map_or(false, |cipher| is_prohibited_cipher_suite(cipher.name())) {
+            return Some(error::ErrorName::ProhibitedCipherSuite);
+        }
+
+        None
+    }
+}
+
+impl Read for OpensslTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for OpensslTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for OpensslTransport {}
+
+impl AsyncWrite for OpensslTransport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+// The subject has the attributes which are commonly used, in the usual order rather than the certificate's.
+fn to_peer_certificate(certificate: &X509Ref) -> PeerCertificate {
+    let attributes = [("CN", nid::COMMONNAME), ("OU", nid::ORGANIZATIONALUNITNAME), ("O", nid::ORGANIZATIONNAME),
+                      ("L", nid::LOCALITYNAME), ("ST", nid::STATEORPROVINCENAME), ("C", nid::COUNTRYNAME)];
+
+    let mut subject = Vec::new();
+    let mut common_name = None;
+    for &(short_name, attribute) in attributes.iter() {
+        for entry in certificate.subject_name().entries_by_nid(attribute) {
+            if let Ok(value) = entry.data().as_utf8() {
+                if attribute == nid::COMMONNAME && common_name.is_none() {
+                    common_name = Some(value.to_string());
+                }
+                subject.push(format!("{}={}", short_name, value));
+            }
+        }
+    }
+
+    let mut dns_names = Vec::new();
+    let mut ip_addresses = Vec::new();
+    if let Some(subject_alt_names) = certificate.subject_alt_names() {
+        for name in subject_alt_names.iter() {
+            if let Some(dns_name) = name.dnsname() {
+                dns_names.push(String::from(dns_name));
+            }
+            if let Some(ip_address) = name.ipaddress() {
+                if ip_address.len() == 4 {
+                    ip_addresses.push(net::IpAddr::from([ip_address[0], ip_address[1], ip_address[2], ip_address[3]]));
+                }
+                else if ip_address.len() == 16 {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(ip_address);
+                    ip_addresses.push(net::IpAddr::from(octets));
+                }
+            }
+        }
+    }
+
+    PeerCertificate::new(subject.join(", "), common_name, dns_names, ip_addresses)
+}
+
+fn build_acceptor(security_settings: &SecuritySettings) -> Result<SslAcceptor, SecurityError> {
+    let identity = load_identity(security_settings)?;
+
+    let mut server_name_contexts = Vec::new();
+    for &(ref server_name, ref certificate) in security_settings.get_server_name_certificates() {
+        let context = load_identity(certificate).and_then(|identity| build_server_name_context(identity, security_settings)).map_err(|e| {
+            SecurityError::InvalidServerNameCertificate(server_name.clone(), Box::new(e))
+        })?;
+        server_name_contexts.push((server_name.to_lowercase(), context));
+    }
+
+    let acceptor_builder = match *security_settings.get_tls_policy() {
+        TlsPolicy::Intermediate => SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(), &identity.private_key, &identity.certificate, &identity.chain),
+        TlsPolicy::Modern => SslAcceptorBuilder::mozilla_modern(SslMethod::tls(), &identity.private_key, &identity.certificate, &identity.chain)
+    };
+    let mut acceptor_builder = acceptor_builder.map_err(SecurityError::InvalidIdentity)?;
+
     {
-        let mut acceptor_builder = SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(),
-                                                                &self.identity.pkey,
-                                                                &self.identity.cert,
-                                                                &self.identity.chain).unwrap();
+        let context_builder = acceptor_builder.builder_mut();
+
+        // Offer http2 in alpn negotiation, with http/1.1 as a fallback.
+        context_builder.set_alpn_protocols(ALPN_PROTOCOLS).map_err(SecurityError::InvalidIdentity)?;
+
+        configure_client_verification(context_builder, security_settings)?;
+
+        if let Some(min_tls_version) = security_settings.get_min_tls_version() {
+            let mut options = ssl::SSL_OP_NO_SSLV2 | ssl::SSL_OP_NO_SSLV3;
+            if min_tls_version > TlsVersion::Tls1_0 {
+                options |= ssl::SSL_OP_NO_TLSV1;
+            }
+            if min_tls_version > TlsVersion::Tls1_1 {
+                options |= ssl::SSL_OP_NO_TLSV1_1;
+            }
+            context_builder.set_options(options);
+        }
+
+        if let Some(cipher_list) = security_settings.get_cipher_list() {
+            context_builder.set_cipher_list(cipher_list).map_err(SecurityError::InvalidCipherList)?;
+        }
+
+        // Switch to the certificate for the server name the client asked for, if there is one. Otherwise the
+        // handshake carries on with the default certificate.
+        if !server_name_contexts.is_empty() {
+            context_builder.set_servername_callback(move |ssl| {
+                let context = ssl.servername().and_then(|server_name| find_server_name_context(&server_name_contexts, server_name));
+
+                if let Some(context) = context {
+                    if let Err(e) = ssl.set_ssl_context(context) {
+                        error!("Failed to switch to the certificate for the requested server name {:?}", e);
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    Ok(acceptor_builder.build())
+}
+
+// A hash of the contents of the certificate files, or none if one of them can't be read. Modification times
+// aren't used because they can be too coarse to tell two writes apart, and a replaced file can have an older
+// one, for example when it's copied with its times preserved or swapped in through a symlink.
+fn files_fingerprint(security_settings: &SecuritySettings) -> Option<u64> {
+    let mut paths = vec![security_settings.get_ssl_cert_path()];
+    paths.extend(security_settings.get_ssl_key_path());
+    paths.extend(security_settings.get_client_ca_path());
+    for &(_, ref certificate) in security_settings.get_server_name_certificates() {
+        paths.push(certificate.get_ssl_cert_path());
+        paths.extend(certificate.get_ssl_key_path());
+    }
+
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        match read_file(path) {
+            Ok(contents) => contents.hash(&mut hasher),
+            Err(_) => return None
+        }
+    }
+
+    Some(hasher.finish())
+}
+
+/// Whether HTTP/2 may be used with a TLS protocol version, as named by OpenSSL (9.2).
+pub fn is_prohibited_tls_version(version: &str) -> bool {
+    match version {
+        "TLSv1.2" | "TLSv1.3" => false,
+        _ => true
+    }
+}
+
+/// Whether a cipher suite, as named by OpenSSL, is in the black list for HTTP/2 (9.2.2, Appendix A).
+///
+/// The black list is every cipher suite without ephemeral key exchange or without an AEAD cipher, which is
+/// easier to check than comparing against the list. TLS 1.3 cipher suites all satisfy both.
+pub fn is_prohibited_cipher_suite(cipher_suite: &str) -> bool {
+    if cipher_suite.starts_with("TLS_") {
+        return false;
+    }
+
+    let is_ephemeral = cipher_suite.starts_with("ECDHE-") || cipher_suite.starts_with("DHE-");
+    let is_aead = cipher_suite.contains("-GCM-") || cipher_suite.contains("CHACHA20-POLY1305") || cipher_suite.contains("-CCM");
+
+    !(is_ephemeral && is_aead)
+}
+
+fn load_identity(security_settings: &SecuritySettings) -> Result<Identity, SecurityError> {
+    match *security_settings.get_certificate_format() {
+        CertificateFormat::Pkcs12 => load_pkcs12(security_settings),
+        CertificateFormat::Pem => load_pem(security_settings),
+        CertificateFormat::Der => load_der(security_settings)
+    }
+}
 
-        {
-            let context_builder = acceptor_builder.builder_mut();
+// The connection keeps the protocol settings from the default certificate's context when it switches, but OpenSSL
+// looks up the ALPN callback and the client CAs on the context it switched to.
+fn build_server_name_context(identity: Identity, security_settings: &SecuritySettings) -> Result<SslContext, SecurityError> {
+    let build = || -> Result<SslContextBuilder, ErrorStack> {
+        let mut context_builder = SslContextBuilder::new(SslMethod::tls())?;
+        context_builder.set_private_key(&identity.private_key)?;
+        context_builder.set_certificate(&identity.certificate)?;
+        context_builder.check_private_key()?;
+        for certificate in &identity.chain {
+            context_builder.add_extra_chain_cert(certificate.clone())?;
+        }
+        context_builder.set_alpn_protocols(ALPN_PROTOCOLS)?;
+
+        Ok(context_builder)
+    };
+
+    let mut context_builder = build().map_err(SecurityError::InvalidIdentity)?;
+    configure_client_verification(&mut context_builder, security_settings)?;
+
+    Ok(context_builder.build())
+}
+
+fn configure_client_verification(context_builder: &mut SslContextBuilder, security_settings: &SecuritySettings) -> Result<(), SecurityError> {
+    let verify_mode = match *security_settings.get_client_verification() {
+        ClientVerification::None => return Ok(()),
+        ClientVerification::Optional => ssl::SSL_VERIFY_PEER,
+        ClientVerification::Required => ssl::SSL_VERIFY_PEER | ssl::SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+    };
+
+    let client_ca_path = security_settings.get_client_ca_path().ok_or(SecurityError::MissingClientCa)?;
+    let client_ca = read_file(client_ca_path)?;
+    let client_ca = X509::stack_from_pem(&client_ca).map_err(|e| SecurityError::InvalidClientCa(Some(e)))?;
+    if client_ca.is_empty() {
+        return Err(SecurityError::InvalidClientCa(None));
+    }
+
+    for certificate in client_ca {
+        context_builder.cert_store_mut().add_cert(certificate).map_err(|e| SecurityError::InvalidClientCa(Some(e)))?;
+    }
+
+    // The certificate request names the CAs, so that a client with several certificates can choose one they accept.
+    let client_ca_names = X509Name::load_client_ca_file(client_ca_path).map_err(|e| SecurityError::InvalidClientCa(Some(e)))?;
+    context_builder.set_client_ca_list(client_ca_names);
+
+    // Sessions can only be resumed within the same context, otherwise a client could skip verification.
+    context_builder.set_session_id_context(b"osmium").map_err(SecurityError::InvalidIdentity)?;
+    context_builder.set_verify(verify_mode);
+
+    Ok(())
+}
+
+fn find_server_name_context<'a>(server_name_contexts: &'a [(String, SslContext)], server_name: &str) -> Option<&'a SslContext> {
+    let server_name = server_name.to_lowercase();
+
+    // An exact match is preferred over a wildcard.
+    server_name_contexts.iter()
+        .find(|&&(ref pattern, _)| *pattern == server_name)
+        .or_else(|| server_name_contexts.iter().find(|&&(ref pattern, _)| matches_wildcard(pattern, &server_name)))
+        .map(|&(_, ref context)| context)
+}
+
+// A wildcard only stands for the left-most label, so `*.example.com` matches `www.example.com` but not
+// `example.com` or `a.www.example.com`.
+fn matches_wildcard(pattern: &str, server_name: &str) -> bool {
+    if !pattern.starts_with("*.") {
+        return false;
+    }
+
+    match server_name.find('.') {
+        Some(index) => index > 0 && server_name[index + 1..] == pattern[2..],
+        None => false
+    }
+}
+
+fn load_pkcs12(security_settings: &SecuritySettings) -> Result<Identity, SecurityError> {
+    let pkcs12 = read_file(security_settings.get_ssl_cert_path())?;
+    let pkcs12 = Pkcs12::from_der(&pkcs12).map_err(SecurityError::InvalidPkcs12)?;
+    let parsed = pkcs12.parse(security_settings.get_ssl_cert_pass()).map_err(SecurityError::IncorrectPassword)?;
+
+    Ok(Identity {
+        private_key: parsed.pkey,
+        certificate: parsed.cert,
+        chain: parsed.chain.into_iter().collect()
+    })
+}
+
+fn load_pem(security_settings: &SecuritySettings) -> Result<Identity, SecurityError> {
+    let certificates = read_file(security_settings.get_ssl_cert_path())?;
+    let mut chain = X509::stack_from_pem(&certificates).map_err(SecurityError::InvalidCertificate)?;
+    if chain.is_empty() {
+        return Err(SecurityError::EmptyCertificateChain);
+    }
+    // The server's certificate comes first, followed by the certificates which issued it.
+    let certificate = chain.remove(0);
+
+    let private_key = read_file(security_settings.get_ssl_key_path().ok_or(SecurityError::MissingPrivateKey)?)?;
+    let private_key = if security_settings.get_ssl_cert_pass().is_empty() {
+        PKey::private_key_from_pem(&private_key)
+    }
+    else {
+        PKey::private_key_from_pem_passphrase(&private_key, security_settings.get_ssl_cert_pass().as_bytes())
+    }.map_err(SecurityError::InvalidPrivateKey)?;
+
+    Ok(Identity {
+        private_key: private_key,
+        certificate: certificate,
+        chain: chain
+    })
+}
+
+fn load_der(security_settings: &SecuritySettings) -> Result<Identity, SecurityError> {
+    let certificate = read_file(security_settings.get_ssl_cert_path())?;
+    let certificate = X509::from_der(&certificate).map_err(SecurityError::InvalidCertificate)?;
+
+    let private_key = read_file(security_settings.get_ssl_key_path().ok_or(SecurityError::MissingPrivateKey)?)?;
+    let private_key = PKey::private_key_from_der(&private_key).map_err(SecurityError::InvalidPrivateKey)?;
+
+    Ok(Identity {
+        private_key: private_key,
+        certificate: certificate,
+        chain: Vec::new()
+    })
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, SecurityError> {
+    let mut contents = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| SecurityError::ReadFailed(String::from(path), e))?;
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net;
+    use std::path;
+    use std::process;
+    use std::thread;
+    use std::time;
+
+    use openssl::nid;
+    use openssl::ssl::{Ssl, SslContextBuilder, SslMethod, SslStream};
+    use openssl::x509::X509_FILETYPE_PEM;
+
+    use shared::server_settings::{SecuritySettings, ClientVerification};
+
+    use super::{AcceptorFactory, SecurityError, matches_wildcard, is_prohibited_cipher_suite, is_prohibited_tls_version, to_peer_certificate};
+
+    // Runs a TLS handshake with the acceptor over a local TCP connection, asking for the server name and presenting
+    // the client certificate if there is one. Yields the server's end of the connection if the server completed the
+    // handshake, and the client's end if the client did.
+    fn handshake(acceptor_factory: &AcceptorFactory, server_name: &str, client_identity: Option<(&str, &str)>) -> (Option<SslStream<net::TcpStream>>, Option<SslStream<net::TcpStream>>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let acceptor = acceptor_factory.make_acceptor();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            acceptor.accept(stream).ok()
+        });
+
+        let mut context = SslContextBuilder::new(SslMethod::tls()).unwrap();
+        if let Some((cert_path, key_path)) = client_identity {
+            context.set_certificate_file(cert_path, X509_FILETYPE_PEM).unwrap();
+            context.set_private_key_file(key_path, X509_FILETYPE_PEM).unwrap();
+        }
+        let mut ssl = Ssl::new(&context.build()).unwrap();
+        ssl.set_hostname(server_name).unwrap();
+
+        // The client's end is kept open until the server has finished with the handshake.
+        let client = ssl.connect(net::TcpStream::connect(addr).unwrap()).ok();
+        (server.join().unwrap(), client)
+    }
+
+    fn server_common_name(client: &SslStream<net::TcpStream>) -> String {
+        let certificate = client.ssl().peer_certificate().unwrap();
+        let common_name = certificate.subject_name().entries_by_nid(nid::COMMONNAME).next().unwrap().data().as_utf8().unwrap().to_string();
+        common_name
+    }
+
+    #[test]
+    pub fn load_pkcs12() {
+        assert!(AcceptorFactory::new(&SecuritySettings::default()).is_ok());
+    }
+
+    #[test]
+    pub fn load_pem() {
+        let security = SecuritySettings::pem(String::from("tests/cert.pem"), String::from("tests/key.pem"));
+        assert!(AcceptorFactory::new(&security).is_ok());
+    }
+
+    #[test]
+    pub fn load_der() {
+        let security = SecuritySettings::der(String::from("tests/cert.der"), String::from("tests/key.der"));
+        assert!(AcceptorFactory::new(&security).is_ok());
+    }
+
+    #[test]
+    pub fn load_server_name_certificates() {
+        let mut security = SecuritySettings::default();
+        security.add_server_name_certificate(String::from("other.localhost"), SecuritySettings::pem(String::from("tests/other_cert.pem"), String::from("tests/other_key.pem")));
+
+        assert!(AcceptorFactory::new(&security).is_ok());
+    }
 
-            // This completely disables certificate verification, because the certificates I'm using are 
-            // self signed. PLEASE REMOVE THIS BEFORE DEPLOYING ANYWHERE!
-            // TODO and now it doesn't need it!?
-            // context_builder.set_verify(openssl::ssl::SslVerifyMode::empty());
+    #[test]
+    pub fn reject_invalid_server_name_certificate() {
+        let mut security = SecuritySettings::default();
+        security.add_server_name_certificate(String::from("other.localhost"), SecuritySettings::pem(String::from("tests/other_cert.pem"), String::from("tests/key.pem")));
 
-            // Require http2 protocol in alpn negotiation.
-            context_builder.set_alpn_protocols(ALPN_PROTOCOLS).unwrap();
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::InvalidServerNameCertificate(ref server_name, _)) if server_name == "other.localhost" => {},
+            r => panic!("Expected an invalid server name certificate but got {:?}", r.err())
         }
+    }
+
+    #[test]
+    pub fn select_certificate_by_server_name() {
+        let mut security = SecuritySettings::pem(String::from("tests/cert.pem"), String::from("tests/key.pem"));
+        security.add_server_name_certificate(String::from("*.other.localhost"), SecuritySettings::pem(String::from("tests/other_cert.pem"), String::from("tests/other_key.pem")));
+        let acceptor_factory = AcceptorFactory::new(&security).unwrap();
+
+        let (server, client) = handshake(&acceptor_factory, "www.other.localhost", None);
+        assert_eq!(Some("www.other.localhost"), server.as_ref().unwrap().ssl().servername());
+        assert_eq!("other.localhost", server_common_name(&client.unwrap()));
+
+        // A server name without a certificate of its own gets the default one.
+        let (server, client) = handshake(&acceptor_factory, "unknown.localhost", None);
+        assert_eq!(Some("unknown.localhost"), server.as_ref().unwrap().ssl().servername());
+        assert_eq!("localhost", server_common_name(&client.unwrap()));
+    }
+
+    #[test]
+    pub fn wildcard_matches_one_label() {
+        assert!(matches_wildcard("*.example.com", "www.example.com"));
+        assert!(!matches_wildcard("*.example.com", "example.com"));
+        assert!(!matches_wildcard("*.example.com", "a.www.example.com"));
+        assert!(!matches_wildcard("*.example.com", ".example.com"));
+        assert!(!matches_wildcard("www.example.com", "www.example.com"));
+    }
+
+    // Replace a file with another one, giving it a modification time rather than relying on the file system's.
+    fn replace_file(from: &str, to: &path::Path, modified: time::SystemTime) {
+        fs::copy(from, to).unwrap();
+        fs::OpenOptions::new().write(true).open(to).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    pub fn reload_modified_certificate() {
+        let dir = env::temp_dir().join(format!("osmium_reload_modified_certificate_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::copy("tests/cert.pem", &cert_path).unwrap();
+        fs::copy("tests/key.pem", &key_path).unwrap();
+        let modified = fs::metadata(&cert_path).unwrap().modified().unwrap();
+
+        let security = SecuritySettings::pem(cert_path.to_string_lossy().into_owned(), key_path.to_string_lossy().into_owned());
+        let acceptor_factory = AcceptorFactory::new(&security).unwrap();
+        assert!(!acceptor_factory.reload_if_modified().unwrap());
+
+        // Only half of the new identity has been written, so the current certificate is kept. The new file has the
+        // same modification time as the old one, as if both were written within the file system's resolution.
+        replace_file("tests/other_cert.pem", &cert_path, modified);
+        assert!(acceptor_factory.reload_if_modified().is_err());
+        assert!(!acceptor_factory.reload_if_modified().unwrap());
+
+        // The new key is older than the files it replaces, as if it was copied with its times preserved.
+        replace_file("tests/other_key.pem", &key_path, time::UNIX_EPOCH);
+        assert!(acceptor_factory.reload_if_modified().unwrap());
+        assert!(!acceptor_factory.reload_if_modified().unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn load_client_ca() {
+        let mut security = SecuritySettings::default();
+        security.set_client_verification(ClientVerification::Required);
+        security.set_client_ca_path(String::from("tests/client_ca.pem"));
+
+        assert!(AcceptorFactory::new(&security).is_ok());
+    }
+
+    fn client_verification_settings(client_verification: ClientVerification) -> SecuritySettings {
+        let mut security = SecuritySettings::pem(String::from("tests/cert.pem"), String::from("tests/key.pem"));
+        security.set_client_verification(client_verification);
+        security.set_client_ca_path(String::from("tests/client_ca.pem"));
+        security
+    }
+
+    #[test]
+    pub fn required_client_certificate() {
+        let acceptor_factory = AcceptorFactory::new(&client_verification_settings(ClientVerification::Required)).unwrap();
+
+        let (server, _client) = handshake(&acceptor_factory, "localhost", None);
+        assert!(server.is_none());
+
+        let (server, _client) = handshake(&acceptor_factory, "localhost", Some(("tests/client_cert.pem", "tests/client_key.pem")));
+        let peer_certificate = to_peer_certificate(&server.unwrap().ssl().peer_certificate().unwrap());
+        assert_eq!("CN=client.localhost, O=Osmium, C=GB", peer_certificate.get_subject());
+        assert_eq!(Some("client.localhost"), peer_certificate.get_common_name());
+        assert_eq!(&[String::from("client.localhost")], peer_certificate.get_dns_names());
+        assert_eq!(&["127.0.0.1".parse::<net::IpAddr>().unwrap()], peer_certificate.get_ip_addresses());
+    }
 
-        acceptor_builder.build()
+    #[test]
+    pub fn optional_client_certificate() {
+        let acceptor_factory = AcceptorFactory::new(&client_verification_settings(ClientVerification::Optional)).unwrap();
+
+        let (server, _client) = handshake(&acceptor_factory, "localhost", None);
+        assert!(server.unwrap().ssl().peer_certificate().is_none());
+
+        let (server, _client) = handshake(&acceptor_factory, "localhost", Some(("tests/client_cert.pem", "tests/client_key.pem")));
+        assert!(server.unwrap().ssl().peer_certificate().is_some());
+    }
+
+    #[test]
+    pub fn reject_client_verification_without_ca() {
+        let mut security = SecuritySettings::default();
+        security.set_client_verification(ClientVerification::Optional);
+
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::MissingClientCa) => {},
+            r => panic!("Expected a missing client CA but got {:?}", r.err())
+        }
+    }
+
+    #[test]
+    pub fn reject_invalid_cipher_list() {
+        let mut security = SecuritySettings::default();
+        security.set_cipher_list(String::from("NOT-A-CIPHER"));
+
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::InvalidCipherList(_)) => {},
+            r => panic!("Expected an invalid cipher list but got {:?}", r.err())
+        }
+    }
+
+    #[test]
+    pub fn http2_cipher_suite_black_list() {
+        assert!(!is_prohibited_cipher_suite("ECDHE-RSA-AES128-GCM-SHA256"));
+        assert!(!is_prohibited_cipher_suite("ECDHE-ECDSA-CHACHA20-POLY1305"));
+        assert!(!is_prohibited_cipher_suite("DHE-RSA-AES256-GCM-SHA384"));
+        assert!(!is_prohibited_cipher_suite("TLS_AES_128_GCM_SHA256"));
+
+        assert!(is_prohibited_cipher_suite("AES128-GCM-SHA256"));
+        assert!(is_prohibited_cipher_suite("ECDHE-RSA-AES128-SHA256"));
+        assert!(is_prohibited_cipher_suite("DHE-RSA-AES256-SHA"));
+        assert!(is_prohibited_cipher_suite("DES-CBC3-SHA"));
+    }
+
+    #[test]
+    pub fn http2_tls_versions() {
+        assert!(!is_prohibited_tls_version("TLSv1.2"));
+        assert!(!is_prohibited_tls_version("TLSv1.3"));
+        assert!(is_prohibited_tls_version("TLSv1.1"));
+        assert!(is_prohibited_tls_version("TLSv1"));
+    }
+
+    #[test]
+    pub fn reject_missing_certificate() {
+        let security = SecuritySettings::pem(String::from("tests/missing.pem"), String::from("tests/key.pem"));
+
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::ReadFailed(ref path, _)) if path == "tests/missing.pem" => {},
+            r => panic!("Expected the certificate not to be found but got {:?}", r.err())
+        }
+    }
+
+    #[test]
+    pub fn reject_incorrect_password() {
+        let security = SecuritySettings::pkcs12(String::from("tests/cert.pfx"), String::from("wrong"));
+
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::IncorrectPassword(_)) => {},
+            r => panic!("Expected an incorrect password but got {:?}", r.err())
+        }
+    }
+
+    #[test]
+    pub fn reject_certificate_in_wrong_format() {
+        let security = SecuritySettings::pem(String::from("tests/cert.der"), String::from("tests/key.pem"));
+
+        match AcceptorFactory::new(&security) {
+            Err(SecurityError::InvalidCertificate(_)) | Err(SecurityError::EmptyCertificateChain) => {},
+            r => panic!("Expected an invalid certificate but got {:?}", r.err())
+        }
     }
 }
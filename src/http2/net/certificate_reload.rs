@@ -0,0 +1,144 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::io;
+use std::time;
+
+// tokio
+use futures::{Future, Stream};
+use tokio_core::reactor;
+
+// futures-cpupool
+use futures_cpupool::CpuPool;
+
+// osmium
+use super::acceptor_factory::{AcceptorFactory, SecurityError};
+
+/// Reloads the certificates of a server's TLS listeners while it is running, for example after they have been
+/// renewed. The handle can be cloned and sent to other threads.
+///
+/// New connections are accepted with the reloaded certificates. Open connections aren't affected.
+#[derive(Clone)]
+pub struct CertificateReloadHandle {
+    acceptor_factories: Vec<AcceptorFactory>
+}
+
+impl CertificateReloadHandle {
+    pub fn new(acceptor_factories: Vec<AcceptorFactory>) -> Self {
+        CertificateReloadHandle {
+            acceptor_factories: acceptor_factories
+        }
+    }
+
+    /// Load the certificate files for every TLS listener again. A listener whose files can't be loaded keeps its
+    /// current certificate, and the first error is returned once every listener has been tried.
+    pub fn reload(&self) -> Result<(), SecurityError> {
+        let mut result = Ok(());
+
+        for acceptor_factory in &self.acceptor_factories {
+            if let Err(e) = acceptor_factory.reload() {
+                error!("Failed to reload a certificate, the current one will be kept {:?}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Check the certificate files at an interval, and reload the ones which have been modified.
+///
+/// Reading the files and building the acceptors blocks, so that's done on the workers. The event loop only swaps
+/// the reloaded certificates in. The next check doesn't start until the last one has finished.
+pub fn watch(acceptor_factories: Vec<AcceptorFactory>, interval: time::Duration, worker_pool: &CpuPool, handle: &reactor::Handle) -> io::Result<()> {
+    let worker_pool = worker_pool.clone();
+
+    let watcher = reactor::Interval::new(interval, handle)?
+        .map_err(|e| {
+            error!("Stopped watching the certificate files [{}]", e);
+        })
+        .for_each(move |_| {
+            let loading_factories = acceptor_factories.clone();
+            let acceptor_factories = acceptor_factories.clone();
+
+            worker_pool.spawn_fn(move || {
+                let reloaded: Vec<_> = loading_factories.iter().map(|acceptor_factory| acceptor_factory.load_if_modified()).collect();
+                Ok::<_, ()>(reloaded)
+            })
+            .map(move |reloaded| {
+                for (acceptor_factory, reloaded) in acceptor_factories.iter().zip(reloaded) {
+                    match reloaded.map(|reloaded| acceptor_factory.use_reloaded(reloaded)) {
+                        Some(Ok(())) => info!("Reloaded a certificate which was modified"),
+                        Some(Err(e)) => error!("Failed to reload a modified certificate, the current one will be kept {:?}", e),
+                        None => {}
+                    }
+                }
+            })
+        });
+
+    handle.spawn(watcher);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::watch;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    use futures_cpupool::CpuPool;
+    use tokio_core::reactor;
+
+    use http2::net::acceptor_factory::AcceptorFactory;
+    use shared::server_settings::SecuritySettings;
+
+    #[test]
+    pub fn watch_reloads_modified_certificate() {
+        let dir = env::temp_dir().join(format!("osmium_watch_reloads_modified_certificate_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::copy("tests/cert.pem", &cert_path).unwrap();
+        fs::copy("tests/key.pem", &key_path).unwrap();
+
+        let security = SecuritySettings::pem(cert_path.to_string_lossy().into_owned(), key_path.to_string_lossy().into_owned());
+        let acceptor_factory = AcceptorFactory::new(&security).unwrap();
+
+        let mut event_loop = reactor::Core::new().unwrap();
+        watch(vec![acceptor_factory.clone()], Duration::from_millis(10), &CpuPool::new(1), &event_loop.handle()).unwrap();
+
+        fs::copy("tests/other_cert.pem", &cert_path).unwrap();
+        fs::copy("tests/other_key.pem", &key_path).unwrap();
+
+        // Once the watcher has swapped in the new certificate, the files are the ones which were last loaded.
+        let start = Instant::now();
+        while acceptor_factory.load_if_modified().is_some() && start.elapsed() < Duration::from_secs(10) {
+            event_loop.turn(Some(Duration::from_millis(10)));
+        }
+        assert!(acceptor_factory.load_if_modified().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
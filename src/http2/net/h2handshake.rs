@@ -15,29 +15,245 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium. If not, see <http://www.gnu.org/licenses/>.
 
-use futures::future::{self, Future};
-use tokio_core::net as tokio_net;
+use futures::future::{self, Future, Loop, loop_fn};
+use shared::transport::Transport;
 use std::io;
-use tokio_openssl::SslStream;
-use http2::frame as framing;
+use tokio_io::{self, AsyncRead};
+use http2::frame::{self as framing, CompressibleHttpFrame};
+use http2::stream as streaming;
+use http2::error;
+use http2::settings;
 
-// Sadly rust doesn't completely support generic traits. To keep the code simpler this is naming the transport directly.
-// It would be nice if the code at this level wasn't tied to the underlying transport but the traits needed are in tokio_io
-// anyway so...
+pub const PREFACE: [u8; 24] = [0x50, 0x52, 0x49, 0x20, 0x2a, 0x20, 0x48, 0x54, 0x54, 0x50, 0x2f, 0x32, 0x2e, 0x30, 0x0d, 0x0a, 0x0d, 0x0a, 0x53, 0x4d, 0x0d, 0x0a, 0x0d, 0x0a];
+
+// The handshakes work on any transport, so that the TLS implementation is chosen by the listener and the
+// connection code doesn't need to know which one it's reading from.
 
 pub trait H2Handshake {
-    fn attempt_handshake(&self, stream: tokio_net::TcpStream, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>;
+    fn attempt_handshake(&self, stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>;
 }
 
-#[derive(Debug)]
 pub struct HandshakeCompletion
 {
-    pub stream: SslStream<tokio_net::TcpStream>,
-    pub settings_frame: framing::settings::SettingsFrame
+    pub stream: Box<Transport>,
+    pub settings_frame: framing::settings::SettingsFrame,
+    pub upgrade: Option<UpgradeRequest>
 }
 
+/// The HTTP/1.1 request which caused the connection to be upgraded to HTTP/2 (3.2).
 #[derive(Debug)]
+pub struct UpgradeRequest
+{
+    /// The settings from the HTTP2-Settings header. The 101 response acknowledges these implicitly.
+    pub settings_frame: framing::settings::SettingsFrame,
+    /// The request which was upgraded. It must be responded to on stream 1.
+    pub request: streaming::StreamRequest
+}
+
 pub enum HandshakeError
 {
-    DidNotUpgrade(SslStream<tokio_net::TcpStream>, Vec<u8>)
+    DidNotUpgrade(Box<Transport>, Vec<u8>)
+}
+
+/// Reads the SETTINGS frame which must follow the client connection preface, then writes the local
+/// settings to the client. This is the same for every handshake once the preface has been read.
+///
+/// If the client sends anything other than a valid SETTINGS frame, the local settings are followed by a
+/// GOAWAY and the handshake fails (3.5).
+pub fn read_settings_and_respond(stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = (Box<Transport>, framing::settings::SettingsFrame), Error = io::Error>>
+{
+    let header_buf = [0; framing::FRAME_HEADER_SIZE];
+
+    Box::new(
+        tokio_io::io::read_exact(stream, header_buf)
+        .and_then(|(stream, buf)| {
+            let frame_header = framing::decompress_frame_header(buf.to_vec());
+
+            // Check the header before reading the payload, so that a huge length isn't allocated.
+            if let Err(e) = check_settings_frame_header(&frame_header) {
+                return future::Either::A(reject_settings(stream, settings_response, e));
+            }
+
+            let mut payload_buf = Vec::with_capacity(frame_header.length as usize);
+            payload_buf.resize(frame_header.length as usize, 0);
+            future::Either::B(
+                tokio_io::io::read_exact(stream, payload_buf)
+                .and_then(move |(stream, buf)| {
+                    match framing::settings::SettingsFrame::new(&frame_header, &mut buf.into_iter()).and_then(check_not_acknowledge) {
+                        Ok(settings_frame) => {
+                            let response = settings_response.compress_frame(0x0);
+                            future::Either::A(
+                                tokio_io::io::write_all(stream, response)
+                                .map(move |(stream, _)| {
+                                    (stream, settings_frame)
+                                })
+                            )
+                        },
+                        Err(e) => {
+                            future::Either::B(reject_settings(stream, settings_response, e))
+                        }
+                    }
+                })
+            )
+        })
+    )
+}
+
+// (3.5) The first frame from the client must be a SETTINGS frame. The client can't have been told about a max frame
+// size larger than the initial value yet.
+fn check_settings_frame_header(frame_header: &framing::FrameHeader) -> Result<(), error::HttpError> {
+    if frame_header.frame_type != Some(framing::FrameType::Settings) {
+        return Err(error::HttpError::ConnectionError(
+            error::ErrorCode::ProtocolError,
+            error::ErrorName::ExpectedSettingsFrameAfterPreface
+        ));
+    }
+
+    if frame_header.stream_id != 0x0 {
+        return Err(error::HttpError::ConnectionError(
+            error::ErrorCode::ProtocolError,
+            error::ErrorName::StreamIdentifierOnConnectionFrame
+        ));
+    }
+
+    if frame_header.length > settings::INITIAL_MAX_FRAME_SIZE {
+        return Err(error::HttpError::ConnectionError(
+            error::ErrorCode::FrameSizeError,
+            error::ErrorName::FramePayloadLargerThanSettingsValue
+        ));
+    }
+
+    Ok(())
+}
+
+// The server hasn't sent its settings yet, so there is nothing for the client to acknowledge.
+fn check_not_acknowledge(settings_frame: framing::settings::SettingsFrame) -> Result<framing::settings::SettingsFrame, error::HttpError> {
+    if settings_frame.is_acknowledge() {
+        return Err(error::HttpError::ConnectionError(
+            error::ErrorCode::ProtocolError,
+            error::ErrorName::ExpectedSettingsFrameAfterPreface
+        ));
+    }
+
+    Ok(settings_frame)
+}
+
+// The server connection preface has to be the first frame sent, so the settings are sent before the GOAWAY.
+fn reject_settings<T>(stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>, e: error::HttpError) -> Box<Future<Item = T, Error = io::Error>>
+    where T: 'static
+{
+    let message = format!("invalid SETTINGS frame after the connection preface {:?}", e);
+
+    let mut response = settings_response.compress_frame(0x0);
+    response.extend(Box::new(framing::go_away::GoAwayFrameCompressModel::new(0x0, e)).compress_frame(0x0));
+
+    Box::new(
+        tokio_io::io::write_all(stream, response)
+        .then(move |_| {
+            Err(io::Error::new(io::ErrorKind::InvalidData, message))
+        })
+    )
+}
+
+/// Reads the client connection preface (3.5), stopping as soon as the bytes received can't be the start of it.
+/// Yields everything which was read and whether it was the preface, so that the caller can hand the bytes to
+/// an HTTP/1.1 handler otherwise.
+pub fn read_preface<S>(stream: S) -> Box<Future<Item = (S, Vec<u8>, bool), Error = io::Error>>
+    where S: 'static + AsyncRead
+{
+    Box::new(
+        loop_fn((stream, Vec::new()), |(stream, mut received): (S, Vec<u8>)| {
+            // Never read past the end of the preface, anything after it belongs to the connection.
+            let read_size = PREFACE.len() - received.len();
+
+            tokio_io::io::read(stream, vec![0; read_size])
+            .and_then(move |(stream, buf, n)| {
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during handshake"));
+                }
+
+                received.extend_from_slice(&buf[0..n]);
+
+                if !PREFACE.starts_with(&received) {
+                    Ok(Loop::Break((stream, received, false)))
+                }
+                else if received.len() == PREFACE.len() {
+                    Ok(Loop::Break((stream, received, true)))
+                }
+                else {
+                    Ok(Loop::Continue((stream, received)))
+                }
+            })
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_settings_and_respond, PREFACE};
+
+    use futures::Future;
+    use http2::frame::settings::SettingsFrameCompressModel;
+    use http2::frame::CompressibleHttpFrame;
+    use http2::error::ErrorCode;
+    use shared::transport::memory::MemoryTransport;
+
+    // Yields the error code of the GOAWAY which followed the server's settings, if there was one.
+    fn respond_to(frame: Vec<u8>) -> Option<u32> {
+        let (transport, output) = MemoryTransport::new(frame);
+
+        let result = read_settings_and_respond(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait();
+
+        let output = output.borrow();
+        let settings = Box::new(SettingsFrameCompressModel::new()).compress_frame(0x0);
+        assert_eq!(settings.as_slice(), &output[0..settings.len()]);
+
+        let go_away = &output[settings.len()..];
+        if go_away.is_empty() {
+            assert!(result.is_ok());
+            return None;
+        }
+
+        assert!(result.is_err());
+        assert_eq!(0x7, go_away[3]);
+        Some(((go_away[13] as u32) << 24) + ((go_away[14] as u32) << 16) + ((go_away[15] as u32) << 8) + go_away[16] as u32)
+    }
+
+    #[test]
+    pub fn accept_settings() {
+        // MAX_CONCURRENT_STREAMS of 100.
+        assert_eq!(None, respond_to(vec![0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100]));
+    }
+
+    #[test]
+    pub fn reject_other_frame_type() {
+        // A PING in place of the SETTINGS.
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), respond_to(vec![0, 0, 8, 0x6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    pub fn reject_settings_acknowledgement() {
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), respond_to(vec![0, 0, 0, 0x4, 0x1, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    pub fn reject_settings_on_stream() {
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), respond_to(vec![0, 0, 0, 0x4, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    pub fn reject_settings_larger_than_max_frame_size() {
+        // Only the header is sent, the payload must not be waited for.
+        assert_eq!(Some(u32::from(ErrorCode::FrameSizeError)), respond_to(vec![0, 0x40, 0x02, 0x4, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    pub fn reject_truncated_settings_parameter() {
+        assert_eq!(Some(u32::from(ErrorCode::FrameSizeError)), respond_to(vec![0, 0, 4, 0x4, 0, 0, 0, 0, 0, 0, 3, 0, 0]));
+    }
+
+    #[test]
+    pub fn reject_repeated_preface() {
+        assert_eq!(Some(u32::from(ErrorCode::ProtocolError)), respond_to(PREFACE.to_vec()));
+    }
 }
@@ -0,0 +1,277 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+use super::h2handshake::{self, HandshakeCompletion, HandshakeError};
+use super::upgrade;
+
+use futures::future::{self, Future, Loop, loop_fn};
+use shared::transport::Transport;
+use shared::timeout::{self, Deadline};
+use std::io;
+use tokio_io;
+use http2::frame as framing;
+
+// An upgrade request which hasn't finished its headers after this many bytes is not going to be accepted.
+const MAX_UPGRADE_REQUEST_HEAD_SIZE: usize = 8192;
+
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Handshake for cleartext HTTP/2. 
+/// 
+/// Clients with prior knowledge that the server supports HTTP/2 (3.4) send the connection preface 
+/// straight away and it is read directly off the TCP stream. Otherwise the client can send an HTTP/1.1 
+/// request asking to upgrade to h2c (3.2), which is answered with 101 Switching Protocols. Any other
+/// HTTP/1.1 request fails the handshake, so that the connection can be served as HTTP/1.1 instead.
+/// 
+/// This is intended for running behind something which has already terminated TLS.
+pub struct HttpH2Handshake {
+    preface_deadline: Option<Deadline>
+}
+
+impl HttpH2Handshake {
+    pub fn new() -> Self {
+        HttpH2Handshake {
+            preface_deadline: None
+        }
+    }
+
+    /// Limit how long the client may take to send the connection preface or its HTTP/1.1 request. An upgrade
+    /// must have got as far as the client's settings by the deadline too.
+    pub fn set_preface_deadline(&mut self, preface_deadline: Deadline) {
+        self.preface_deadline = Some(preface_deadline);
+    }
+}
+
+enum ConnectionStart {
+    Preface,
+    Upgrade(upgrade::UpgradeRequestHead),
+    Other
+}
+
+impl h2handshake::H2Handshake for HttpH2Handshake
+{
+    fn attempt_handshake(&self, stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>
+    {
+        let handshake_future = read_connection_start(stream)
+            .and_then(move |(stream, received, connection_start)| {
+                let handshake_future: Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>> = match connection_start {
+                    ConnectionStart::Preface => {
+                        Box::new(
+                            h2handshake::read_settings_and_respond(stream, settings_response)
+                            .map(|(stream, settings_frame)| {
+                                future::ok(HandshakeCompletion { stream, settings_frame, upgrade: None })
+                            })
+                        )
+                    },
+                    ConnectionStart::Upgrade(upgrade_request_head) => {
+                        upgrade_connection(stream, received, upgrade_request_head, settings_response)
+                    },
+                    ConnectionStart::Other => {
+                        Box::new(
+                            future::ok(future::err(HandshakeError::DidNotUpgrade(stream, received)))
+                        )
+                    }
+                };
+
+                handshake_future
+            });
+
+        timeout::limit(self.preface_deadline.as_ref(), handshake_future, "connection preface")
+    }
+}
+
+// Reads until either the connection preface or the head of an HTTP/1.1 request has been received.
+fn read_connection_start(stream: Box<Transport>) -> Box<Future<Item = (Box<Transport>, Vec<u8>, ConnectionStart), Error = io::Error>>
+{
+    Box::new(
+        loop_fn((stream, Vec::new()), |(stream, mut received): (Box<Transport>, Vec<u8>)| {
+            // While the bytes received could still be the preface, don't read past the end of it. Anything
+            // after the preface belongs to the connection.
+            let read_size = if is_preface_prefix(&received) {
+                h2handshake::PREFACE.len() - received.len()
+            }
+            else {
+                READ_CHUNK_SIZE
+            };
+
+            tokio_io::io::read(stream, vec![0; read_size])
+            .and_then(move |(stream, buf, n)| {
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during handshake"));
+                }
+
+                received.extend_from_slice(&buf[0..n]);
+
+                if received.as_slice() == &h2handshake::PREFACE[..] {
+                    return Ok(Loop::Break((stream, received, ConnectionStart::Preface)));
+                }
+
+                if is_preface_prefix(&received) {
+                    return Ok(Loop::Continue((stream, received)));
+                }
+
+                match upgrade::parse_upgrade_request(&received) {
+                    upgrade::UpgradeParseResult::Upgrade(upgrade_request_head) => {
+                        Ok(Loop::Break((stream, received, ConnectionStart::Upgrade(upgrade_request_head))))
+                    },
+                    upgrade::UpgradeParseResult::NotUpgrade => {
+                        Ok(Loop::Break((stream, received, ConnectionStart::Other)))
+                    },
+                    upgrade::UpgradeParseResult::Incomplete => {
+                        if received.len() > MAX_UPGRADE_REQUEST_HEAD_SIZE {
+                            Ok(Loop::Break((stream, received, ConnectionStart::Other)))
+                        }
+                        else {
+                            Ok(Loop::Continue((stream, received)))
+                        }
+                    }
+                }
+            })
+        })
+    )
+}
+
+// (3.2) Reads the rest of the upgrade request, switches protocols and then expects the client connection preface.
+fn upgrade_connection(
+    stream: Box<Transport>,
+    mut received: Vec<u8>,
+    upgrade_request_head: upgrade::UpgradeRequestHead,
+    settings_response: Box<framing::settings::SettingsFrameCompressModel>
+) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>
+{
+    // Some or all of the request body may have been read with the request head. The content length is no more than
+    // upgrade::MAX_UPGRADE_BODY_SIZE, so the rest of the body can be read in one go.
+    let mut body = received.split_off(upgrade_request_head.get_head_length());
+    body.truncate(upgrade_request_head.get_content_length());
+    let remaining_body_length = upgrade_request_head.get_content_length() - body.len();
+
+    Box::new(
+        tokio_io::io::read_exact(stream, vec![0; remaining_body_length])
+        .and_then(move |(stream, remaining_body)| {
+            body.extend(remaining_body);
+            let upgrade_request = upgrade_request_head.into_upgrade_request(body);
+
+            tokio_io::io::write_all(stream, upgrade::SWITCHING_PROTOCOLS_RESPONSE)
+            .map(move |(stream, _)| {
+                (stream, upgrade_request)
+            })
+        })
+        .and_then(|(stream, upgrade_request)| {
+            // (3.5) Upon receipt of the 101 response, the client MUST send a connection preface.
+            tokio_io::io::read_exact(stream, [0; 24])
+            .map(move |(stream, buf)| {
+                (stream, buf, upgrade_request)
+            })
+        })
+        .and_then(|(stream, buf, upgrade_request)| {
+            if buf == h2handshake::PREFACE {
+                Ok((stream, upgrade_request))
+            }
+            else {
+                // The protocol has already been switched, so the connection can't go back to HTTP/1.1.
+                Err(io::Error::new(io::ErrorKind::InvalidData, "the client did not send the connection preface after switching protocols"))
+            }
+        })
+        .and_then(move |(stream, upgrade_request)| {
+            h2handshake::read_settings_and_respond(stream, settings_response)
+            .map(move |(stream, settings_frame)| {
+                future::ok(HandshakeCompletion { stream, settings_frame, upgrade: Some(upgrade_request) })
+            })
+        })
+    )
+}
+
+fn is_preface_prefix(received: &[u8]) -> bool {
+    received.len() < h2handshake::PREFACE.len() && h2handshake::PREFACE.starts_with(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpH2Handshake;
+
+    use futures::Future;
+    use http2::frame::settings::SettingsFrameCompressModel;
+    use http2::frame::CompressibleHttpFrame;
+    use http2::net::h2handshake::{self, H2Handshake, HandshakeError};
+    use shared::transport::memory::MemoryTransport;
+
+    #[test]
+    pub fn handshake_with_prior_knowledge() {
+        let mut input = h2handshake::PREFACE.to_vec();
+        input.extend(Box::new(SettingsFrameCompressModel::new()).compress_frame(0x0));
+        let (transport, output) = MemoryTransport::new(input);
+
+        let handshake_result = HttpH2Handshake::new().attempt_handshake(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait().unwrap().wait();
+
+        match handshake_result {
+            Ok(handshake_completion) => {
+                assert!(handshake_completion.upgrade.is_none());
+                assert!(!handshake_completion.stream.is_secure());
+            },
+            Err(_) => panic!("expected the handshake to complete")
+        }
+
+        // The server's settings are sent once the client's have been read.
+        assert_eq!(Box::new(SettingsFrameCompressModel::new()).compress_frame(0x0), *output.borrow());
+    }
+
+    #[test]
+    pub fn http1_request_does_not_upgrade() {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+        let (transport, output) = MemoryTransport::new(request.clone());
+
+        let handshake_result = HttpH2Handshake::new().attempt_handshake(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait().unwrap().wait();
+
+        match handshake_result {
+            Err(HandshakeError::DidNotUpgrade(_, received)) => assert_eq!(request, received),
+            Ok(_) => panic!("expected the handshake not to upgrade")
+        }
+
+        assert!(output.borrow().is_empty());
+    }
+
+    #[test]
+    pub fn upgrade_request_with_oversized_body_does_not_upgrade() {
+        let request = b"POST / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\nContent-Length: 18446744073709551615\r\n\r\n".to_vec();
+        let (transport, output) = MemoryTransport::new(request.clone());
+
+        let handshake_result = HttpH2Handshake::new().attempt_handshake(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait().unwrap().wait();
+
+        match handshake_result {
+            Err(HandshakeError::DidNotUpgrade(_, received)) => assert_eq!(request, received),
+            Ok(_) => panic!("expected the handshake not to upgrade")
+        }
+
+        assert!(output.borrow().is_empty());
+    }
+
+    #[test]
+    pub fn upgrade_request_without_connection_options_does_not_upgrade() {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n".to_vec();
+        let (transport, output) = MemoryTransport::new(request.clone());
+
+        let handshake_result = HttpH2Handshake::new().attempt_handshake(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait().unwrap().wait();
+
+        // The request is served as HTTP/1.1 instead.
+        match handshake_result {
+            Err(HandshakeError::DidNotUpgrade(_, received)) => assert_eq!(request, received),
+            Ok(_) => panic!("expected the handshake not to upgrade")
+        }
+
+        assert!(output.borrow().is_empty());
+    }
+}
@@ -19,82 +19,157 @@ use super::h2handshake::{self, HandshakeCompletion, HandshakeError};
 use super::acceptor_factory;
 
 use futures::future::{self, Future};
-use tokio_core::net as tokio_net;
+use shared::transport::{Transport, TlsAcceptor};
+use shared::timeout::{self, Deadline};
 use std::io;
+use std::sync::Arc;
 use tokio_io;
-use tokio_openssl::SslAcceptorExt;
-use http2::frame::{self as framing, CompressibleHttpFrame};
-
-const PREFACE: [u8; 24] = [0x50, 0x52, 0x49, 0x20, 0x2a, 0x20, 0x48, 0x54, 0x54, 0x50, 0x2f, 0x32, 0x2e, 0x30, 0x0d, 0x0a, 0x0d, 0x0a, 0x53, 0x4d, 0x0d, 0x0a, 0x0d, 0x0a];
+use http2::frame as framing;
 
+/// Handshake for HTTP/2 over TLS. The TLS handshake is done by the listener's acceptor, then the client
+/// either negotiates h2 with ALPN, negotiates HTTP/1.1 or sends the connection preface without ALPN.
 pub struct HttpsH2Handshake {
-    acceptor_factory: acceptor_factory::AcceptorFactory
+    acceptor: Arc<TlsAcceptor + Send + Sync>,
+    tls_handshake_deadline: Option<Deadline>,
+    preface_deadline: Option<Deadline>
 }
 
 impl HttpsH2Handshake {
-    pub fn new(acceptor_factory: acceptor_factory::AcceptorFactory) -> Self {
+    pub fn new(acceptor: Arc<TlsAcceptor + Send + Sync>) -> Self {
         HttpsH2Handshake {
-            acceptor_factory: acceptor_factory
+            acceptor: acceptor,
+            tls_handshake_deadline: None,
+            preface_deadline: None
         }
     }
+
+    /// Limit how long the TLS handshake may take.
+    pub fn set_tls_handshake_deadline(&mut self, tls_handshake_deadline: Deadline) {
+        self.tls_handshake_deadline = Some(tls_handshake_deadline);
+    }
+
+    /// Limit how long the client may take to choose a protocol once the TLS handshake is done.
+    pub fn set_preface_deadline(&mut self, preface_deadline: Deadline) {
+        self.preface_deadline = Some(preface_deadline);
+    }
 }
 
 impl h2handshake::H2Handshake for HttpsH2Handshake
 {
-    fn attempt_handshake(&self, stream: tokio_net::TcpStream, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>
+    fn attempt_handshake(&self, stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>
     {
-        let acceptor = self.acceptor_factory.make_acceptor();
+        let preface_deadline = self.preface_deadline.clone();
 
         Box::new(
-            acceptor.accept_async(stream)
-            .map_err(|e| {
-                println!("accept error {:?}", e);
-                io::Error::new(io::ErrorKind::Other, e)
-            })
-            .and_then(|stream| {
-                let buf = [0; 24];
-                tokio_io::io::read_exact(stream, buf)
-            })
-            .and_then(|(stream, buf)| {
-                if buf == PREFACE {
-                    let header_buf = [0; 9];
-                    let handshake_settings_future: Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>> = 
-                    Box::new(
-                        tokio_io::io::read_exact(stream, header_buf)
-                        .and_then(|(stream, buf)| {
-                            let frame_header = framing::decompress_frame_header(buf.to_vec());
-
-                            let mut payload_buf = Vec::with_capacity(frame_header.length as usize);
-                            payload_buf.resize(frame_header.length as usize, 0);
-                            future::ok(frame_header)
-                            .join(
-                                tokio_io::io::read_exact(stream, payload_buf)
-                            )
-                        })
-                        .and_then(move |(frame_header, (stream, buf))| {
-                            // TODO need to check frame type and stream id
-                            // TODO error is ignored (doing this to get the error handling below checked in)
-                            let settings_frame = framing::settings::SettingsFrame::new(&frame_header, &mut buf.to_vec().into_iter()).unwrap();
-
-                            let response = Box::new(settings_response).compress_frame(0x0);
-                            future::ok(settings_frame)
-                            .join(
-                                tokio_io::io::write_all(stream, response)
-                            )
-                        })
-                        .map(|(settings_frame, (stream, _))| {
-                            future::ok(HandshakeCompletion { stream, settings_frame })
-                        })
-                    );
-
-                    handshake_settings_future
-                }
-                else {
-                    Box::new(
-                        future::ok(future::err(HandshakeError::DidNotUpgrade(stream, buf.to_vec())))
-                    )
-                }
+            timeout::limit(self.tls_handshake_deadline.as_ref(), self.acceptor.accept(stream), "TLS handshake")
+            .and_then(move |stream| {
+                let alpn_protocol = stream.alpn_protocol();
+
+                let handshake_future: Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>> = match alpn_protocol {
+                    Some(ref protocol) if protocol.as_slice() == acceptor_factory::ALPN_HTTP_1_1 => {
+                        // The client has chosen HTTP/1.1, so there won't be a preface.
+                        Box::new(
+                            future::ok(future::err(HandshakeError::DidNotUpgrade(stream, Vec::new())))
+                        )
+                    },
+                    Some(_) => {
+                        // (3.3) Having negotiated h2, the client must send the connection preface.
+                        Box::new(
+                            tokio_io::io::read_exact(stream, [0; 24])
+                            .and_then(move |(stream, buf)| {
+                                if buf == h2handshake::PREFACE {
+                                    Ok((stream, settings_response))
+                                }
+                                else {
+                                    Err(io::Error::new(io::ErrorKind::InvalidData, "negotiated h2 but the client did not send the connection preface"))
+                                }
+                            })
+                            .and_then(|(stream, settings_response)| {
+                                complete_handshake(stream, settings_response)
+                            })
+                        )
+                    },
+                    None => {
+                        // Without ALPN the client may be speaking either protocol.
+                        Box::new(
+                            h2handshake::read_preface(stream)
+                            .and_then(move |(stream, received, is_preface)| {
+                                let handshake_future: Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>> = if is_preface {
+                                    complete_handshake(stream, settings_response)
+                                }
+                                else {
+                                    Box::new(
+                                        future::ok(future::err(HandshakeError::DidNotUpgrade(stream, received)))
+                                    )
+                                };
+
+                                handshake_future
+                            })
+                        )
+                    }
+                };
+
+                timeout::limit(preface_deadline.as_ref(), handshake_future, "connection preface")
             })
         )
     }
 }
+
+fn complete_handshake(stream: Box<Transport>, settings_response: Box<framing::settings::SettingsFrameCompressModel>) -> Box<Future<Item = future::FutureResult<HandshakeCompletion, HandshakeError>, Error = io::Error>>
+{
+    Box::new(
+        h2handshake::read_settings_and_respond(stream, settings_response)
+        .map(|(stream, settings_frame)| {
+            future::ok(HandshakeCompletion { stream, settings_frame, upgrade: None })
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpsH2Handshake;
+
+    use std::io;
+    use std::sync::Arc;
+
+    use futures::future::{self, Future};
+    use http2::frame::settings::SettingsFrameCompressModel;
+    use http2::frame::CompressibleHttpFrame;
+    use http2::net::acceptor_factory;
+    use http2::net::h2handshake::{self, H2Handshake, HandshakeError};
+    use shared::transport::{Transport, TlsAcceptor};
+    use shared::transport::memory::MemoryTransport;
+
+    // Accepts the transport as it is, so that the tests can decide which protocol was negotiated.
+    struct PassThroughAcceptor;
+
+    impl TlsAcceptor for PassThroughAcceptor {
+        fn accept(&self, transport: Box<Transport>) -> Box<Future<Item = Box<Transport>, Error = io::Error>> {
+            Box::new(future::ok(transport))
+        }
+    }
+
+    fn accept_memory(alpn_protocol: &'static [u8], input: Vec<u8>) -> Result<bool, Vec<u8>> {
+        let (mut transport, _output) = MemoryTransport::new(input);
+        transport.set_alpn_protocol(alpn_protocol);
+
+        let handshake = HttpsH2Handshake::new(Arc::new(PassThroughAcceptor));
+        match handshake.attempt_handshake(Box::new(transport), Box::new(SettingsFrameCompressModel::new())).wait().unwrap().wait() {
+            Ok(handshake_completion) => Ok(handshake_completion.stream.is_secure()),
+            Err(HandshakeError::DidNotUpgrade(_, received)) => Err(received)
+        }
+    }
+
+    #[test]
+    pub fn negotiated_h2_completes_handshake() {
+        let mut input = h2handshake::PREFACE.to_vec();
+        input.extend(Box::new(SettingsFrameCompressModel::new()).compress_frame(0x0));
+
+        assert_eq!(Ok(true), accept_memory(acceptor_factory::ALPN_H2, input));
+    }
+
+    #[test]
+    pub fn negotiated_http1_does_not_upgrade() {
+        assert_eq!(Err(Vec::new()), accept_memory(acceptor_factory::ALPN_HTTP_1_1, b"GET / HTTP/1.1\r\n\r\n".to_vec()));
+    }
+}
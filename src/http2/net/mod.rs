@@ -17,30 +17,36 @@
 
 pub mod h2handshake;
 pub mod https;
+pub mod http;
+pub mod upgrade;
 pub mod acceptor_factory;
+pub mod certificate_reload;
+pub mod settings_update;
 pub mod shutdown_signal;
 
 // std
-use std::sync::mpsc;
 use std::sync::Arc;
-use std::rc::Rc;
 use std::marker;
 use std::mem;
-use std::net;
+use std::io;
+use std::panic;
+use std::time::{Duration, Instant};
 
 // tokio
 use futures::{Stream, Sink, Future, stream};
-use futures::future::{self, loop_fn};
+use futures::future::{self, loop_fn, Either};
 use futures::sync::mpsc as futures_mpsc;
 use tokio_core;
+use tokio_core::reactor;
 use tokio_io::io as tokio_io;
 use tokio_io::AsyncRead;
 
-// threadpool
-use threadpool::ThreadPool;
+// futures-cpupool
+use futures_cpupool::CpuPool;
 use std::convert;
 
 // osmium
+use http::connection as http1_connection;
 use http2::frame as framing;
 use http2::core::connection;
 use http2::hpack;
@@ -48,93 +54,368 @@ use shared::server_trait;
 use http2::stream as streaming;
 use http2::settings;
 use shared::server_settings;
+use shared::listener;
+use shared::shutdown;
+use shared::server_handle;
+use shared::connection_info::ConnectionInfo;
+use shared::transport::{Transport, TlsAcceptor};
+use shared::timeout::Deadline;
+
+// The connections which the server is given to serve, along with the addresses they came from.
+type Connections = Box<Stream<Item=(Box<Transport>, String), Error=io::Error>>;
+
+// The connection loop is given frames read from the network, and the responses which the application has
+// finished producing along with the push promises it made.
+enum ConnectionMessage {
+    Frame(framing::FrameHeader, Vec<u8>),
+    Response(streaming::StreamId, Vec<streaming::StreamRequest>, Result<streaming::StreamResponse, ()>),
+    ReadClosed,
+    GracefulShutdown,
+    SettingsAcknowledgeTimeout(usize),
+    IdleTimeout,
+    Ping,
+    UpdateSettings(Vec<settings::SettingsParameter>)
+}
+
+// The state which is carried between iterations of the connection loop.
+struct ConnectionLoopState<T> {
+    connection: connection::Connection,
+    app: Arc<T>,
+    worker_pool: CpuPool,
+    to_conn_loop: futures_mpsc::UnboundedSender<ConnectionMessage>,
+    settings_update_tx: settings_update::SettingsUpdateSender,
+    connection_info: Arc<ConnectionInfo>,
+    responses_in_flight: usize,
+    read_closed: bool,
+    // When a frame or a response was last received, which is how long the connection has been idle for. Pings
+    // only show that the client is there, so they don't count.
+    last_activity: Instant,
+    // Whether there has been any activity since the connection was last checked for whether it needs a ping.
+    active_since_ping_check: bool
+}
+
+impl<T, R, S> ConnectionLoopState<T>
+    where T: 'static + server_trait::AsyncOsmiumServer<Request=R, Response=S> + marker::Sync + marker::Send,
+          R: 'static + convert::From<streaming::StreamRequest>,
+          S: 'static + convert::Into<streaming::StreamResponse>
+{
+    // Passes each request which is ready to the application on a worker, so that a slow request doesn't hold up the 
+    // other streams on the connection. Each response is sent back to the connection loop when it is ready, which
+    // is where it gets encoded. That keeps all the header compression in the order the frames are sent and received.
+    //
+    // The connection loop waits for every response before it ends, so an application which panics is answered the
+    // same way as one whose future fails.
+    fn dispatch_requests(&mut self) {
+        while let Some(ready_request) = self.connection.pull_request() {
+            let app = self.app.clone();
+            let to_conn_loop = self.to_conn_loop.clone();
+            let connection_info = self.connection_info.clone();
+            let settings_update_tx = self.settings_update_tx.clone();
+            let stream_id = ready_request.stream_id;
+
+            self.worker_pool.spawn_fn(move || {
+                let process_future = future::lazy(move || {
+                    let mut stream_handle = streaming::StreamHandle::new(ready_request.push_enabled, ready_request.max_push_promises, connection_info, settings_update_tx);
+                    let response_future = app.process(ready_request.request.into(), Box::new(&mut stream_handle));
+                    let push_promises = stream_handle.into_push_promises();
+
+                    response_future.then(move |response| {
+                        Ok::<_, ()>((push_promises, response.map(|response| -> streaming::StreamResponse { response.into() })))
+                    })
+                });
+
+                panic::AssertUnwindSafe(process_future).catch_unwind().then(move |result| {
+                    let (push_promises, response) = match result {
+                        Ok(Ok(processed)) => processed,
+                        Ok(Err(())) => (Vec::new(), Err(())),
+                        Err(_) => {
+                            error!("The application panicked while processing stream [{}]", stream_id);
+                            (Vec::new(), Err(()))
+                        }
+                    };
+
+                    if to_conn_loop.unbounded_send(ConnectionMessage::Response(stream_id, push_promises, response)).is_err() {
+                        debug!("The connection loop has stopped, the response for stream [{}] will be dropped", stream_id);
+                    }
+                    Ok::<(), ()>(())
+                })
+            }).forget();
+
+            self.responses_in_flight += 1;
+        }
+    }
+
+    // Sends changes to the local settings to the client, which then has the same time to acknowledge them as it
+    // had for the settings sent during the handshake.
+    fn update_settings(&mut self, changes: Vec<settings::SettingsParameter>, acknowledge_timeout: Option<Duration>, handle: &reactor::Handle) {
+        if self.connection.is_shutdown_initiated() {
+            return;
+        }
+
+        let sequence_number = match self.connection.update_local_settings(&changes) {
+            Ok(sequence_number) => sequence_number,
+            Err(e) => {
+                error!("The settings changes are invalid and won't be sent {:?}", e);
+                return;
+            }
+        };
+
+        if let Some(acknowledge_timeout) = acknowledge_timeout {
+            match reactor::Timeout::new(acknowledge_timeout, handle) {
+                Ok(timeout) => {
+                    let to_conn_loop = self.to_conn_loop.clone();
+                    handle.spawn(timeout.then(move |_| {
+                        // The connection may have closed in the meantime.
+                        let _ = to_conn_loop.unbounded_send(ConnectionMessage::SettingsAcknowledgeTimeout(sequence_number));
+                        Ok(())
+                    }));
+                },
+                Err(e) => {
+                    error!("Failed to start the settings acknowledge timeout [{}]", e);
+                }
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ServerError {
-    InvalidSettingsConfiguration
+    InvalidSettingsConfiguration,
+    InvalidServerSettings(server_settings::SettingsError),
+    /// The certificate or private key for one of the listeners couldn't be loaded.
+    InvalidSecuritySettings(acceptor_factory::SecurityError),
+    /// The server couldn't listen on the configured address, or its event loop couldn't be created.
+    StartFailed(io::Error)
 }
 
 // TODO this doesn't really belong in the net package.
 pub struct Server<T, R, S>
-    where T: server_trait::OsmiumServer<Request=R, Response=S>, 
+    where T: server_trait::AsyncOsmiumServer<Request=R, Response=S>, 
           R: convert::From<streaming::StreamRequest>,
           S: convert::Into<streaming::StreamResponse>
 {
     hpack: hpack::HPack,
-    app: T,
-    security_settings: Option<server_settings::SecuritySettings>,
-    bind_address: net::SocketAddr,
-    local_settings: settings::Settings,
-    local_settings_frame: framing::settings::SettingsFrameCompressModel
+    app: Arc<T>,
+    server_settings: server_settings::ServerSettings,
+    // The local settings which new connections start with, and which the open connections are told about changes to.
+    settings_update_handle: settings_update::SettingsUpdateHandle,
+    // One for each listener, in the order they are given by the settings. Cleartext listeners don't have one.
+    tls_acceptors: Vec<Option<Arc<TlsAcceptor + Send + Sync>>>,
+    // The OpenSSL acceptors, which have certificates that can be reloaded.
+    acceptor_factories: Vec<acceptor_factory::AcceptorFactory>,
+    shutdown_handle: shutdown::ShutdownHandle,
+    shutdown_receiver: Option<shutdown::ShutdownReceiver>
 }
 
 impl<T, R, S> Server<T, R, S> 
-    where T: 'static + server_trait::OsmiumServer<Request=R, Response=S> + marker::Sync + marker::Send,
+    where T: 'static + server_trait::AsyncOsmiumServer<Request=R, Response=S> + marker::Sync + marker::Send,
           R: 'static + convert::From<streaming::StreamRequest>,
           S: 'static + convert::Into<streaming::StreamResponse>
 {
     pub fn new(app: T, server_settings: server_settings::ServerSettings) -> Result<Self, ServerError> {
-        // Read the settings configuration.
-        let mut local_settings = settings::Settings::spec_default();
-        if local_settings.apply_changes(server_settings.get_http2_settings()).is_err() {
-            return Err(ServerError::InvalidSettingsConfiguration);
-        };
+        server_settings.validate().map_err(ServerError::InvalidServerSettings)?;
 
-        let mut local_settings_frame = framing::settings::SettingsFrameCompressModel::new();
-        for setting in server_settings.get_http2_settings() {
-            local_settings_frame.add_parameter(setting.get_name(), setting.get_value());
+        // Read the settings configuration.
+        let settings_update_handle = settings_update::SettingsUpdateHandle::new(server_settings.get_http2_settings())
+            .map_err(|_| ServerError::InvalidSettingsConfiguration)?;
+
+        // Load the certificates up front, so that a bad certificate stops the server from being created.
+        let mut tls_acceptors = Vec::new();
+        let mut acceptor_factories = Vec::new();
+        for listener_settings in server_settings.get_listeners() {
+            let tls_acceptor: Option<Arc<TlsAcceptor + Send + Sync>> = match (listener_settings.get_tls_acceptor(), listener_settings.get_security()) {
+                (Some(tls_acceptor), _) => Some(tls_acceptor),
+                (None, Some(security)) => {
+                    let acceptor_factory = acceptor_factory::AcceptorFactory::new(&security).map_err(ServerError::InvalidSecuritySettings)?;
+                    acceptor_factories.push(acceptor_factory.clone());
+                    Some(Arc::new(acceptor_factory))
+                },
+                (None, None) => None
+            };
+            tls_acceptors.push(tls_acceptor);
         }
 
-        // Build the server bind address.
-        let addr = format!("{}:{}", server_settings.get_host(), server_settings.get_port()).parse().unwrap();
+        let (shutdown_handle, shutdown_receiver) = shutdown::new_shutdown_channel();
 
         Ok(Server {
             hpack: hpack::HPack::new(),
-            app: app,
-            security_settings: server_settings.get_security(),
-            bind_address: addr,
-            local_settings: local_settings,
-            local_settings_frame: local_settings_frame
+            app: Arc::new(app),
+            server_settings: server_settings,
+            settings_update_handle: settings_update_handle,
+            tls_acceptors: tls_acceptors,
+            acceptor_factories: acceptor_factories,
+            shutdown_handle: shutdown_handle,
+            shutdown_receiver: Some(shutdown_receiver)
         })
     }
 
-    // The start method consumes self so that it can ensure it can be used on the connection threads.
-    // The connection threads take a closure which must have static lifetime. If server startup 
-    // succeeds, then the a shared pointer to self is returned.
-    pub fn start_server(mut self) -> Arc<Box<Self>>
+    /// Get a handle which can be used to shut the server down gracefully once it has been started.
+    pub fn shutdown_handle(&self) -> shutdown::ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// Get a handle which can be used to reload the certificates of the TLS listeners once the server has been started.
+    pub fn certificate_reload_handle(&self) -> certificate_reload::CertificateReloadHandle {
+        certificate_reload::CertificateReloadHandle::new(self.acceptor_factories.clone())
+    }
+
+    /// Get a handle which can be used to change the HTTP/2 settings of the connections once the server has been started.
+    pub fn settings_update_handle(&self) -> settings_update::SettingsUpdateHandle {
+        self.settings_update_handle.clone()
+    }
+
+    // The start method consumes self so that it can ensure it can be shared by the connections and the workers.
+    // The futures spawned for each connection must have static lifetime. The server runs until it is shut 
+    // down using a shutdown handle, then the a shared pointer to self is returned. If the server can't listen
+    // on one of its addresses it fails straight away instead.
+    pub fn start_server(self) -> Result<Arc<Box<Self>>, ServerError>
     {
         // tokio event loop
-        let mut event_loop = tokio_core::reactor::Core::new().unwrap();
+        let event_loop = tokio_core::reactor::Core::new().map_err(ServerError::StartFailed)?;
+
+        // create the listeners for incoming connections
+        let listeners = listener::bind(&self.server_settings, &event_loop.handle()).map_err(ServerError::StartFailed)?;
+
+        Ok(self.run(event_loop, listeners))
+    }
+
+    /// Start the server on a background thread. This returns once the server is listening, with a handle
+    /// which has the addresses it is listening on and can be used to stop it.
+    pub fn spawn_server(self) -> Result<server_handle::ServerHandle, ServerError> {
+        let listeners = listener::bind_all(&self.server_settings).map_err(ServerError::StartFailed)?;
+        let shutdown_handle = self.shutdown_handle();
+
+        server_handle::spawn(listeners, shutdown_handle, move |event_loop, listeners| {
+            self.run(event_loop, listeners);
+        }).map_err(ServerError::StartFailed)
+    }
+
+    /// Serve a single connection over a transport which the caller has set up, for example a `MemoryTransport`
+    /// in a test, instead of listening for connections. The transport is handshaken like a connection to a
+    /// cleartext listener, so it can start with the HTTP/2 connection preface, an upgrade request or an HTTP/1.1
+    /// request. This returns once the connection has closed, or once the server has been shut down.
+    pub fn serve_transport(self, transport: Box<Transport>) -> Result<Arc<Box<Self>>, ServerError> {
+        let event_loop = tokio_core::reactor::Core::new().map_err(ServerError::StartFailed)?;
+
+        let worker_pool = listener::new_worker_pool(&self.server_settings);
+
+        let connections = stream::once(Ok::<_, io::Error>((transport, String::from("transport"))));
+
+        Ok(self.serve(event_loop, worker_pool, vec![(Box::new(connections) as Connections, None)]))
+    }
+
+    // The listeners must be in the order they are given by the settings, so that each one gets its own security settings.
+    fn run(mut self, event_loop: tokio_core::reactor::Core, listeners: Vec<listener::Listener>) -> Arc<Box<Self>> {
         let handle = event_loop.handle();
 
-        // create a listener for incoming tcp connections
-        let listener = tokio_core::net::TcpListener::bind(&self.bind_address, &handle).unwrap();
+        // Connections are driven by the event loop, so an idle connection doesn't hold onto a thread. Requests are
+        // processed by the application on these workers instead.
+        let worker_pool = listener::new_worker_pool(&self.server_settings);
+
+        let tls_acceptors = mem::replace(&mut self.tls_acceptors, Vec::new());
+
+        if let Some(interval) = self.server_settings.get_certificate_watch_interval() {
+            if !self.acceptor_factories.is_empty() {
+                if let Err(e) = certificate_reload::watch(self.acceptor_factories.clone(), interval, &worker_pool, &handle) {
+                    error!("Failed to start watching the certificate files [{}]", e);
+                }
+            }
+        }
+
+        let incoming: Vec<_> = listeners.into_iter().zip(tls_acceptors).map(|(listener, tls_acceptor)| {
+            let server_settings = self.server_settings.clone();
+            let connections = listener.incoming(&handle).map(move |(socket, remote_addr)| {
+                if let Err(e) = listener::configure_connection(&socket, &server_settings) {
+                    error!("Failed to configure the connection socket [{}]", e);
+                }
+
+                (Box::new(socket) as Box<Transport>, remote_addr)
+            });
+
+            (Box::new(connections) as Connections, tls_acceptor)
+        }).collect();
 
-        let thread_pool = Rc::new(ThreadPool::new(10));
+        self.serve(event_loop, worker_pool, incoming)
+    }
 
-        // Create the acceptor factory.
-        let mut security = None;
-        mem::swap(&mut security, &mut self.security_settings);
-        // TODO might not exist.
-        let acceptor_factory = acceptor_factory::AcceptorFactory::new(&security.unwrap());
+    // Serve the connections until the server shuts down, or until every stream of connections has ended and the
+    // connections it yielded have closed. Each stream is handshaken with its own TLS acceptor, if it has one.
+    fn serve(mut self, mut event_loop: tokio_core::reactor::Core, worker_pool: CpuPool, incoming: Vec<(Connections, Option<Arc<TlsAcceptor + Send + Sync>>)>) -> Arc<Box<Self>> {
+        let handle = event_loop.handle();
 
-        // TODO this should vary depending on the startup type chosen (http/https)
-        let handshake: Box<self::h2handshake::H2Handshake> = Box::new(https::HttpsH2Handshake::new(acceptor_factory));
+        let shutdown_receiver = self.shutdown_receiver.take().unwrap();
+        let open_connections = shutdown::OpenConnections::new();
 
         let server_instance = Arc::new(Box::new(self));
 
-        // get a stream (infinite iterator) of incoming connections
-        let server = listener.incoming().zip(stream::repeat(server_instance.clone())).for_each(|((socket, _remote_addr), server_instance)| {
+        // Every listener shares the application, the workers and the HPACK static table. A listener which fails
+        // doesn't stop the others.
+        let accepts: Vec<_> = incoming.into_iter().map(|(connections, tls_acceptor)| {
+            Self::accept_connections(connections, tls_acceptor, server_instance.clone(), worker_pool.clone(), &open_connections, handle.clone())
+                .or_else(|e| {
+                    error!("A listener has stopped accepting connections [{}]", e);
+                    Ok::<(), io::Error>(())
+                })
+        }).collect();
+
+        let server = future::join_all(accepts).map(|_| ());
+
+        // move the incoming connection stream onto the event loop
+        shutdown::run_until_shutdown(&mut event_loop, server, shutdown_receiver, &open_connections);
+
+        server_instance
+    }
+
+    // Accepts connections from one of the listeners, or the transport which is being served, until the server shuts
+    // down. Each connection is handshaken, then driven by its own loops on the event loop.
+    fn accept_connections<'a>(connections: Connections, tls_acceptor: Option<Arc<TlsAcceptor + Send + Sync>>, server_instance: Arc<Box<Self>>, worker_pool: CpuPool, open_connections: &'a shutdown::OpenConnections, handle: reactor::Handle) -> Box<Future<Item=(), Error=io::Error> + 'a> {
+        // Choose the handshake from the listener's security settings. Without security the server accepts
+        // cleartext connections from clients with prior knowledge of HTTP/2.
+        let tls_handshake_timeout = server_instance.server_settings.get_tls_handshake_timeout();
+        let preface_timeout = server_instance.server_settings.get_preface_timeout();
+        let handshake: Box<self::h2handshake::H2Handshake> = match tls_acceptor {
+            Some(tls_acceptor) => {
+                let mut handshake = https::HttpsH2Handshake::new(tls_acceptor);
+                if let Some(timeout) = tls_handshake_timeout {
+                    handshake.set_tls_handshake_deadline(Deadline::new(timeout, handle.clone()));
+                }
+                if let Some(timeout) = preface_timeout {
+                    handshake.set_preface_deadline(Deadline::new(timeout, handle.clone()));
+                }
+                Box::new(handshake)
+            },
+            None => {
+                let mut handshake = http::HttpH2Handshake::new();
+                if let Some(timeout) = preface_timeout {
+                    handshake.set_preface_deadline(Deadline::new(timeout, handle.clone()));
+                }
+                Box::new(handshake)
+            }
+        };
+
+        // Clients which fall back to HTTP/1.1 have the same time to send their first request.
+        let http1_preface_deadline = preface_timeout.map(|timeout| Deadline::new(timeout, handle.clone()));
+
+        // serve each of the incoming connections
+        Box::new(connections.zip(stream::repeat(server_instance)).for_each(move |((transport, _remote_addr), server_instance)| {
             debug!("Starting connection on {}", _remote_addr);
 
             let inner_handle = handle.clone();
-            
-            let handshake_future = handshake.attempt_handshake(socket, Box::new(server_instance.local_settings_frame.clone()))
+            let worker_pool = worker_pool.clone();
+            let tracked_connection = open_connections.track();
+            let http1_preface_deadline = http1_preface_deadline.clone();
+            let settings_update::SettingsSubscription {
+                settings: local_settings,
+                settings_frame: local_settings_frame,
+                update_rx: settings_update_rx,
+                update_tx: settings_update_tx
+            } = server_instance.settings_update_handle.subscribe();
+        
+            let handshake_future = handshake.attempt_handshake(transport, Box::new(local_settings_frame))
             .map_err(|e| {
                 error!("I/O error while attempting connection handshake {}", e);
             })
-            .join(Ok(thread_pool.clone()))
-            .map(move |(handshake_result, thread_pool)| {
+            .map(move |handshake_result| {
                 // Convert the future result to a standard result.
                 let handshake_result = handshake_result
                 .map_err(|handshake_error| {
@@ -144,59 +425,196 @@ impl<T, R, S> Server<T, R, S>
                     handshake_completion
                 })
                 .wait(); // safe to wait here as long as the handshake result is a future result.
-                
+            
                 match handshake_result {
                     Ok(mut handshake_completion) => {
                         // TODO (naming) really? temp_frame... fix me
                         let mut temp_frame = framing::settings::SettingsFrame::new_noop();
                         mem::swap(&mut handshake_completion.settings_frame, &mut temp_frame);
-                        
+
+                        let upgrade_request = handshake_completion.upgrade.take();
+                        let connection_info = handshake_completion.stream.connection_info();
+                        let security_error = handshake_completion.stream.http2_security_error();
+                    
                         let (reader, writer) = handshake_completion.stream.split();
 
                         let (shutdown_read_tx, shutdown_read_rx) = futures_mpsc::channel::<u8>(1);
-                        let (mut ftx, frx) = futures_mpsc::channel(5);
-                        let (tx, rx) = mpsc::channel::<(framing::FrameHeader, Vec<u8>)>();
-                        thread_pool.execute(move || {
-                            let mut connection = connection::Connection::new(
-                                server_instance.hpack.new_send_context(),
-                                server_instance.hpack.new_recv_context(),
-                                server_instance.local_settings.clone(),
-                                temp_frame,
-                                shutdown_signal::ShutdownSignaller::new(shutdown_read_tx.clone())
-                            );
-
-                            // Note that if the initial settings contain an error the connection will immediately initiate shutdown.
-                            // The documentation for iter() on a receiver states that it will never panic, just yield None when the 
-                            // sender has hung up.
-                            let mut msg_iter = rx.iter();
-                            'connection_loop: while let Some(msg) = msg_iter.next() {
-                                connection.recv(
-                                    framing::Frame {
-                                        header: msg.0,
-                                        payload: msg.1
-                                    },
-                                    &server_instance.app
-                                );
-                                
-                                while let Some(response_frame) = connection.pull_frame() {
-                                    ftx = match ftx.send(response_frame).wait() {
-                                        Ok(ftx) => ftx,
-                                        Err(e) => {
-                                            info!("Write error, will exit connection loop {:?}", e);
-                                            break 'connection_loop;
-                                        }
-                                    };
+                        let (ftx, frx) = futures_mpsc::channel(server_instance.server_settings.get_write_queue_depth());
+                        let (read_tx, read_rx) = futures_mpsc::channel(server_instance.server_settings.get_read_queue_depth());
+                        let (tx, rx) = futures_mpsc::unbounded::<ConnectionMessage>();
+
+                        // The workers must never wait to hand back a response, so only the frames which have been read are bounded.
+                        // Once the read loop stops and drops its sender, the connection loop is told that no more frames will arrive.
+                        let shutdown::TrackedConnection { close_rx, guard } = tracked_connection;
+
+                        // (6.5.3) The client has to acknowledge the settings which were sent during the handshake.
+                        let settings_acknowledge_timeout: Box<Stream<Item = ConnectionMessage, Error = ()>> = match server_instance.server_settings.get_settings_acknowledge_timeout().map(|timeout| reactor::Timeout::new(timeout, &inner_handle)) {
+                            Some(Ok(timeout)) => Box::new(timeout.into_stream().map(|_| ConnectionMessage::SettingsAcknowledgeTimeout(0)).map_err(|_| ())),
+                            Some(Err(e)) => {
+                                error!("Failed to start the settings acknowledge timeout [{}]", e);
+                                Box::new(stream::empty())
+                            },
+                            None => Box::new(stream::empty())
+                        };
+
+                        // Idle connections are pinged to check that the client is still there.
+                        let pings: Box<Stream<Item = ConnectionMessage, Error = ()>> = match server_instance.server_settings.get_ping_interval().map(|interval| reactor::Interval::new(interval, &inner_handle)) {
+                            Some(Ok(interval)) => Box::new(interval.map(|_| ConnectionMessage::Ping).map_err(|_| ())),
+                            Some(Err(e)) => {
+                                error!("Failed to start pinging the connection [{}]", e);
+                                Box::new(stream::empty())
+                            },
+                            None => Box::new(stream::empty())
+                        };
+
+                        let rx: Box<Stream<Item = ConnectionMessage, Error = ()>> = Box::new(read_rx
+                            .map(|(frame_header, payload)| ConnectionMessage::Frame(frame_header, payload))
+                            .chain(stream::once(Ok(ConnectionMessage::ReadClosed)))
+                            .select(rx)
+                            .select(close_rx.map(|_| ConnectionMessage::GracefulShutdown))
+                            .select(settings_acknowledge_timeout)
+                            .select(pings)
+                            .select(settings_update_rx.map(ConnectionMessage::UpdateSettings)));
+
+                        let mut connection = connection::Connection::new(
+                            server_instance.hpack.new_send_context(),
+                            server_instance.hpack.new_recv_context(),
+                            local_settings,
+                            temp_frame,
+                            upgrade_request,
+                            shutdown_signal::ShutdownSignaller::new(shutdown_read_tx.clone())
+                        );
+
+                        connection.set_max_request_body_size(server_instance.server_settings.get_max_request_body_size());
+
+                        // A connection which was upgraded from HTTP/1.1 already has a request to respond to on stream 1.
+                        connection.execute_upgrade();
+
+                        if let Some(error_name) = security_error {
+                            info!("Closing a connection which doesn't meet the TLS requirements for HTTP/2 {:?}", error_name);
+                            connection.reject_inadequate_security(error_name);
+                        }
+
+                        let mut loop_state = ConnectionLoopState {
+                            connection: connection,
+                            app: server_instance.app.clone(),
+                            worker_pool: worker_pool,
+                            to_conn_loop: tx.clone(),
+                            settings_update_tx: settings_update_tx,
+                            connection_info: Arc::new(connection_info),
+                            responses_in_flight: 0,
+                            read_closed: false,
+                            last_activity: Instant::now(),
+                            active_since_ping_check: false
+                        };
+                        loop_state.dispatch_requests();
+
+                        // The connection is driven by the event loop, so it only needs attention when there's a message for it.
+                        // Note that if the initial settings contain an error the connection will immediately initiate shutdown.
+                        // The loop holds a sender for the responses it hands to the workers, so the channel never hangs up.
+                        // Instead, the loop ends once every response has been received and either the read loop has stopped
+                        // or a graceful shutdown has finished.
+                        let idle_timeout = server_instance.server_settings.get_idle_timeout();
+                        let max_missed_pings = server_instance.server_settings.get_max_missed_pings();
+                        let settings_acknowledge_timeout = server_instance.server_settings.get_settings_acknowledge_timeout();
+                        let loop_handle = inner_handle.clone();
+                        let connection_loop = loop_fn((loop_state, rx, ftx), move |(mut loop_state, rx, ftx)| {
+                            let loop_handle = loop_handle.clone();
+                            let mut send_frames = Vec::new();
+                            while let Some(response_frame) = loop_state.connection.pull_frame() {
+                                send_frames.push(response_frame);
+                            }
+
+                            ftx.send_all(stream::iter_ok::<_, futures_mpsc::SendError<Vec<u8>>>(send_frames))
+                            .map_err(|e| {
+                                info!("Write error, will exit connection loop {:?}", e);
+                            })
+                            .and_then(move |(ftx, _)| {
+                                if loop_state.responses_in_flight == 0 && (loop_state.read_closed || loop_state.connection.is_shutdown_complete()) {
+                                    return future::Either::A(future::ok(future::Loop::Break(())));
                                 }
 
-                                // TODO this is quite a big commitement, the connection will not process any new frames until this is done.
-                                // Of course, frames will still be read off the network and queued for when this finishes.
-                                while connection.execute_promised(&server_instance.app) {
-                                    while let Some(response_frame) = connection.pull_frame() {
-                                        ftx = ftx.send(response_frame).wait().unwrap();
-                                    }
+                                // The connection is idle while it waits for the client with no requests being processed. Once it
+                                // has been closed there's no need to time it out again.
+                                let idle_timer = if loop_state.responses_in_flight == 0 && !loop_state.connection.is_shutdown_initiated() {
+                                    idle_timeout.and_then(|timeout| reactor::Timeout::new_at(loop_state.last_activity + timeout, &loop_handle).ok())
                                 }
-                            }
+                                else {
+                                    None
+                                };
+
+                                let next_message = match idle_timer {
+                                    Some(idle_timer) => {
+                                        Either::A(rx.into_future().select2(idle_timer).then(|result| {
+                                            match result {
+                                                Ok(Either::A(((msg, rx), _))) => Ok((msg, rx)),
+                                                Ok(Either::B((_, next_message))) => {
+                                                    // The stream future hasn't finished, so it still has the stream.
+                                                    Ok((Some(ConnectionMessage::IdleTimeout), next_message.into_inner().unwrap()))
+                                                },
+                                                Err(_) => Err(())
+                                            }
+                                        }))
+                                    },
+                                    None => Either::B(rx.into_future().map_err(|_| ()))
+                                };
+
+                                future::Either::B(next_message.map(move |(msg, rx)| {
+                                    match msg {
+                                        Some(ConnectionMessage::Frame(frame_header, payload)) => {
+                                            let is_ping = frame_header.frame_type == Some(framing::FrameType::Ping);
+                                            if !is_ping {
+                                                loop_state.last_activity = Instant::now();
+                                                loop_state.active_since_ping_check = true;
+                                            }
+                                            loop_state.connection.recv(
+                                                framing::Frame {
+                                                    header: frame_header,
+                                                    payload: payload
+                                                }
+                                            );
+                                            // The requests which are being processed see the new round trip time straight away.
+                                            if is_ping {
+                                                loop_state.connection_info.set_round_trip_time(loop_state.connection.get_round_trip_time());
+                                            }
+                                        },
+                                        Some(ConnectionMessage::Response(stream_id, push_promises, response)) => {
+                                            loop_state.last_activity = Instant::now();
+                                            loop_state.active_since_ping_check = true;
+                                            loop_state.responses_in_flight -= 1;
+                                            loop_state.connection.recv_response(stream_id, push_promises, response);
+                                        },
+                                        Some(ConnectionMessage::ReadClosed) | None => {
+                                            loop_state.read_closed = true;
+                                        },
+                                        Some(ConnectionMessage::GracefulShutdown) => {
+                                            loop_state.connection.graceful_shutdown();
+                                        },
+                                        Some(ConnectionMessage::SettingsAcknowledgeTimeout(sequence_number)) => {
+                                            loop_state.connection.settings_acknowledge_timeout(sequence_number);
+                                        },
+                                        Some(ConnectionMessage::IdleTimeout) => {
+                                            loop_state.connection.idle_timeout();
+                                        },
+                                        Some(ConnectionMessage::Ping) => {
+                                            // A connection which is receiving frames doesn't need to be checked.
+                                            if !loop_state.active_since_ping_check {
+                                                loop_state.connection.ping(max_missed_pings);
+                                            }
+                                            loop_state.active_since_ping_check = false;
+                                        },
+                                        Some(ConnectionMessage::UpdateSettings(changes)) => {
+                                            loop_state.update_settings(changes, settings_acknowledge_timeout, &loop_handle);
+                                        }
+                                    }
 
+                                    loop_state.dispatch_requests();
+
+                                    future::Loop::Continue((loop_state, rx, ftx))
+                                }))
+                            })
+                        })
+                        .then(move |_: Result<(), ()>| {
                             info!("connection loop ended, about to drop connection");
 
                             match shutdown_read_tx.clone().try_send(1) {
@@ -208,19 +626,19 @@ impl<T, R, S> Server<T, R, S>
                                 }
                             }
 
-                            // TODO (goaway) The loop has exited (again, hopefully) so check for send frames and figure out how to
-                            // keep the send loop alive long enough to make sure the goaway frame has sent.
-                            // TODO (goaway) Then make sure the send loop shuts down. When ftx is dropped the loop should end but it needs
-                            // to be checked.
-                            // Now just letting this closure exit will free this thread back into the pool to be used again.
+                            // TODO (goaway) Make sure that the goaway frame has been written before the send loop shuts down.
+                            // Dropping the connection state here drops the frame sender, which ends the send loop.
+                            Ok(())
                         });
 
+                        inner_handle.spawn(connection_loop);
+
                         let shutdown_read_future = shutdown_read_rx.into_future().map(|_| [
                             // value received on shutdown channel, ignore value.
                             ()
                         ]);
 
-                        let reader_loop = loop_fn((reader, tx, shutdown_read_future), move |(reader, to_conn_thread, shutdown_read_future)| {
+                        let reader_loop = loop_fn((reader, read_tx, shutdown_read_future), move |(reader, to_conn_loop, shutdown_read_future)| {
                             // this read exact will run on the event loop until enough bytes for an
                             // http2 header frame have been read
                             let read_frame_future = tokio_io::read_exact(reader, [0; framing::FRAME_HEADER_SIZE])
@@ -228,7 +646,7 @@ impl<T, R, S> Server<T, R, S>
                                     // Note that this can panic if the frame header is too small. But because the first read specifies
                                     // how many bytes to read before continuing to here, that should never be a problem.
                                     let frame_header = framing::decompress_frame_header(frame_header_buf.to_vec());
-                                    
+                                
                                     let mut buf = Vec::with_capacity(frame_header.length as usize);
                                     buf.resize(frame_header.length as usize, 0);
 
@@ -236,28 +654,35 @@ impl<T, R, S> Server<T, R, S>
                                 });
 
                             let loop_read_frame_future = read_frame_future
-                                .join(future::ok(to_conn_thread));
+                                .join(future::ok(to_conn_loop));
 
-                            loop_read_frame_future.select2(shutdown_read_future).then(|result| {
+                            loop_read_frame_future.select2(shutdown_read_future).then(|result| -> Box<Future<Item=future::Loop<(), _>, Error=()>> {
                                 match result {
-                                    Ok(future::Either::A(((((reader, payload_buf), frame_header), to_conn_thread), shutdown_read_future))) => {
+                                    Ok(future::Either::A(((((reader, payload_buf), frame_header), to_conn_loop), shutdown_read_future))) => {
                                         trace!("got frame [{:?}]: [{:?}]", frame_header, payload_buf);
 
-                                        to_conn_thread.send((frame_header, payload_buf)).unwrap();
-
-                                        Ok(future::Loop::Continue((reader, to_conn_thread, shutdown_read_future)))
+                                        // Waits for space in the read queue, so a connection loop which is behind stops the reading.
+                                        Box::new(to_conn_loop.send((frame_header, payload_buf)).then(move |send_result| {
+                                            match send_result {
+                                                Ok(to_conn_loop) => Ok(future::Loop::Continue((reader, to_conn_loop, shutdown_read_future))),
+                                                Err(_) => {
+                                                    debug!("The connection loop has stopped, nothing more will be read");
+                                                    Ok(future::Loop::Break(()))
+                                                }
+                                            }
+                                        }))
                                     },
                                     Ok(future::Either::B((_, _read_future))) => {
                                         debug!("The internal connection has sent the shutdown signal to the network read loop, nothing more will be read.");
-                                        Ok(future::Loop::Break(()))
+                                        Box::new(future::ok(future::Loop::Break(())))
                                     },
                                     Err(future::Either::A((e, _))) => {
                                         info!("Connection terminated by the remote [{}]", e);
-                                        Ok(future::Loop::Break(()))
+                                        Box::new(future::ok(future::Loop::Break(())))
                                     },
                                     Err(future::Either::B((e, _))) => {
                                         error!("Network read loop lost connection with the internal connection, will stop reading from the network [{:?}]", e);
-                                        Ok(future::Loop::Break(()))
+                                        Box::new(future::ok(future::Loop::Break(())))
                                     }
                                 }
                             })
@@ -267,8 +692,9 @@ impl<T, R, S> Server<T, R, S>
 
                         // From the documentation, when all sender handles have been dropped the stream is considered completed and 'none' is
                         // returned. That is what is needed to end the 'fold'.
-                        // Therefore, no shutdown mechanism is required for this. As soon as the connection thread exits this future will complete
-                        // and be removed from the event loop.
+                        // Therefore, no shutdown mechanism is required for this. As soon as the connection loop exits this future will complete
+                        // and be removed from the event loop. The frames which were queued, such as a GOAWAY, are written first. Then the
+                        // connection is no longer open as far as a server shutdown is concerned.
                         let send_loop = frx.fold(writer, |writer, msg| {
                             trace!("will push to network [{:?}]", msg);
                             tokio_io::write_all(writer, msg)
@@ -282,19 +708,25 @@ impl<T, R, S> Server<T, R, S>
                                     ()
                                 })
                         })
-                        .map(|_| ()); // Drop the writer, can't move a future which returns a value onto the event loop.
+                        .then(move |_| {
+                            // Drop the writer, can't move a future which returns a value onto the event loop.
+                            drop(guard);
+                            Ok(())
+                        });
 
                         inner_handle.spawn(send_loop);
                     },
                     Err(e) => {
                         match e {
-                            h2handshake::HandshakeError::DidNotUpgrade(_connection, received_bytes) => {
-                                if received_bytes.len() > 80 {
-                                    info!("Rejected connection because of failed handshake [{:?}]", &received_bytes[0..80]);
-                                }
-                                else {
-                                    info!("Rejected connection because of failed handshake [{:?}]", received_bytes);
-                                }
+                            h2handshake::HandshakeError::DidNotUpgrade(connection, received_bytes) => {
+                                // The client didn't ask for HTTP/2, so serve it HTTP/1.1 on the same connection. Anything read
+                                // while trying to find the preface is the start of the first request.
+                                debug!("Connection did not upgrade to HTTP/2, falling back to HTTP/1.1");
+
+                                let scheme = if connection.is_secure() { "https" } else { "http" };
+
+                                let connection_info = connection.connection_info();
+                                http1_connection::serve(connection, received_bytes, scheme, connection_info, server_instance.app.clone(), &worker_pool, &server_instance.server_settings, http1_preface_deadline.as_ref(), tracked_connection, &inner_handle);
                             }
                         }
                     }
@@ -302,86 +734,223 @@ impl<T, R, S> Server<T, R, S>
             });
 
             handle.spawn(handshake_future);
-            
+        
             Ok(())
-        });
-
-        // move the incoming connection stream onto the event loop
-        event_loop.run(server).unwrap();
-
-        server_instance
+        }))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Server;
+    use super::{Server, ConnectionLoopState, ConnectionMessage};
+
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
 
+    use futures::{future, Future, Stream};
+    use futures::sync::mpsc as futures_mpsc;
+    use futures::sync::oneshot;
+    use futures_cpupool::CpuPool;
+
+    use http2::core::connection;
+    use http2::frame as framing;
     use http2::header;
-    use http2::stream as streaming;
-    use shared::{server_trait, server_settings};
+    use http2::hpack;
+    use http2::net::shutdown_signal::ShutdownSignaller;
+    use http2::settings;
+    use shared::{self, server_trait, server_settings};
     use shared::connection_handle::ConnectionHandle;
+    use shared::connection_info::ConnectionInfo;
+    use shared::transport::memory::MemoryTransport;
 
-    struct MyServer;
+    // A GET which ends the stream, for / when `index` is false and for /index.html when it's true.
+    fn get_request(stream_id: u8, index: bool) -> framing::Frame {
+        let frame = vec![0, 0, 3, 0x1, 0x5, 0, 0, 0, stream_id, 0x82, 0x86, if index { 0x85 } else { 0x84 }];
+
+        framing::Frame {
+            header: framing::decompress_frame_header(frame.clone()),
+            payload: frame[framing::FRAME_HEADER_SIZE..].to_vec()
+        }
+    }
+
+    fn new_loop_state<T>(app: T, local_settings: settings::Settings) -> (ConnectionLoopState<T>, futures_mpsc::UnboundedReceiver<ConnectionMessage>) {
+        let hpack = hpack::HPack::new();
+        let (shutdown_read_tx, _) = futures_mpsc::channel(1);
+        let (to_conn_loop, conn_loop_rx) = futures_mpsc::unbounded();
+        let (settings_update_tx, _) = futures_mpsc::unbounded();
+
+        let mut loop_state = ConnectionLoopState {
+            connection: connection::Connection::new(
+                hpack.new_send_context(),
+                hpack.new_recv_context(),
+                local_settings,
+                framing::settings::SettingsFrame::new_noop(),
+                None,
+                ShutdownSignaller::new(shutdown_read_tx)
+            ),
+            app: Arc::new(app),
+            // A single worker, so the streams can only be processed together if waiting doesn't hold the worker.
+            worker_pool: CpuPool::new(1),
+            to_conn_loop: to_conn_loop,
+            settings_update_tx: settings_update_tx,
+            connection_info: Arc::new(ConnectionInfo::new()),
+            responses_in_flight: 0,
+            read_closed: false,
+            last_activity: Instant::now(),
+            active_since_ping_check: false
+        };
+
+        // The client acknowledges the local settings, so that they apply.
+        let settings_acknowledge = vec![0, 0, 0, 0x4, 0x1, 0, 0, 0, 0];
+        loop_state.connection.recv(framing::Frame {
+            header: framing::decompress_frame_header(settings_acknowledge),
+            payload: Vec::new()
+        });
 
-    #[derive(Debug)]
-    struct HttpRequest {
-        pub headers: header::Headers,
-        pub body: Option<String>
+        (loop_state, conn_loop_rx)
     }
 
-    #[derive(Debug)]
-    struct HttpResponse {
-        pub headers: header::Headers,
-        pub body: Option<Vec<u8>>
+    // The stream identifier and whether the application produced a response, for the next response message.
+    fn next_response(conn_loop_rx: &mut futures_mpsc::UnboundedReceiver<ConnectionMessage>) -> (u32, bool) {
+        match conn_loop_rx.by_ref().wait().next() {
+            Some(Ok(ConnectionMessage::Response(stream_id, _, response))) => (stream_id, response.is_ok()),
+            _ => panic!("expected a response")
+        }
+    }
+
+    // Responds to /index.html once it's told to, and to anything else straight away.
+    struct SlowIndexServer {
+        release_rx: Mutex<Option<oneshot::Receiver<()>>>
     }
 
-    impl From<streaming::StreamRequest> for HttpRequest {
-        fn from(stream_request: streaming::StreamRequest) -> HttpRequest {
-            let body = if stream_request.payload.is_some() {
-                Some(
-                    String::from_utf8(stream_request.payload.unwrap()).unwrap()
-                )
+    impl server_trait::AsyncOsmiumServer for SlowIndexServer {
+        type Request = shared::Request;
+        type Response = shared::Response;
+
+        fn process(&self, request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> server_trait::ResponseFuture<Self::Response> {
+            if request.get_uri() == "/index.html" {
+                let release_rx = self.release_rx.lock().unwrap().take().unwrap();
+                Box::new(release_rx.map_err(|_| ()).map(|_| shared::Response::new(200)))
             }
             else {
-                None
-            };
-
-            HttpRequest {
-                headers: stream_request.headers,
-                body: body
+                Box::new(future::ok(shared::Response::new(200)))
             }
         }
     }
 
-    impl From<HttpResponse> for streaming::StreamResponse {
-        fn from(http_response: HttpResponse) -> streaming::StreamResponse {
-            streaming::StreamResponse {
-                informational_headers: Vec::new(),
-                headers: http_response.headers,
-                payload: http_response.body,
-                trailer_headers: None
-            }
+    struct PanickingServer;
+
+    impl server_trait::OsmiumServer for PanickingServer {
+        type Request = shared::Request;
+        type Response = shared::Response;
+
+        fn process(&self, _request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
+            panic!("the application failed");
+        }
+    }
+
+    #[test]
+    pub fn slow_stream_does_not_hold_up_other_streams() {
+        let (release_tx, release_rx) = oneshot::channel();
+        let (mut loop_state, mut conn_loop_rx) = new_loop_state(SlowIndexServer {
+            release_rx: Mutex::new(Some(release_rx))
+        }, settings::Settings::spec_default());
+
+        loop_state.connection.recv(get_request(1, true));
+        loop_state.connection.recv(get_request(3, false));
+        loop_state.dispatch_requests();
+        assert_eq!(2, loop_state.responses_in_flight);
+
+        // The second stream is responded to while the first is still waiting.
+        assert_eq!((3, true), next_response(&mut conn_loop_rx));
+
+        release_tx.send(()).unwrap();
+        assert_eq!((1, true), next_response(&mut conn_loop_rx));
+    }
+
+    #[test]
+    pub fn application_panic_fails_the_response() {
+        let (mut loop_state, mut conn_loop_rx) = new_loop_state(PanickingServer, settings::Settings::spec_default());
+
+        loop_state.connection.recv(get_request(1, false));
+        loop_state.dispatch_requests();
+
+        // The connection loop still hears back, so it isn't left waiting for the response forever.
+        assert_eq!((1, false), next_response(&mut conn_loop_rx));
+
+        // The worker survives to process the next stream.
+        loop_state.connection.recv(get_request(3, false));
+        loop_state.dispatch_requests();
+        assert_eq!((3, false), next_response(&mut conn_loop_rx));
+    }
+
+    #[test]
+    pub fn application_panic_frees_the_stream() {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.max_concurrent_streams = Some(1);
+        let (mut loop_state, mut conn_loop_rx) = new_loop_state(PanickingServer, local_settings);
+
+        loop_state.connection.recv(get_request(1, false));
+        loop_state.dispatch_requests();
+
+        match conn_loop_rx.by_ref().wait().next() {
+            Some(Ok(ConnectionMessage::Response(stream_id, push_promises, response))) => {
+                loop_state.connection.recv_response(stream_id, push_promises, response);
+            },
+            _ => panic!("expected a response")
+        }
+
+        // Stream 1 was reset, so there's room for stream 3 and it isn't refused.
+        loop_state.connection.recv(get_request(3, false));
+        while let Some(frame) = loop_state.connection.pull_frame() {
+            assert_ne!(3, framing::decompress_frame_header(frame).stream_id);
         }
+        assert_eq!(3, loop_state.connection.pull_request().unwrap().stream_id);
     }
 
+    struct MyServer;
+
     impl server_trait::OsmiumServer for MyServer {
-        type Request = HttpRequest;
-        type Response = HttpResponse;
+        type Request = shared::Request;
+        type Response = shared::Response;
 
-        fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> Self::Response {
+        fn process(&self, request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
             println!("Got request {:?}", request);
 
-            let mut headers = header::Headers::new();
-            headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(200));
-            headers.push(header::HeaderName::ContentLength, header::HeaderValue::Num(111));
-            headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
+            let mut response = shared::Response::new(200);
+            response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(111));
+            response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/html")));
+            response.set_body(String::from("<!DOCTYPE html><html><head><title>test</title></head><body><h1>Osmium served me like a beast</h1></body></html>").into_bytes());
 
-            HttpResponse {
-                headers: headers,
-                body: Some(String::from("<!DOCTYPE html><html><head><title>test</title></head><body><h1>Osmium served me like a beast</h1></body></html>").into_bytes())
+            response
+        }
+    }
+
+    #[test]
+    pub fn serve_transport_responds_to_request() {
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        // An empty SETTINGS frame, then the request.
+        input.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        input.extend_from_slice(&[0, 0, 3, 0x1, 0x5, 0, 0, 0, 1, 0x82, 0x86, 0x84]);
+
+        let (transport, output) = MemoryTransport::new(input);
+
+        Server::new(MyServer {}, server_settings::ServerSettings::default()).unwrap().serve_transport(Box::new(transport)).unwrap();
+
+        // Find the frames the server sent on the request's stream.
+        let output = output.borrow();
+        let mut frame_types = Vec::new();
+        let mut position = 0;
+        while position + framing::FRAME_HEADER_SIZE <= output.len() {
+            let frame_header = framing::decompress_frame_header(output[position..position + framing::FRAME_HEADER_SIZE].to_vec());
+            if frame_header.stream_id == 1 {
+                frame_types.push(frame_header.frame_type);
             }
+            position += framing::FRAME_HEADER_SIZE + frame_header.length as usize;
         }
+
+        assert_eq!(Some(&Some(framing::FrameType::Headers)), frame_types.first());
+        assert!(frame_types.contains(&Some(framing::FrameType::Data)));
     }
 
     // MANUAL TESTING #[test]
@@ -390,6 +959,6 @@ mod tests {
         let mut settings = server_settings::ServerSettings::default();
         settings.set_security(server_settings::SecuritySettings::default());
 
-        Server::new(MyServer {}, settings).unwrap().start_server();
+        Server::new(MyServer {}, settings).unwrap().start_server().unwrap();
     }
 }
@@ -0,0 +1,215 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::sync::{Arc, Mutex};
+
+// tokio
+use futures::sync::mpsc as futures_mpsc;
+
+// osmium
+use http2::frame as framing;
+use http2::settings;
+
+pub use shared::settings_update_error::SettingsUpdateError;
+
+/// Tells an open connection about changes to its local settings.
+pub type SettingsUpdateSender = futures_mpsc::UnboundedSender<Vec<settings::SettingsParameter>>;
+
+/// Check that changes to the local settings have values which are allowed.
+pub fn validate(changes: &[settings::SettingsParameter]) -> Result<(), SettingsUpdateError> {
+    settings::Settings::spec_default().apply_changes(changes)
+        .map(|_| ())
+        .map_err(SettingsUpdateError::InvalidSettings)
+}
+
+/// The local settings for a new connection and how it's told about later changes to them.
+pub struct SettingsSubscription {
+    /// The settings which the connection starts with.
+    pub settings: settings::Settings,
+    /// Sends the settings to the client during the handshake.
+    pub settings_frame: framing::settings::SettingsFrameCompressModel,
+    /// Receives the changes which are made to the server's settings while the connection is open.
+    pub update_rx: futures_mpsc::UnboundedReceiver<Vec<settings::SettingsParameter>>,
+    /// Changes the settings of just this connection.
+    pub update_tx: SettingsUpdateSender
+}
+
+struct LocalSettings {
+    settings: settings::Settings,
+    connections: Vec<SettingsUpdateSender>
+}
+
+// The client starts from the values in the specification, so the handshake only needs to send the settings which
+// differ from those, once each.
+fn handshake_settings_frame(local_settings: &settings::Settings) -> framing::settings::SettingsFrameCompressModel {
+    let spec_default = settings::Settings::spec_default();
+    let mut settings_frame = framing::settings::SettingsFrameCompressModel::new();
+
+    if local_settings.header_table_size != spec_default.header_table_size {
+        settings_frame.add_parameter(settings::SettingName::SettingsHeaderTableSize, local_settings.header_table_size);
+    }
+    if local_settings.enable_push != spec_default.enable_push {
+        settings_frame.add_parameter(settings::SettingName::SettingsEnablePush, local_settings.enable_push as u32);
+    }
+    if let Some(max_concurrent_streams) = local_settings.max_concurrent_streams {
+        settings_frame.add_parameter(settings::SettingName::SettingsMaxConcurrentStreams, max_concurrent_streams);
+    }
+    if local_settings.initial_window_size != spec_default.initial_window_size {
+        settings_frame.add_parameter(settings::SettingName::SettingsInitialWindowSize, local_settings.initial_window_size);
+    }
+    if local_settings.max_frame_size != spec_default.max_frame_size {
+        settings_frame.add_parameter(settings::SettingName::SettingsMaxFrameSize, local_settings.max_frame_size);
+    }
+    if let Some(max_header_list_size) = local_settings.max_header_list_size {
+        settings_frame.add_parameter(settings::SettingName::SettingsMaxHeaderListSize, max_header_list_size);
+    }
+
+    settings_frame
+}
+
+/// Changes the local HTTP/2 settings of a running server, for example to lower `max_concurrent_streams` under load.
+/// The handle can be cloned and sent to other threads.
+///
+/// New connections start with the changed settings. Open connections send the changes to their client, and each
+/// change only takes effect on a connection once the client has acknowledged it (6.5.3).
+#[derive(Clone)]
+pub struct SettingsUpdateHandle {
+    local_settings: Arc<Mutex<LocalSettings>>
+}
+
+impl SettingsUpdateHandle {
+    /// Start from the settings which the server was configured with.
+    pub fn new(changes: &[settings::SettingsParameter]) -> Result<Self, SettingsUpdateError> {
+        let mut local_settings = settings::Settings::spec_default();
+        local_settings.apply_changes(changes).map_err(SettingsUpdateError::InvalidSettings)?;
+
+        Ok(SettingsUpdateHandle {
+            local_settings: Arc::new(Mutex::new(LocalSettings {
+                settings: local_settings,
+                connections: Vec::new()
+            }))
+        })
+    }
+
+    /// Change the settings of every connection. Nothing is changed if any of the changes are invalid.
+    pub fn update_settings(&self, changes: Vec<settings::SettingsParameter>) -> Result<(), SettingsUpdateError> {
+        let mut local_settings = self.local_settings.lock().unwrap();
+
+        let mut new_settings = local_settings.settings.clone();
+        new_settings.apply_changes(&changes).map_err(SettingsUpdateError::InvalidSettings)?;
+        local_settings.settings = new_settings;
+
+        // Forget the connections which have closed.
+        local_settings.connections.retain(|update_tx| update_tx.unbounded_send(changes.clone()).is_ok());
+
+        Ok(())
+    }
+
+    /// Get the settings for a new connection. It's subscribed to the changes at the same time, so that it can't
+    /// miss one which is made while it's starting.
+    pub fn subscribe(&self) -> SettingsSubscription {
+        let mut local_settings = self.local_settings.lock().unwrap();
+
+        let settings_frame = handshake_settings_frame(&local_settings.settings);
+
+        let (update_tx, update_rx) = futures_mpsc::unbounded();
+        // Forget the connections which have closed, in case the settings are never updated.
+        local_settings.connections.retain(|update_tx| !update_tx.is_closed());
+        local_settings.connections.push(update_tx.clone());
+
+        SettingsSubscription {
+            settings: local_settings.settings.clone(),
+            settings_frame: settings_frame,
+            update_rx: update_rx,
+            update_tx: update_tx
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SettingsUpdateHandle, SettingsUpdateError};
+
+    use futures::Stream;
+    use http2::frame::CompressibleHttpFrame;
+    use http2::settings::{SettingName, SettingsParameter};
+
+    #[test]
+    pub fn new_connections_start_with_updated_settings() {
+        let handle = SettingsUpdateHandle::new(&[SettingsParameter::new(SettingName::SettingsMaxConcurrentStreams, 100)]).unwrap();
+
+        handle.update_settings(vec![SettingsParameter::new(SettingName::SettingsMaxConcurrentStreams, 10)]).unwrap();
+
+        assert_eq!(Some(10), handle.subscribe().settings.max_concurrent_streams);
+    }
+
+    #[test]
+    pub fn handshake_sends_each_setting_once() {
+        let handle = SettingsUpdateHandle::new(&[
+            SettingsParameter::new(SettingName::SettingsMaxConcurrentStreams, 100),
+            SettingsParameter::new(SettingName::SettingsMaxFrameSize, 0x4000)
+        ]).unwrap();
+
+        for max_concurrent_streams in 0..10 {
+            handle.update_settings(vec![SettingsParameter::new(SettingName::SettingsMaxConcurrentStreams, max_concurrent_streams)]).unwrap();
+        }
+
+        // The max frame size is the default, so it isn't sent.
+        let settings_frame = Box::new(handle.subscribe().settings_frame);
+        assert_eq!(6, settings_frame.get_length());
+        assert_eq!(vec![0x0, 0x3, 0x0, 0x0, 0x0, 0x9], settings_frame.get_payload());
+    }
+
+    #[test]
+    pub fn open_connections_are_sent_updates() {
+        let handle = SettingsUpdateHandle::new(&[]).unwrap();
+        let subscription = handle.subscribe();
+
+        handle.update_settings(vec![SettingsParameter::new(SettingName::SettingsInitialWindowSize, 0x20000)]).unwrap();
+        drop(handle);
+        drop(subscription.update_tx);
+
+        let updates: Vec<_> = subscription.update_rx.wait().map(|changes| changes.unwrap()).collect();
+        assert_eq!(1, updates.len());
+        assert_eq!(0x20000, updates[0][0].get_value());
+    }
+
+    #[test]
+    pub fn forget_closed_connections() {
+        let handle = SettingsUpdateHandle::new(&[]).unwrap();
+
+        for _ in 0..10 {
+            handle.subscribe();
+        }
+        let _subscription = handle.subscribe();
+
+        assert_eq!(1, handle.local_settings.lock().unwrap().connections.len());
+    }
+
+    #[test]
+    pub fn reject_invalid_update() {
+        let handle = SettingsUpdateHandle::new(&[]).unwrap();
+
+        match handle.update_settings(vec![SettingsParameter::new(SettingName::SettingsMaxFrameSize, 10)]) {
+            Err(SettingsUpdateError::InvalidSettings(_)) => {},
+            _ => panic!("expected the update to be rejected")
+        }
+
+        assert_eq!(0x4000, handle.subscribe().settings.max_frame_size);
+    }
+}
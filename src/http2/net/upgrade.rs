@@ -0,0 +1,381 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// httparse
+use httparse;
+
+// osmium
+use http2::frame as framing;
+use http2::header;
+use http2::stream as streaming;
+use http::request as http_request;
+use super::h2handshake;
+
+/// The response which tells the client that the server has accepted the upgrade to h2c (3.2).
+pub const SWITCHING_PROTOCOLS_RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+const MAX_HEADERS: usize = 64;
+
+/// The largest request body which an upgrade request can have. The body has to be held in memory until the protocol
+/// has been switched, so larger requests are left to be served as HTTP/1.1 instead.
+pub const MAX_UPGRADE_BODY_SIZE: usize = 64 * 1024;
+
+pub enum UpgradeParseResult {
+    /// The request head has not been fully received yet.
+    Incomplete,
+    /// The request is not a valid h2c upgrade request. It may still be a valid HTTP/1.1 request.
+    NotUpgrade,
+    Upgrade(UpgradeRequestHead)
+}
+
+/// The head of an HTTP/1.1 request which has asked to upgrade to h2c.
+pub struct UpgradeRequestHead {
+    headers: header::Headers,
+    settings_frame: framing::settings::SettingsFrame,
+    head_length: usize,
+    content_length: usize
+}
+
+impl UpgradeRequestHead {
+    /// The number of bytes in the request line and headers, including the empty line which ends the headers.
+    pub fn get_head_length(&self) -> usize {
+        self.head_length
+    }
+
+    /// The length of the request body which has to be read before the protocol can be switched.
+    pub fn get_content_length(&self) -> usize {
+        self.content_length
+    }
+
+    pub fn into_upgrade_request(self, body: Vec<u8>) -> h2handshake::UpgradeRequest {
+        let payload = if body.is_empty() {
+            None
+        }
+        else {
+            Some(body)
+        };
+
+        h2handshake::UpgradeRequest {
+            settings_frame: self.settings_frame,
+            request: streaming::StreamRequest {
+                headers: self.headers,
+                payload: payload,
+                trailer_headers: None
+            }
+        }
+    }
+}
+
+/// Attempt to read an h2c upgrade request (3.2) from the bytes received so far.
+pub fn parse_upgrade_request(buf: &[u8]) -> UpgradeParseResult {
+    let mut raw_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut raw_headers);
+
+    let head_length = match request.parse(buf) {
+        Ok(httparse::Status::Complete(head_length)) => head_length,
+        Ok(httparse::Status::Partial) => {
+            return UpgradeParseResult::Incomplete;
+        },
+        Err(e) => {
+            debug!("Failed to parse HTTP/1.1 request while looking for upgrade [{:?}]", e);
+            return UpgradeParseResult::NotUpgrade;
+        }
+    };
+
+    let mut is_h2c_upgrade = false;
+    let mut connection_options = Vec::new();
+    let mut settings_values = Vec::new();
+    let mut content_length = None;
+    let mut fields = Vec::new();
+
+    for raw_header in request.headers.iter() {
+        let name = raw_header.name.to_lowercase();
+        let value = match String::from_utf8(raw_header.value.to_vec()) {
+            Ok(value) => value,
+            Err(_) => {
+                return UpgradeParseResult::NotUpgrade;
+            }
+        };
+
+        match name.as_str() {
+            "upgrade" => {
+                is_h2c_upgrade = value.split(',').any(|token| token.trim().eq_ignore_ascii_case("h2c"));
+            },
+            "connection" => {
+                connection_options.extend(value.split(',').map(|token| token.trim().to_lowercase()));
+            },
+            "http2-settings" => {
+                settings_values.push(value.trim().to_owned());
+            },
+            "content-length" => {
+                let length = match http_request::parse_content_length(&value) {
+                    Some(length) => length,
+                    None => {
+                        return UpgradeParseResult::NotUpgrade;
+                    }
+                };
+
+                if length > MAX_UPGRADE_BODY_SIZE {
+                    debug!("Not upgrading a request with a body of [{}] bytes, which is larger than the maximum", length);
+                    return UpgradeParseResult::NotUpgrade;
+                }
+
+                // The HTTP/1.1 connection rejects a request with differing content lengths.
+                if content_length.is_some() && content_length != Some(length) {
+                    return UpgradeParseResult::NotUpgrade;
+                }
+
+                content_length = Some(length);
+            },
+            "transfer-encoding" => {
+                // The body has to be read before switching protocols, only bodies with a known length are supported.
+                // That includes a request which also has a Content-Length, which the HTTP/1.1 connection rejects.
+                return UpgradeParseResult::NotUpgrade;
+            },
+            _ => {}
+        }
+
+        fields.push((name, value));
+    }
+
+    // (3.2.1) A server MUST NOT upgrade the connection to HTTP/2 if this header field is not present
+    // or if more than one is present.
+    if !is_h2c_upgrade || settings_values.len() != 1 {
+        return UpgradeParseResult::NotUpgrade;
+    }
+
+    // (3.2.1) The HTTP2-Settings header is connection specific, so the Connection header has to name it as well
+    // as the upgrade.
+    if !connection_options.iter().any(|option| option == "upgrade") || !connection_options.iter().any(|option| option == "http2-settings") {
+        debug!("Not upgrading a request whose Connection header doesn't list both Upgrade and HTTP2-Settings");
+        return UpgradeParseResult::NotUpgrade;
+    }
+
+    let settings_payload = match decode_base64url(settings_values[0].as_str()) {
+        Some(settings_payload) => settings_payload,
+        None => {
+            return UpgradeParseResult::NotUpgrade;
+        }
+    };
+
+    // The header contains the payload of a SETTINGS frame, so build the header that frame would have had.
+    let settings_frame_header = framing::FrameHeader {
+        length: settings_payload.len() as u32,
+        frame_type: Some(framing::FrameType::Settings),
+        flags: 0,
+        stream_id: 0
+    };
+
+    let settings_frame = match framing::settings::SettingsFrame::new(&settings_frame_header, &mut settings_payload.into_iter()) {
+        Ok(settings_frame) => settings_frame,
+        Err(e) => {
+            debug!("Invalid HTTP2-Settings on upgrade request [{:?}]", e);
+            return UpgradeParseResult::NotUpgrade;
+        }
+    };
+
+    UpgradeParseResult::Upgrade(UpgradeRequestHead {
+        headers: http_request::to_http2_headers(request.method.unwrap(), request.path.unwrap(), "http", &fields),
+        settings_frame: settings_frame,
+        head_length: head_length,
+        content_length: content_length.unwrap_or(0)
+    })
+}
+
+// The HTTP2-Settings header is base64url encoded with any trailing '=' characters omitted (3.2.1).
+fn decode_base64url(input: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(input.len() * 3 / 4);
+
+    let mut accumulator: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c {
+            b'A' ..= b'Z' => c - b'A',
+            b'a' ..= b'z' => c - b'a' + 26,
+            b'0' ..= b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            b'=' => {
+                // Be lenient about padding, even though it should have been omitted.
+                break;
+            },
+            _ => {
+                return None;
+            }
+        };
+
+        accumulator = (accumulator << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            result.push((accumulator >> bits) as u8);
+            accumulator &= (1 << bits) - 1;
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base64url, parse_upgrade_request, UpgradeParseResult, MAX_UPGRADE_BODY_SIZE};
+
+    #[test]
+    pub fn decode_curl_settings() {
+        // This is the HTTP2-Settings header curl sends on an upgrade request.
+        let decoded = decode_base64url("AAMAAABkAARAAAAAAAIAAAAA").unwrap();
+
+        assert_eq!(vec![0, 3, 0, 0, 0, 100, 0, 4, 64, 0, 0, 0, 0, 2, 0, 0, 0, 0], decoded);
+    }
+
+    #[test]
+    pub fn decode_rejects_invalid_characters() {
+        assert!(decode_base64url("AAMA+AB/").is_none());
+    }
+
+    #[test]
+    pub fn parse_upgrade() {
+        let request = b"GET /index.html HTTP/1.1\r\nHost: localhost:8080\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+
+        match parse_upgrade_request(request) {
+            UpgradeParseResult::Upgrade(head) => {
+                assert_eq!(request.len(), head.get_head_length());
+                assert_eq!(0, head.get_content_length());
+
+                let upgrade_request = head.into_upgrade_request(Vec::new());
+                assert_eq!(3, upgrade_request.settings_frame.get_parameters().len());
+                // :method, :scheme, :path and :authority. The connection specific headers are dropped.
+                assert_eq!(4, upgrade_request.request.headers.len());
+                assert!(upgrade_request.request.payload.is_none());
+            },
+            _ => {
+                panic!("expected upgrade");
+            }
+        }
+    }
+
+    fn upgrade_request_with_content_length(content_length: &str) -> Vec<u8> {
+        format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\nContent-Length: {}\r\n\r\n",
+            content_length
+        ).into_bytes()
+    }
+
+    #[test]
+    pub fn parse_upgrade_without_connection_options() {
+        let requests: Vec<&[u8]> = vec![
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n"
+        ];
+
+        for request in requests {
+            match parse_upgrade_request(request) {
+                UpgradeParseResult::NotUpgrade => {},
+                _ => {
+                    panic!("expected not upgrade for [{}]", String::from_utf8_lossy(request));
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_upgrade_with_connection_options_across_headers() {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, upgrade\r\nConnection: http2-settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+
+        match parse_upgrade_request(request) {
+            UpgradeParseResult::Upgrade(_) => {},
+            _ => {
+                panic!("expected upgrade");
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_upgrade_with_largest_body() {
+        match parse_upgrade_request(&upgrade_request_with_content_length(&MAX_UPGRADE_BODY_SIZE.to_string())) {
+            UpgradeParseResult::Upgrade(head) => {
+                assert_eq!(MAX_UPGRADE_BODY_SIZE, head.get_content_length());
+            },
+            _ => {
+                panic!("expected upgrade");
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_upgrade_with_oversized_body() {
+        let oversized_content_lengths = vec![(MAX_UPGRADE_BODY_SIZE + 1).to_string(), "18446744073709551615".to_owned()];
+
+        for content_length in oversized_content_lengths {
+            match parse_upgrade_request(&upgrade_request_with_content_length(&content_length)) {
+                UpgradeParseResult::NotUpgrade => {},
+                _ => {
+                    panic!("expected not upgrade");
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_upgrade_with_content_length_which_is_not_digits() {
+        for content_length in &["+5", "-5", "0x5", ""] {
+            match parse_upgrade_request(&upgrade_request_with_content_length(content_length)) {
+                UpgradeParseResult::NotUpgrade => {},
+                _ => {
+                    panic!("expected not upgrade for [{}]", content_length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_upgrade_with_content_length_and_transfer_encoding() {
+        let mut request = upgrade_request_with_content_length("5");
+        let head_end = request.len() - 2;
+        request.splice(head_end..head_end, b"Transfer-Encoding: chunked\r\n".iter().cloned());
+
+        match parse_upgrade_request(&request) {
+            UpgradeParseResult::NotUpgrade => {},
+            _ => {
+                panic!("expected not upgrade");
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_incomplete() {
+        match parse_upgrade_request(b"GET /index.html HTTP/1.1\r\nHost: local") {
+            UpgradeParseResult::Incomplete => {},
+            _ => {
+                panic!("expected incomplete");
+            }
+        }
+    }
+
+    #[test]
+    pub fn parse_without_upgrade() {
+        match parse_upgrade_request(b"GET /index.html HTTP/1.1\r\nHost: localhost:8080\r\n\r\n") {
+            UpgradeParseResult::NotUpgrade => {},
+            _ => {
+                panic!("expected not upgrade");
+            }
+        }
+    }
+}
@@ -34,7 +34,7 @@ pub enum SettingName {
     SettingsMaxHeaderListSize
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SettingsParameter {
     name: SettingName,
     value: u32
@@ -18,12 +18,13 @@
 pub mod state;
 pub mod stream_request;
 pub mod stream_response;
+pub mod stream_handle;
 
 pub use self::stream_request::StreamRequest;
 pub use self::stream_response::StreamResponse;
+pub use self::stream_handle::StreamHandle;
 
 // std
-use std::convert;
 use std::mem;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -32,12 +33,10 @@ use std::collections::VecDeque;
 // osmium
 use http2::frame as framing;
 use http2::error;
+use http2::settings;
 use http2::header;
 use http2::hpack::{context as hpack_context, pack as hpack_pack};
-use shared::server_trait;
-use shared::connection_handle::ConnectionHandle;
 use http2::core::connection_shared_state::ConnectionSharedState;
-use shared::push_error;
 use http2::frame::check as frame_checking;
 
 /// Convenience typedef for stream identifiers.
@@ -81,18 +80,25 @@ pub struct Stream {
 
     connection_shared_state: Rc<RefCell<ConnectionSharedState>>,
 
-    push_promise_queue: VecDeque<StreamRequest>,
-
     // Because these requests are being generated locally, the remote encoder will never encode them.
     // Therefore, it is necessary to keep them for use later without decoding.
     push_promise_publish_queue: VecDeque<(u32, StreamRequest)>,
 
-    send_window: u32
-    // TODO receive
+    // A request which has been fully received and is waiting to be passed to the application.
+    ready_request: Option<StreamRequest>,
+
+    // Starts from the client's initial window size, and can be negative if the client reduces that (6.9.2).
+    send_window: i32,
+
+    // Starts from the initial window size in the local settings which the client has acknowledged.
+    receive_window: i32
 }
 
 impl Stream {
     pub fn new(id: StreamId, connection_shared_state: Rc<RefCell<ConnectionSharedState>>) -> Self {
+        let initial_window_size = connection_shared_state.borrow().remote_settings.initial_window_size as i32;
+        let initial_receive_window_size = connection_shared_state.borrow().local_settings.initial_window_size as i32;
+
         Stream {
             id: id,
 
@@ -111,10 +117,12 @@ impl Stream {
 
             connection_shared_state: connection_shared_state,
 
-            push_promise_queue: VecDeque::new(),
             push_promise_publish_queue: VecDeque::new(),
 
-            send_window: 0
+            ready_request: None,
+
+            send_window: initial_window_size,
+            receive_window: initial_receive_window_size
         }
     }
 
@@ -131,17 +139,30 @@ impl Stream {
         promised_stream
     }
 
+    /// Create the stream for a request which was received as HTTP/1.1 before the connection was upgraded.
+    /// (3.2) The stream is implicitly "half-closed" from the client toward the server.
+    pub fn new_upgrade(id: StreamId, connection_shared_state: Rc<RefCell<ConnectionSharedState>>, request: StreamRequest) -> Self {
+        let mut upgrade_stream = Stream::new(id, connection_shared_state);
+
+        upgrade_stream.state_name = if let state::StreamStateName::Idle(ref state) = upgrade_stream.state_name {
+            let open_state: state::StreamState<state::StateOpen> = state.into();
+            state::StreamStateName::HalfClosedRemote((&open_state).into())
+        }
+        else {
+            unreachable!("new streams are always idle");
+        };
+
+        upgrade_stream.request = request;
+
+        upgrade_stream
+    }
+
     // Note that unpacking headers is stateful, and we can only borrow the connection's context mutably once.
-    pub fn recv<T, R, S>(
+    pub fn recv(
         &mut self, 
         frame: framing::StreamFrame,
-        hpack_send_context: &mut hpack_context::SendContext,
-        hpack_recv_context: &mut hpack_context::RecvContext,
-        app: &T
+        hpack_recv_context: &mut hpack_context::RecvContext
     ) -> Option<error::HttpError>
-        where T: server_trait::OsmiumServer<Request=R, Response=S>, 
-              R: convert::From<StreamRequest>,
-              S: convert::Into<StreamResponse>
     {
         log_stream_recv!("Receive frame", self.id, self.state_name, frame);
 
@@ -237,23 +258,84 @@ impl Stream {
             state::StreamStateName::Open(ref state) => {
                 match frame.header.frame_type {
                     framing::FrameType::Data => {
-                        let data_frame = framing::data::DataFrame::new(&frame.header, &mut frame.payload.into_iter());
-
-                        // If the client ended the stream then it becomes half closed remote.
-                        let new_state = if data_frame.is_end_stream() {
-                            Some(
-                                state::StreamStateName::HalfClosedRemote(state.into())
+                        // (6.9.1) A receiver MAY respond with a stream error (Section 5.4.2) or connection error 
+                        // (Section 5.4.1) of type FLOW_CONTROL_ERROR unless it receives a WINDOW_UPDATE frame that causes
+                        // the flow-control window to become positive.
+                        // The padding counts towards the window, so the frame length is used rather than the payload's.
+                        if frame.header.length as i64 > self.receive_window as i64 {
+                            (
+                                Some(
+                                    state::StreamStateName::Closed(
+                                        (
+                                            state,
+                                            state::StreamClosedInfo {
+                                                reason: state::StreamClosedReason::ResetLocal
+                                            }
+                                        ).into()
+                                    )
+                                ),
+                                Some(
+                                    error::HttpError::StreamError(
+                                        error::ErrorCode::FlowControlError,
+                                        error::ErrorName::StreamFlowControlWindowNotRespected
+                                    )
+                                )
                             )
                         }
                         else {
-                            None
-                        };
+                            self.receive_window -= frame.header.length as i32;
 
-                        self.request.payload = Some(
-                            data_frame.get_payload().to_vec()
-                        );
+                            let data_frame = framing::data::DataFrame::new(&frame.header, &mut frame.payload.into_iter());
 
-                        (new_state, None)
+                            let body_size = self.request.payload.as_ref().map(|payload| payload.len()).unwrap_or(0) + data_frame.get_payload().len();
+                            let is_body_too_large = self.connection_shared_state.borrow().max_request_body_size.map(|max_size| body_size > max_size).unwrap_or(false);
+
+                            if is_body_too_large {
+                                // The request won't be processed, so the stream is reset rather than receiving the rest of the body.
+                                (
+                                    Some(
+                                        state::StreamStateName::Closed(
+                                            (
+                                                state,
+                                                state::StreamClosedInfo {
+                                                    reason: state::StreamClosedReason::ResetLocal
+                                                }
+                                            ).into()
+                                        )
+                                    ),
+                                    Some(
+                                        error::HttpError::StreamError(
+                                            error::ErrorCode::Cancel,
+                                            error::ErrorName::RequestBodyTooLarge
+                                        )
+                                    )
+                                )
+                            }
+                            else {
+                                // If the client ended the stream then it becomes half closed remote.
+                                let new_state = if data_frame.is_end_stream() {
+                                    Some(
+                                        state::StreamStateName::HalfClosedRemote(state.into())
+                                    )
+                                }
+                                else {
+                                    // Top the window back up to the initial size so that the client can keep sending.
+                                    let initial_window_size = self.connection_shared_state.borrow().local_settings.initial_window_size as i32;
+                                    if self.receive_window < initial_window_size {
+                                        let update_amount = (initial_window_size - self.receive_window) as u32;
+                                        self.receive_window += update_amount as i32;
+                                        self.send_frames.push(Box::new(framing::window_update::WindowUpdateFrameCompressModel::new(update_amount)));
+                                    }
+
+                                    None
+                                };
+
+                                // The body can be split across any number of frames.
+                                self.request.payload.get_or_insert_with(Vec::new).extend_from_slice(data_frame.get_payload());
+
+                                (new_state, None)
+                            }
+                        }
                     },
                     framing::FrameType::Headers => {
                         // Decode and receive the header block.
@@ -382,7 +464,7 @@ impl Stream {
 
                         match window_update_frame {
                             Ok(frame) => {
-                                self.send_window += frame.get_window_size_increment();
+                                self.send_window += frame.get_window_size_increment() as i32;
                                 
                                 (None, None)
                             },
@@ -542,7 +624,7 @@ impl Stream {
 
                         match window_update_frame {
                             Ok(frame) => {
-                                self.send_window += frame.get_window_size_increment();
+                                self.send_window += frame.get_window_size_increment() as i32;
 
                                 (None, None)
                             },
@@ -638,6 +720,18 @@ impl Stream {
                         match frame.header.frame_type {
                             framing::FrameType::WindowUpdate => {
                                 // (5.1) Endpoints MUST ignore WINDOW_UPDATE or RST_STREAM frames received in this state
+                                // The stream closes as soon as the whole response has been generated, but the end of
+                                // the response may still be waiting for the send window. So the increment is kept
+                                // when it's valid, and the frame is otherwise ignored.
+                                let window_update_frame = frame_checking::window_update::check_stream_window_update(
+                                    framing::window_update::WindowUpdateFrame::new_stream(&frame.header, &mut frame.payload.into_iter()),
+                                    self.send_window
+                                );
+
+                                if let Ok(frame) = window_update_frame {
+                                    self.send_window += frame.get_window_size_increment() as i32;
+                                }
+
                                 (None, None)
                             },
                             framing::FrameType::ResetStream => {
@@ -704,6 +798,13 @@ impl Stream {
                                 // (5.1) Endpoints MUST ignore WINDOW_UPDATE or RST_STREAM frames received in this state
                                 (None, None)
                             },
+                            framing::FrameType::Data => {
+                                // (5.1) An endpoint MUST ignore frames that it receives on closed streams after it has sent
+                                // a RST_STREAM frame. The client may have sent the request body before it received the reset,
+                                // for example when the stream was refused. The data has already been counted against the
+                                // connection's flow control window.
+                                (None, None)
+                            },
                             _ => {
                                 // TODO there is more to do here. There is a small race condition, where the stream might be closed
                                 // because we've sent a reset or end stream but they haven't been received by the peer.
@@ -749,21 +850,13 @@ impl Stream {
         // The least bad error would still terminate this stream, so there's no need to process the request.
         if opt_err.is_none() {
             // Process the request if it is fully received.
-            self.try_start_process(app, hpack_send_context);
+            self.try_start_process();
         }
 
         opt_err
     }
 
-    pub fn recv_promised<T, R, S>(
-        &mut self,
-        hpack_send_context: &mut hpack_context::SendContext,
-        app: &T
-    ) -> Option<error::HttpError>
-        where T: server_trait::OsmiumServer<Request=R, Response=S>, 
-              R: convert::From<StreamRequest>,
-              S: convert::Into<StreamResponse>
-    {
+    pub fn recv_promised(&mut self) -> Option<error::HttpError> {
         // TODO Because promises are required to be 'safe', there is no need for the client to know
         // whether we've started processing a promise, the below can be safely removed.
 
@@ -784,17 +877,18 @@ impl Stream {
             }
         };
 
-        let response: StreamResponse = app.process(new_request.into(), Box::new(self)).into();
-
-        // Notice that we do not handle push promise here. That is because promises must be initiated on a peer initiated stream,
-        // which this stream will not be.
-
-        self.send(response.to_frames(hpack_send_context));
+        self.ready_request = Some(new_request);
 
         // TODO handle errors
         None
     }
 
+    /// Process the request on a stream created by `new_upgrade`. The request was fully received before
+    /// the connection switched protocols so there are no frames to wait for.
+    pub fn recv_upgrade(&mut self) {
+        self.try_start_process();
+    }
+
     fn send(&mut self, frames: Vec<Box<framing::CompressibleHttpFrame>>) {
         let mut temp_send_frames = Vec::new();
 
@@ -984,17 +1078,146 @@ impl Stream {
         self.send_frames.drain(0..).collect()
     }
 
+    /// Take the request on this stream if it is ready to be processed by the application.
+    pub fn fetch_ready_request(&mut self) -> Option<StreamRequest> {
+        self.ready_request.take()
+    }
+
+    /// Whether the remote may still send the rest of a request on this stream.
+    pub fn is_receiving(&self) -> bool {
+        match self.state_name {
+            state::StreamStateName::Idle(_) | state::StreamStateName::Open(_) | state::StreamStateName::HalfClosedLocal(_) => true,
+            _ => false
+        }
+    }
+
+    /// Whether the stream counts toward the limit on concurrent streams, which it does while it's open or half-closed (5.1.2).
+    pub fn is_active(&self) -> bool {
+        match self.state_name {
+            state::StreamStateName::Open(_) | state::StreamStateName::HalfClosedLocal(_) | state::StreamStateName::HalfClosedRemote(_) => true,
+            _ => false
+        }
+    }
+
+    /// Whether the stream was closed by a RST_STREAM, from either endpoint, rather than by ending it.
+    pub fn is_reset(&self) -> bool {
+        match self.state_name {
+            state::StreamStateName::Closed(ref state) => match state.state.info.reason {
+                state::StreamClosedReason::StreamEnded => false,
+                _ => true
+            },
+            _ => false
+        }
+    }
+
+    /// Whether the stream has been promised to the client and is waiting for its response to be sent.
+    pub fn is_reserved(&self) -> bool {
+        match self.state_name {
+            state::StreamStateName::ReservedLocal(_) => true,
+            _ => false
+        }
+    }
+
+    /// How much data may be sent on the stream before the client has to update the window.
+    pub fn get_send_window(&self) -> i32 {
+        self.send_window
+    }
+
+    /// Use up the send window for data which is being sent.
+    pub fn consume_send_window(&mut self, size: u32) {
+        self.send_window -= size as i32;
+    }
+
+    /// (6.9.2) Adjust the receive window by the change to the local initial window size, once the client has
+    /// acknowledged the change.
+    pub fn apply_local_initial_window_size_delta(&mut self, delta: i64) {
+        // The local settings have been validated, so the window can't exceed the maximum size.
+        self.receive_window = (self.receive_window as i64 + delta) as i32;
+    }
+
+    /// (6.9.2) Adjust the send window by the change to the client's initial window size. Closed streams are
+    /// adjusted too, because the end of their response may still be waiting for the send window.
+    pub fn apply_initial_window_size_delta(&mut self, delta: i64) -> Result<(), error::HttpError> {
+        let send_window = self.send_window as i64 + delta;
+
+        // (6.9.2) An endpoint MUST treat a change to SETTINGS_INITIAL_WINDOW_SIZE that causes any flow-control window
+        // to exceed the maximum size as a connection error (Section 5.4.1) of type FLOW_CONTROL_ERROR.
+        if send_window > settings::MAXIMUM_FLOW_CONTROL_WINDOW_SIZE as i64 {
+            return Err(error::HttpError::ConnectionError(
+                error::ErrorCode::FlowControlError,
+                error::ErrorName::InitialWindowSizeChangeWouldCauseSendWindowToExceedLimit
+            ));
+        }
+
+        self.send_window = send_window as i32;
+
+        Ok(())
+    }
+
+    /// Close the stream because the server has reset it. Its request won't be passed to the application.
+    pub fn reset_local(&mut self) {
+        let new_state = match self.state_name {
+            state::StreamStateName::ReservedLocal(ref state) => Some(state::StreamStateName::Closed(
+                (state, state::StreamClosedInfo { reason: state::StreamClosedReason::ResetLocal }).into()
+            )),
+            state::StreamStateName::Open(ref state) => Some(state::StreamStateName::Closed(
+                (state, state::StreamClosedInfo { reason: state::StreamClosedReason::ResetLocal }).into()
+            )),
+            state::StreamStateName::HalfClosedLocal(ref state) => Some(state::StreamStateName::Closed(
+                (state, state::StreamClosedInfo { reason: state::StreamClosedReason::ResetLocal }).into()
+            )),
+            state::StreamStateName::HalfClosedRemote(ref state) => Some(state::StreamStateName::Closed(
+                (state, state::StreamClosedInfo { reason: state::StreamClosedReason::ResetLocal }).into()
+            )),
+            _ => None
+        };
+
+        if let Some(new_state) = new_state {
+            self.state_name = new_state;
+        }
+
+        self.ready_request = None;
+    }
+
+    /// Send the application's response to the request on this stream, preceded by a promise for each of the 
+    /// requests it wants to push. The frames are encoded now rather than when the application produced them,
+    /// so that header blocks are compressed in the order they are sent.
+    pub fn send_response(&mut self, response: StreamResponse, push_promises: Vec<StreamRequest>, hpack_send_context: &mut hpack_context::SendContext) {
+        match self.state_name {
+            state::StreamStateName::ReservedLocal(_) | state::StreamStateName::Open(_) | state::StreamStateName::HalfClosedRemote(_) => {},
+            _ => {
+                // The stream has been closed, most likely reset by the client, while the application was working.
+                debug!("Discarding response for stream [{}] which can no longer be sent on", self.id);
+                return;
+            }
+        }
+
+        for request in push_promises {
+            let mut push_promise_frame = framing::push_promise::PushPromiseFrameCompressModel::new(true);
+
+            let promised_stream_identifier = self.connection_shared_state.borrow_mut().get_next_stream_id_for_locally_initiated_stream();
+            push_promise_frame.set_promised_stream_identifier(
+                promised_stream_identifier
+            );
+            push_promise_frame.set_header_block_fragment(
+                hpack_pack::pack(request.headers.iter(), hpack_send_context, true)
+            );
+
+            self.push_promise_publish_queue.push_front((promised_stream_identifier, request));
+
+            self.send(vec![Box::new(push_promise_frame)]);
+        }
+
+        self.send(response.to_frames(hpack_send_context));
+    }
+
     fn should_headers_frame_end_stream(&self) -> bool {
         // If the request headers have already been received, but another headers frame is
         // being processed then is must end the stream.
         !self.request.headers.is_empty()
     }
 
-    fn try_start_process<T, R, S>(&mut self, app: &T, hpack_send_context: &mut hpack_context::SendContext) 
-        where T: server_trait::OsmiumServer<Request=R, Response=S>,
-              R: convert::From<StreamRequest>,
-              S: convert::Into<StreamResponse>
-    {
+    fn try_start_process(&mut self) {
         match self.state_name {
             state::StreamStateName::HalfClosedRemote(_) => {
                 if !self.temp_header_block.is_empty() {
@@ -1017,52 +1240,12 @@ impl Stream {
                 let mut new_request = StreamRequest::new();
                 mem::swap(&mut self.request, &mut new_request);
 
-                trace!("Passing request to the application [{:?}]", new_request);
-                // TODO should the application be allowed to error?
-                let response: StreamResponse = app.process(new_request.into(), Box::new(self)).into();
-                trace!("Got response from the application [{:?}]", response);
-
-                // TODO this has been duplicated.
-                while let Some(request) = self.push_promise_queue.pop_back() {
-                    let mut push_promise_frame = framing::push_promise::PushPromiseFrameCompressModel::new(true);
-
-                    let promised_stream_identifier = self.connection_shared_state.borrow_mut().get_next_stream_id_for_locally_initiated_stream();
-                    push_promise_frame.set_promised_stream_identifier(
-                        promised_stream_identifier
-                    );
-                    push_promise_frame.set_header_block_fragment(
-                        hpack_pack::pack(request.headers.iter(), hpack_send_context, true)
-                    );
-
-                    self.push_promise_publish_queue.push_front((promised_stream_identifier, request));
-
-                    self.send(vec![Box::new(push_promise_frame)]);
-                }
-
-                self.send(response.to_frames(hpack_send_context));
+                trace!("Request is ready to be passed to the application [{:?}]", new_request);
+                self.ready_request = Some(new_request);
             },
             _ => {
                 // Request not fully received, do nothing.
             }
         }
     }
-
-    fn queue_push_promise(&mut self, request: StreamRequest) -> Option<push_error::PushError> {
-        self.push_promise_queue.push_front(request);
-
-        // TODO handle errors.
-        None
-    }
-}
-
-impl ConnectionHandle for Stream {
-    fn is_push_enabled(&self) -> bool {
-        // TODO modify the stream to understand that it is a synthetic stream, and do not allow promises to be sent in that case.
-        // TODO test that updating server push setting while running actually updates this value.
-        self.connection_shared_state.borrow().remote_settings.enable_push
-    }
-
-    fn push_promise(&mut self, request: StreamRequest) -> Option<push_error::PushError> {
-        self.queue_push_promise(request)
-    }
 }
@@ -0,0 +1,102 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::sync::Arc;
+use std::time::Duration;
+
+// osmium
+use http2::net::settings_update::{self, SettingsUpdateError, SettingsUpdateSender};
+use http2::settings::SettingsParameter;
+use shared::connection_handle::ConnectionHandle;
+use shared::connection_info::{ConnectionInfo, PeerCertificate};
+use shared::push_error;
+use shared::request as shared_request;
+use super::StreamRequest;
+
+/// The connection handle given to the application while it processes the request on a stream.
+///
+/// The application runs on a worker thread, so it can't use the connection directly. Instead, the push promises
+/// it makes are collected here and sent ahead of the response once it has been passed back to the connection.
+pub struct StreamHandle {
+    push_enabled: bool,
+    max_push_promises: Option<usize>,
+    push_promises: Vec<StreamRequest>,
+    connection_info: Arc<ConnectionInfo>,
+    settings_update_tx: SettingsUpdateSender
+}
+
+impl StreamHandle {
+    /// Changes to the settings are sent straight to the connection, rather than waiting for the response. The limit on
+    /// push promises is how many more streams the client allowed the server to push when the request was dispatched.
+    pub fn new(push_enabled: bool, max_push_promises: Option<usize>, connection_info: Arc<ConnectionInfo>, settings_update_tx: SettingsUpdateSender) -> Self {
+        StreamHandle {
+            push_enabled: push_enabled,
+            max_push_promises: max_push_promises,
+            push_promises: Vec::new(),
+            connection_info: connection_info,
+            settings_update_tx: settings_update_tx
+        }
+    }
+
+    /// The requests the application promised to respond to, in the order the promises were made.
+    pub fn into_push_promises(self) -> Vec<StreamRequest> {
+        self.push_promises
+    }
+}
+
+impl ConnectionHandle for StreamHandle {
+    fn is_push_enabled(&self) -> bool {
+        self.push_enabled
+    }
+
+    fn push_promise(&mut self, request: shared_request::Request) -> Option<push_error::PushError> {
+        if !self.push_enabled {
+            return Some(push_error::PushError::PushDisabled);
+        }
+
+        // (5.1.2) The client limits how many streams the server may have open at once.
+        if let Some(max_push_promises) = self.max_push_promises {
+            if self.push_promises.len() >= max_push_promises {
+                return Some(push_error::PushError::TooManyActiveStreams);
+            }
+        }
+
+        self.push_promises.push(request.into());
+
+        None
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.connection_info.get_server_name()
+    }
+
+    fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.connection_info.get_peer_certificate()
+    }
+
+    fn round_trip_time(&self) -> Option<Duration> {
+        // The connection keeps measuring while the request is processed, so this is the latest measurement.
+        self.connection_info.get_round_trip_time()
+    }
+
+    fn update_settings(&mut self, changes: Vec<SettingsParameter>) -> Result<(), SettingsUpdateError> {
+        settings_update::validate(&changes)?;
+
+        self.settings_update_tx.unbounded_send(changes).map_err(|_| SettingsUpdateError::ConnectionClosed)
+    }
+}
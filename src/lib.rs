@@ -18,14 +18,16 @@
 extern crate bytes;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate net2;
 extern crate tokio_io;
 extern crate tokio_core;
+#[cfg(unix)]
+extern crate tokio_uds;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate httparse;
 #[macro_use] extern crate log;
 extern crate pretty_env_logger;
-extern crate threadpool;
 
 extern crate openssl;
 extern crate tokio_openssl;
@@ -15,11 +15,15 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium. If not, see <http://www.gnu.org/licenses/>.
 
-// TODO remove stream requests and responses from the system completely. There is no difference
-// between http1 and http2 request/response so the shared module should contain a single representation
-// of each.
-use http2::stream::StreamRequest;
+// std
+use std::time::Duration;
+
+// osmium
+use http2::settings::SettingsParameter;
 use shared::push_error;
+use shared::settings_update_error::SettingsUpdateError;
+use shared::request::Request;
+use shared::connection_info::PeerCertificate;
 
 /// Trait to be implemented as part of an http implementation. That need not be the connection
 /// representation though, any struct which has access to the data required to implement the trait 
@@ -41,5 +45,30 @@ pub trait ConnectionHandle {
     /// This method MUST NOT be called if `is_push_enabled` yields false in the same application
     /// processing call.
     // TODO point 2 about promise rejection above is not implemented
-    fn push_promise(&mut self, request: StreamRequest) -> Option<push_error::PushError>;
+    fn push_promise(&mut self, request: Request) -> Option<push_error::PushError>;
+
+    /// The server name the client asked for using SNI. Connections without TLS, and clients which
+    /// didn't send a server name, have none.
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The certificate the client authenticated with, when the server asks clients for one. It has been verified
+    /// against the trusted client CAs.
+    fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        None
+    }
+
+    /// The time the client took to acknowledge the server's most recent ping. There is none until the server
+    /// has pinged the client, which only happens if the server is set up to ping idle connections.
+    fn round_trip_time(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Send the client changes to the HTTP/2 settings of this connection, for example to raise `initial_window_size`
+    /// for a large upload. The changes are checked straight away, then sent by the connection. They take effect once
+    /// the client has acknowledged them.
+    fn update_settings(&mut self, _changes: Vec<SettingsParameter>) -> Result<(), SettingsUpdateError> {
+        Err(SettingsUpdateError::NotSupported)
+    }
 }
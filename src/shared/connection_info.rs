@@ -0,0 +1,107 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What the server learned about a connection while establishing it. The application can read this
+/// through the connection handle while it processes a request.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    server_name: Option<String>,
+    peer_certificate: Option<PeerCertificate>,
+    // Measured by the connection while it's open, so clones see the latest value.
+    round_trip_time: Arc<Mutex<Option<Duration>>>
+}
+
+/// The certificate a client authenticated with, which has been verified against the trusted client CAs.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    subject: String,
+    common_name: Option<String>,
+    dns_names: Vec<String>,
+    ip_addresses: Vec<net::IpAddr>
+}
+
+impl ConnectionInfo {
+    pub fn new() -> Self {
+        ConnectionInfo {
+            server_name: None,
+            peer_certificate: None,
+            round_trip_time: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// The server name the client asked for using SNI during the TLS handshake.
+    pub fn get_server_name(&self) -> Option<&str> {
+        self.server_name.as_ref().map(|server_name| server_name.as_ref())
+    }
+
+    pub fn set_server_name(&mut self, server_name: String) {
+        self.server_name = Some(server_name);
+    }
+
+    /// The verified client certificate, when the client was asked for one and sent it.
+    pub fn get_peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.peer_certificate.as_ref()
+    }
+
+    pub fn set_peer_certificate(&mut self, peer_certificate: PeerCertificate) {
+        self.peer_certificate = Some(peer_certificate);
+    }
+
+    /// The time the client took to acknowledge the most recent ping which it has acknowledged so far.
+    pub fn get_round_trip_time(&self) -> Option<Duration> {
+        *self.round_trip_time.lock().unwrap()
+    }
+
+    pub fn set_round_trip_time(&self, round_trip_time: Option<Duration>) {
+        *self.round_trip_time.lock().unwrap() = round_trip_time;
+    }
+}
+
+impl PeerCertificate {
+    pub fn new(subject: String, common_name: Option<String>, dns_names: Vec<String>, ip_addresses: Vec<net::IpAddr>) -> Self {
+        PeerCertificate {
+            subject: subject,
+            common_name: common_name,
+            dns_names: dns_names,
+            ip_addresses: ip_addresses
+        }
+    }
+
+    /// The subject's distinguished name, for example `CN=client, O=Example, C=GB`.
+    pub fn get_subject(&self) -> &str {
+        self.subject.as_ref()
+    }
+
+    pub fn get_common_name(&self) -> Option<&str> {
+        self.common_name.as_ref().map(|common_name| common_name.as_ref())
+    }
+
+    /// The DNS names from the subject alternative names.
+    pub fn get_dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    /// The IP addresses from the subject alternative names.
+    pub fn get_ip_addresses(&self) -> &[net::IpAddr] {
+        &self.ip_addresses
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::fs;
+use std::net;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net as unix_net;
+use std::path;
+use std::time::Duration;
+
+// tokio
+use futures::{Async, Future, Stream, Poll};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio_uds::{UnixListener, UnixStream};
+
+// net2
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+
+// futures-cpupool
+use futures_cpupool::CpuPool;
+
+// osmium
+use shared::server_settings;
+
+// How long to wait before accepting again after an error. Errors such as running out of file descriptors usually
+// last until some connections close, so trying again straight away would only keep the event loop busy.
+const ACCEPT_ERROR_DELAY_MILLIS: u64 = 100;
+
+/// A listener which has been bound, but isn't registered with an event loop yet. This allows the address it 
+/// was bound to, which is chosen by the operating system when the port is 0, to be found before the event 
+/// loop is started.
+pub enum BoundListener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(unix_net::UnixListener, SocketFile)
+}
+
+/// A listener which accepts connections on the event loop.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, SocketFile)
+}
+
+/// The file of a Unix domain socket listener. It's removed when the listener is dropped, so that the next
+/// server to start can bind the same path.
+#[cfg(unix)]
+pub struct SocketFile {
+    path: path::PathBuf
+}
+
+/// A connection which was accepted by one of the listeners.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl BoundListener {
+    /// The address of a TCP listener. Unix domain sockets don't have one.
+    pub fn local_addr(&self) -> Option<net::SocketAddr> {
+        match *self {
+            BoundListener::Tcp(ref listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            BoundListener::Unix(..) => None
+        }
+    }
+
+    pub fn register(self, handle: &reactor::Handle) -> io::Result<Listener> {
+        match self {
+            BoundListener::Tcp(listener) => {
+                let addr = listener.local_addr()?;
+                Ok(Listener::Tcp(TcpListener::from_listener(listener, &addr, handle)?))
+            },
+            #[cfg(unix)]
+            BoundListener::Unix(listener, socket_file) => {
+                Ok(Listener::Unix(UnixListener::from_listener(listener, handle)?, socket_file))
+            }
+        }
+    }
+}
+
+impl Listener {
+    /// The connections accepted by this listener, along with a description of the peer for logging.
+    ///
+    /// A failure to accept a connection is logged and accepting carries on shortly afterwards, so the stream
+    /// only fails if the delay before the next attempt can't be started.
+    pub fn incoming(self, handle: &reactor::Handle) -> Box<Stream<Item = (Socket, String), Error = io::Error>> {
+        Box::new(SkipAcceptErrors {
+            connections: self.accept(),
+            delay: None,
+            handle: handle.clone()
+        })
+    }
+
+    fn accept(self) -> Box<Stream<Item = (Socket, String), Error = io::Error>> {
+        match self {
+            Listener::Tcp(listener) => {
+                Box::new(listener.incoming().map(|(socket, remote_addr)| {
+                    (Socket::Tcp(socket), remote_addr.to_string())
+                }))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, socket_file) => {
+                // The socket file lives as long as the connections are being accepted.
+                Box::new(listener.incoming().map(move |(socket, _remote_addr)| {
+                    let _ = &socket_file;
+                    (Socket::Unix(socket), String::from("unix socket"))
+                }))
+            }
+        }
+    }
+}
+
+struct SkipAcceptErrors<S> {
+    connections: S,
+    // Accepting waits for this after an error.
+    delay: Option<reactor::Timeout>,
+    handle: reactor::Handle
+}
+
+impl<S> Stream for SkipAcceptErrors<S>
+    where S: Stream<Error = io::Error>
+{
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(mut delay) = self.delay.take() {
+                if let Async::NotReady = delay.poll()? {
+                    self.delay = Some(delay);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            match self.connections.poll() {
+                Err(e) => {
+                    error!("Failed to accept a connection, will try again [{}]", e);
+                    self.delay = Some(reactor::Timeout::new(Duration::from_millis(ACCEPT_ERROR_DELAY_MILLIS), &self.handle)?);
+                },
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove socket file [{}] {:?}", self.path.display(), e);
+        }
+    }
+}
+
+/// Create the listeners from the settings, in the order they are given by `get_listeners`.
+///
+/// The settings are expected to have been validated already.
+pub fn bind(settings: &server_settings::ServerSettings, handle: &reactor::Handle) -> io::Result<Vec<Listener>> {
+    bind_all(settings)?.into_iter().map(|listener| listener.register(handle)).collect()
+}
+
+/// Bind every listener from the settings without registering them with an event loop.
+pub fn bind_all(settings: &server_settings::ServerSettings) -> io::Result<Vec<BoundListener>> {
+    settings.get_listeners().iter().map(|listener_settings| {
+        match *listener_settings.get_address() {
+            server_settings::ListenAddress::Tcp(..) => {
+                bind_tcp(listener_settings, settings).map(BoundListener::Tcp)
+            },
+            server_settings::ListenAddress::Unix(ref path) => {
+                bind_unix(path)
+            }
+        }
+    }).collect()
+}
+
+fn bind_tcp(listener_settings: &server_settings::ListenerSettings, settings: &server_settings::ServerSettings) -> io::Result<net::TcpListener> {
+    let addr = listener_settings.get_socket_address().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e))
+    })?;
+
+    let builder = match addr {
+        net::SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        net::SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            // Listening on IPv4 as well is decided by the settings, with a separate listener.
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+
+    builder.reuse_address(true)?;
+    set_reuse_port(&builder, settings.is_reuse_port())?;
+
+    builder.bind(addr)?;
+
+    // The operating system caps the backlog anyway, so a larger value than listen accepts is the same as the largest.
+    builder.listen(cmp::min(settings.get_listen_backlog(), i32::MAX as u32) as i32)
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &path::Path) -> io::Result<BoundListener> {
+    remove_stale_socket_file(path)?;
+
+    let listener = unix_net::UnixListener::bind(path)?;
+    Ok(BoundListener::Unix(listener, SocketFile { path: path.to_path_buf() }))
+}
+
+// A socket file which is left behind by a server which didn't shut down cleanly would stop the path from being
+// bound again. Nothing is listening on it if connecting is refused, so it can be removed. Anything else at the
+// path is left alone, and binding will report that the address is in use.
+#[cfg(unix)]
+fn remove_stale_socket_file(path: &path::Path) -> io::Result<()> {
+    let is_socket = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type().is_socket(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    if !is_socket {
+        return Ok(());
+    }
+
+    match unix_net::UnixStream::connect(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket file [{}]", path.display());
+            fs::remove_file(path)
+        },
+        _ => Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &path::Path) -> io::Result<BoundListener> {
+    // Validation rejects Unix domain sockets on other platforms.
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "unix domain sockets are not supported on this platform"))
+}
+
+/// Apply the socket options for accepted connections.
+pub fn configure_connection(socket: &Socket, settings: &server_settings::ServerSettings) -> io::Result<()> {
+    match *socket {
+        Socket::Tcp(ref socket) => socket.set_nodelay(settings.is_tcp_nodelay()),
+        #[cfg(unix)]
+        Socket::Unix(_) => Ok(())
+    }
+}
+
+/// Create the workers which process requests, one for each CPU unless a worker count has been set.
+pub fn new_worker_pool(settings: &server_settings::ServerSettings) -> CpuPool {
+    match settings.get_worker_count() {
+        Some(worker_count) => CpuPool::new(worker_count),
+        None => CpuPool::new_num_cpus()
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &TcpBuilder, reuse_port: bool) -> io::Result<()> {
+    builder.reuse_port(reuse_port)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_builder: &TcpBuilder, _reuse_port: bool) -> io::Result<()> {
+    // Validation rejects SO_REUSEPORT on other platforms.
+    Ok(())
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl AsyncRead for Socket {}
+
+impl AsyncWrite for Socket {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Socket::Tcp(ref mut stream) => AsyncWrite::shutdown(stream),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => AsyncWrite::shutdown(stream)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SkipAcceptErrors;
+
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use futures::{stream, Stream};
+    use tokio_core::reactor;
+
+    #[test]
+    pub fn keep_accepting_after_error() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let connections = stream::iter_result(vec![Err(io::Error::new(io::ErrorKind::Other, "too many open files")), Ok(1)]);
+
+        let incoming = SkipAcceptErrors {
+            connections: connections,
+            delay: None,
+            handle: event_loop.handle()
+        };
+
+        let start = Instant::now();
+        let accepted = event_loop.run(incoming.collect()).unwrap();
+
+        assert_eq!(vec![1], accepted);
+        assert!(start.elapsed() >= Duration::from_millis(super::ACCEPT_ERROR_DELAY_MILLIS));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_tests {
+    use super::{bind, bind_all, BoundListener, Socket};
+
+    use std::env;
+    use std::fs;
+    use std::net;
+    use std::os::unix::net as unix_net;
+    use std::path;
+    use std::process;
+
+    use futures::Stream;
+    use tokio_core::reactor;
+
+    use shared::server_settings::{ListenerSettings, ServerSettings};
+
+    // A directory of its own for each test, so that tests running at the same time don't share socket files.
+    fn socket_dir(name: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join(format!("osmium_listener_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tcp_and_unix_settings(socket_path: &path::Path) -> ServerSettings {
+        let mut settings = ServerSettings::default();
+        settings.set_host(String::from("127.0.0.1"));
+        settings.set_port(0);
+        settings.add_listener(ListenerSettings::unix(socket_path));
+        settings
+    }
+
+    #[test]
+    pub fn accept_on_every_listener() {
+        let dir = socket_dir("accept");
+        let socket_path = dir.join("server.sock");
+        let settings = tcp_and_unix_settings(&socket_path);
+
+        let mut event_loop = reactor::Core::new().unwrap();
+        let handle = event_loop.handle();
+        let mut listeners = bind(&settings, &handle).unwrap();
+        assert_eq!(2, listeners.len());
+
+        let unix_listener = listeners.pop().unwrap();
+        let tcp_listener = listeners.pop().unwrap();
+        let tcp_addr = match tcp_listener {
+            super::Listener::Tcp(ref listener) => listener.local_addr().unwrap(),
+            _ => panic!("expected the first listener to be the TCP one")
+        };
+
+        let _tcp_client = net::TcpStream::connect(tcp_addr).unwrap();
+        let (tcp_socket, _) = event_loop.run(tcp_listener.incoming(&handle).into_future()).map_err(|(e, _)| e).unwrap();
+        match tcp_socket {
+            Some((Socket::Tcp(_), _)) => {},
+            _ => panic!("expected a TCP connection")
+        }
+
+        let _unix_client = unix_net::UnixStream::connect(&socket_path).unwrap();
+        let (unix_socket, unix_incoming) = event_loop.run(unix_listener.incoming(&handle).into_future()).map_err(|(e, _)| e).unwrap();
+        match unix_socket {
+            Some((Socket::Unix(_), _)) => {},
+            _ => panic!("expected a Unix domain socket connection")
+        }
+
+        // The socket file is removed once the listener is dropped, so that the path can be bound again.
+        drop(unix_incoming);
+        assert!(!socket_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn replace_stale_socket_file() {
+        let dir = socket_dir("stale");
+        let socket_path = dir.join("server.sock");
+
+        // A listener which is dropped without cleaning up leaves its socket file behind.
+        drop(unix_net::UnixListener::bind(&socket_path).unwrap());
+        assert!(socket_path.exists());
+
+        let listeners = bind_all(&tcp_and_unix_settings(&socket_path)).unwrap();
+        match listeners[1] {
+            BoundListener::Unix(..) => {},
+            _ => panic!("expected a Unix domain socket listener")
+        }
+
+        // The path is still in use while the server is listening on it.
+        assert!(bind_all(&tcp_and_unix_settings(&socket_path)).is_err());
+
+        drop(listeners);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
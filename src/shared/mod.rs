@@ -17,5 +17,17 @@
 
 pub mod server_trait;
 pub mod server_settings;
+pub mod listener;
+pub mod shutdown;
+pub mod server_handle;
 pub mod connection_handle;
+pub mod connection_info;
+pub mod transport;
+pub mod timeout;
 pub mod push_error;
+pub mod settings_update_error;
+pub mod request;
+pub mod response;
+
+pub use self::request::Request;
+pub use self::response::Response;
@@ -21,5 +21,8 @@
 pub enum PushError {
     /// This error occurs when an attempt is made to create a new push promise but
     /// the allowed limit for concurrent promises has already been reached.
-    TooManyActiveStreams
+    TooManyActiveStreams,
+    /// This error occurs when an attempt is made to create a new push promise but
+    /// the client has disabled push, or the connection's protocol doesn't have push.
+    PushDisabled
 }
@@ -0,0 +1,205 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// osmium
+use http2::header;
+use http2::stream as streaming;
+
+/// A request received from a client, which is the same whether it was sent over HTTP/1.1 or HTTP/2.
+///
+/// The HTTP/2 pseudo headers (8.1.2.3) are available through their own accessors, and are not included
+/// in the headers. A request can also be built by the application, to push a promise to the client.
+#[derive(Debug)]
+pub struct Request {
+    method: String,
+    scheme: String,
+    authority: Option<String>,
+    uri: String,
+    headers: header::Headers,
+    body: Option<Vec<u8>>,
+    trailers: Option<header::Headers>
+}
+
+impl Request {
+    pub fn new(method: &str, scheme: &str, authority: &str, uri: &str) -> Self {
+        Request {
+            method: String::from(method),
+            scheme: String::from(scheme),
+            authority: Some(String::from(authority)),
+            uri: String::from(uri),
+            headers: header::Headers::new(),
+            body: None,
+            trailers: None
+        }
+    }
+
+    pub fn get_method(&self) -> &str {
+        self.method.as_ref()
+    }
+
+    pub fn get_scheme(&self) -> &str {
+        self.scheme.as_ref()
+    }
+
+    /// The host (and port) which the request was sent to, if the client provided it.
+    pub fn get_authority(&self) -> Option<&str> {
+        self.authority.as_ref().map(|authority| authority.as_ref())
+    }
+
+    /// The path and query of the request target.
+    pub fn get_uri(&self) -> &str {
+        self.uri.as_ref()
+    }
+
+    pub fn get_headers(&self) -> &header::Headers {
+        &self.headers
+    }
+
+    pub fn get_headers_mut(&mut self) -> &mut header::Headers {
+        &mut self.headers
+    }
+
+    /// Find the value of the first header with the given name.
+    pub fn get_header(&self, name: &header::HeaderName) -> Option<&header::HeaderValue> {
+        self.headers.iter().find(|header| header.name == *name).map(|header| &header.value)
+    }
+
+    pub fn get_body(&self) -> Option<&[u8]> {
+        self.body.as_ref().map(|body| body.as_slice())
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Some(body);
+    }
+
+    pub fn take_body(&mut self) -> Option<Vec<u8>> {
+        self.body.take()
+    }
+
+    pub fn get_trailers(&self) -> Option<&header::Headers> {
+        self.trailers.as_ref()
+    }
+}
+
+impl From<streaming::StreamRequest> for Request {
+    fn from(stream_request: streaming::StreamRequest) -> Request {
+        let mut request = Request {
+            method: String::new(),
+            scheme: String::new(),
+            authority: None,
+            uri: String::new(),
+            headers: header::Headers::new(),
+            body: stream_request.payload,
+            trailers: stream_request.trailer_headers
+        };
+
+        for header in stream_request.headers.into_iter() {
+            match header.name {
+                header::HeaderName::PseudoMethod => {
+                    request.method = header.value.into();
+                },
+                header::HeaderName::PseudoScheme => {
+                    request.scheme = header.value.into();
+                },
+                header::HeaderName::PseudoAuthority => {
+                    request.authority = Some(header.value.into());
+                },
+                header::HeaderName::PseudoPath => {
+                    request.uri = header.value.into();
+                },
+                _ => {
+                    request.headers.push_header(header);
+                }
+            }
+        }
+
+        request
+    }
+}
+
+impl From<Request> for streaming::StreamRequest {
+    fn from(request: Request) -> streaming::StreamRequest {
+        // (8.1.2.1) All pseudo headers must come before the regular headers.
+        let mut headers = header::Headers::new();
+        headers.push(header::HeaderName::PseudoMethod, header::HeaderValue::Str(request.method));
+        headers.push(header::HeaderName::PseudoScheme, header::HeaderValue::Str(request.scheme));
+        headers.push(header::HeaderName::PseudoPath, header::HeaderValue::Str(request.uri));
+        if let Some(authority) = request.authority {
+            headers.push(header::HeaderName::PseudoAuthority, header::HeaderValue::Str(authority));
+        }
+
+        for header in request.headers.into_iter() {
+            headers.push_header(header);
+        }
+
+        streaming::StreamRequest {
+            headers: headers,
+            payload: request.body,
+            trailer_headers: request.trailers
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Request;
+    use http2::header;
+    use http2::stream as streaming;
+
+    #[test]
+    pub fn from_stream_request() {
+        let mut headers = header::Headers::new();
+        headers.push(header::HeaderName::PseudoMethod, header::HeaderValue::Str(String::from("POST")));
+        headers.push(header::HeaderName::PseudoScheme, header::HeaderValue::Str(String::from("https")));
+        headers.push(header::HeaderName::PseudoPath, header::HeaderValue::Str(String::from("/upload?name=a")));
+        headers.push(header::HeaderName::PseudoAuthority, header::HeaderValue::Str(String::from("localhost:8080")));
+        headers.push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/plain")));
+
+        let request = Request::from(streaming::StreamRequest {
+            headers: headers,
+            payload: Some(b"hello".to_vec()),
+            trailer_headers: None
+        });
+
+        assert_eq!("POST", request.get_method());
+        assert_eq!("https", request.get_scheme());
+        assert_eq!(Some("localhost:8080"), request.get_authority());
+        assert_eq!("/upload?name=a", request.get_uri());
+        assert_eq!(1, request.get_headers().len());
+        assert_eq!(Some(&header::HeaderValue::Str(String::from("text/plain"))), request.get_header(&header::HeaderName::ContentType));
+        assert_eq!(Some(&b"hello"[..]), request.get_body());
+        assert!(request.get_trailers().is_none());
+    }
+
+    #[test]
+    pub fn into_stream_request() {
+        let mut request = Request::new("GET", "https", "localhost:8080", "/style.css");
+        request.get_headers_mut().push(header::HeaderName::Accept, header::HeaderValue::Str(String::from("text/css")));
+
+        let stream_request = streaming::StreamRequest::from(request);
+
+        let names: Vec<header::HeaderName> = stream_request.headers.iter().map(|header| header.name.clone()).collect();
+        assert_eq!(vec![
+            header::HeaderName::PseudoMethod,
+            header::HeaderName::PseudoScheme,
+            header::HeaderName::PseudoPath,
+            header::HeaderName::PseudoAuthority,
+            header::HeaderName::Accept
+        ], names);
+        assert!(stream_request.payload.is_none());
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// osmium
+use http2::header;
+use http2::stream as streaming;
+
+/// A response to send to a client, which is the same whether the client is using HTTP/1.1 or HTTP/2.
+///
+/// The status is sent as the :status pseudo header for HTTP/2 and in the status line for HTTP/1.1, so
+/// it should not be added to the headers.
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    headers: header::Headers,
+    body: Option<Vec<u8>>,
+    trailers: Option<header::Headers>
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status: status,
+            headers: header::Headers::new(),
+            body: None,
+            trailers: None
+        }
+    }
+
+    pub fn get_status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    pub fn get_headers(&self) -> &header::Headers {
+        &self.headers
+    }
+
+    pub fn get_headers_mut(&mut self) -> &mut header::Headers {
+        &mut self.headers
+    }
+
+    pub fn get_body(&self) -> Option<&[u8]> {
+        self.body.as_ref().map(|body| body.as_slice())
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Some(body);
+    }
+
+    pub fn get_trailers(&self) -> Option<&header::Headers> {
+        self.trailers.as_ref()
+    }
+
+    pub fn set_trailers(&mut self, trailers: header::Headers) {
+        self.trailers = Some(trailers);
+    }
+}
+
+impl From<Response> for streaming::StreamResponse {
+    fn from(response: Response) -> streaming::StreamResponse {
+        let mut headers = header::Headers::new();
+        headers.push(header::HeaderName::PseudoStatus, header::HeaderValue::Num(response.status as i32));
+
+        for header in response.headers.into_iter() {
+            headers.push_header(header);
+        }
+
+        streaming::StreamResponse {
+            informational_headers: Vec::new(),
+            headers: headers,
+            payload: response.body,
+            trailer_headers: response.trailers
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+    use http2::header;
+    use http2::stream as streaming;
+
+    #[test]
+    pub fn into_stream_response() {
+        let mut response = Response::new(404);
+        response.get_headers_mut().push(header::HeaderName::ContentType, header::HeaderValue::Str(String::from("text/plain")));
+        response.set_body(b"not found".to_vec());
+
+        let stream_response = streaming::StreamResponse::from(response);
+
+        let status = stream_response.headers.iter().next().unwrap();
+        assert_eq!(header::HeaderName::PseudoStatus, status.name);
+        assert_eq!(header::HeaderValue::Num(404), status.value);
+        assert_eq!(2, stream_response.headers.len());
+        assert_eq!(Some(b"not found".to_vec()), stream_response.payload);
+        assert!(stream_response.trailer_headers.is_none());
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::io;
+use std::net;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+// tokio
+use tokio_core::reactor;
+
+// osmium
+use shared::listener;
+use shared::shutdown;
+
+/// A server which is running on a background thread.
+pub struct ServerHandle {
+    local_addrs: Vec<net::SocketAddr>,
+    shutdown_handle: shutdown::ShutdownHandle,
+    join_handle: thread::JoinHandle<()>
+}
+
+impl ServerHandle {
+    /// The address the server is listening on for its host and port. If the server was configured with
+    /// port 0, this has the port which was chosen by the operating system.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// The addresses of all of the TCP listeners, in the order they were configured.
+    pub fn local_addrs(&self) -> &[net::SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn shutdown_handle(&self) -> shutdown::ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// Shut the server down gracefully, waiting at most until the deadline for open connections to close,
+    /// and wait for the server thread to finish.
+    pub fn stop(self, deadline: time::Duration) -> thread::Result<()> {
+        self.shutdown_handle.shutdown(deadline);
+        self.join_handle.join()
+    }
+
+    /// Wait for the server thread to finish, which happens once the server has been shut down.
+    pub fn join(self) -> thread::Result<()> {
+        self.join_handle.join()
+    }
+}
+
+/// Run a server on a new thread. The listeners are moved onto an event loop created on that thread, which is
+/// then given to `run`. This returns once the event loop has been created, so that any error can be reported.
+///
+/// The first listener must be a TCP listener.
+pub fn spawn<F>(listeners: Vec<listener::BoundListener>, shutdown_handle: shutdown::ShutdownHandle, run: F) -> io::Result<ServerHandle>
+    where F: 'static + FnOnce(reactor::Core, Vec<listener::Listener>) + Send
+{
+    let local_addrs: Vec<net::SocketAddr> = listeners.iter().filter_map(|listener| listener.local_addr()).collect();
+
+    let (started_tx, started_rx) = mpsc::channel();
+
+    let join_handle = thread::Builder::new().name(String::from("osmium-server")).spawn(move || {
+        let event_loop = match reactor::Core::new() {
+            Ok(event_loop) => event_loop,
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let listeners: io::Result<Vec<listener::Listener>> = listeners.into_iter().map(|listener| listener.register(&event_loop.handle())).collect();
+        let listeners = match listeners {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let _ = started_tx.send(Ok(()));
+
+        run(event_loop, listeners);
+    })?;
+
+    match started_rx.recv() {
+        Ok(Ok(())) => {
+            Ok(ServerHandle {
+                local_addrs: local_addrs,
+                shutdown_handle: shutdown_handle,
+                join_handle: join_handle
+            })
+        },
+        Ok(Err(e)) => {
+            Err(e)
+        },
+        Err(_) => {
+            Err(io::Error::new(io::ErrorKind::Other, "the server thread stopped before it started listening"))
+        }
+    }
+}
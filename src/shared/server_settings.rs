@@ -11,30 +11,267 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
 
+// std
+use std::net;
+use std::path;
+use std::time;
+use std::sync::Arc;
+
 // osmium
 use http2::settings as http2_settings;
+use shared::transport::TlsAcceptor;
+
+/// The reasons the server settings can be rejected when a server is created.
+#[derive(Debug)]
+pub enum SettingsError {
+    /// The host and port don't make a valid socket address.
+    InvalidBindAddress,
+    /// At least one worker is needed to process requests.
+    ZeroWorkerCount,
+    /// A connection's queues must be able to hold at least one item.
+    ZeroQueueDepth,
+    /// The listener must be able to queue at least one connection waiting to be accepted.
+    ZeroListenBacklog,
+    /// SO_REUSEPORT is only available on unix platforms.
+    ReusePortNotSupported,
+    /// Unix domain sockets are only available on unix platforms.
+    UnixSocketNotSupported,
+    /// The certificate files can't be checked for changes continuously.
+    ZeroCertificateWatchInterval,
+    /// A timeout of zero would close every connection straight away.
+    ZeroTimeout,
+    /// Idle connections can't be pinged continuously.
+    ZeroPingInterval,
+    /// A connection would be closed as soon as it was pinged.
+    ZeroMaxMissedPings
+}
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone)]
+pub enum ListenAddress {
+    /// A host and port to accept TCP connections on.
+    Tcp(String, u16),
+    /// The path of a Unix domain socket to create and accept connections on.
+    Unix(path::PathBuf)
+}
+
+/// A listener in addition to the one on the server's host and port.
+#[derive(Clone)]
+pub struct ListenerSettings {
+    address: ListenAddress,
+    security: Option<SecuritySettings>,
+    tls_acceptor: Option<Arc<TlsAcceptor + Send + Sync>>
+}
+
+impl ListenerSettings {
+    pub fn tcp(host: String, port: u16) -> Self {
+        ListenerSettings {
+            address: ListenAddress::Tcp(host, port),
+            security: None,
+            tls_acceptor: None
+        }
+    }
+
+    /// The socket file is created when the server starts and removed when it stops. A socket file which was left
+    /// behind by a server that didn't stop cleanly is replaced, but nothing else may exist at the path.
+    pub fn unix<P: Into<path::PathBuf>>(path: P) -> Self {
+        ListenerSettings {
+            address: ListenAddress::Unix(path.into()),
+            security: None,
+            tls_acceptor: None
+        }
+    }
+
+    pub fn get_address(&self) -> &ListenAddress {
+        &self.address
+    }
+
+    pub fn get_security(&self) -> Option<SecuritySettings> {
+        self.security.clone()
+    }
+
+    pub fn set_security(&mut self, security: SecuritySettings) {
+        self.security = Some(security);
+    }
+
+    pub fn get_tls_acceptor(&self) -> Option<Arc<TlsAcceptor + Send + Sync>> {
+        self.tls_acceptor.clone()
+    }
+
+    /// Accept TLS connections with another TLS implementation. This is used instead of the security settings.
+    pub fn set_tls_acceptor(&mut self, tls_acceptor: Arc<TlsAcceptor + Send + Sync>) {
+        self.tls_acceptor = Some(tls_acceptor);
+    }
+
+    /// Whether the listener accepts TLS connections.
+    pub fn is_secure(&self) -> bool {
+        self.security.is_some() || self.tls_acceptor.is_some()
+    }
+
+    /// The address of a TCP listener.
+    pub fn get_socket_address(&self) -> Result<net::SocketAddr, SettingsError> {
+        match self.address {
+            ListenAddress::Tcp(ref host, port) => to_socket_address(host, port),
+            ListenAddress::Unix(_) => Err(SettingsError::InvalidBindAddress)
+        }
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        match self.address {
+            ListenAddress::Tcp(ref host, port) => {
+                to_socket_address(host, port)?;
+            },
+            ListenAddress::Unix(_) => {
+                if !cfg!(unix) {
+                    return Err(SettingsError::UnixSocketNotSupported);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
 
+#[derive(Clone)]
 pub struct ServerSettings {
     host: String,
     port: u16,
     security: Option<SecuritySettings>,
-    http2_settings: Option<Vec<http2_settings::SettingsParameter>>
+    tls_acceptor: Option<Arc<TlsAcceptor + Send + Sync>>,
+    http2_settings: Option<Vec<http2_settings::SettingsParameter>>,
+
+    worker_count: Option<usize>,
+    read_queue_depth: usize,
+    write_queue_depth: usize,
+    max_request_body_size: usize,
+    listen_backlog: u32,
+    tcp_nodelay: bool,
+    reuse_port: bool,
+
+    additional_listeners: Vec<ListenerSettings>,
+    certificate_watch_interval: Option<time::Duration>,
+
+    tls_handshake_timeout: Option<time::Duration>,
+    preface_timeout: Option<time::Duration>,
+    settings_acknowledge_timeout: Option<time::Duration>,
+    idle_timeout: Option<time::Duration>,
+    ping_interval: Option<time::Duration>,
+    max_missed_pings: usize
+}
+
+/// How the server's certificate and private key are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertificateFormat {
+    /// A PKCS #12 archive holding the key, the certificate and its chain, protected by a password.
+    Pkcs12,
+    /// A PEM file holding the certificate followed by its chain, and a separate PEM private key.
+    Pem,
+    /// A DER certificate, and a separate DER private key.
+    Der
+}
+
+/// Whether clients are asked for a certificate, which is verified against the trusted client CAs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientVerification {
+    /// Clients aren't asked for a certificate.
+    None,
+    /// Clients are asked for a certificate, but may connect without one. A certificate which is sent must be valid.
+    Optional,
+    /// Clients must send a valid certificate to connect.
+    Required
+}
+
+/// The preset TLS configurations, from Mozilla's server side TLS recommendations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlsPolicy {
+    /// Supports most clients, including older ones which HTTP/1.1 is served to.
+    Intermediate,
+    /// Only TLS 1.2 and above with strong cipher suites, for modern clients.
+    Modern
+}
+
+/// The TLS protocol versions which the lowest accepted version can be set to.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2
 }
 
 #[derive(Clone)]
 pub struct SecuritySettings {
+    certificate_format: CertificateFormat,
     ssl_cert_path: String,
-    ssl_cert_pass: String
+    ssl_cert_pass: String,
+    ssl_key_path: Option<String>,
+    server_name_certificates: Vec<(String, SecuritySettings)>,
+    client_verification: ClientVerification,
+    client_ca_path: Option<String>,
+    tls_policy: TlsPolicy,
+    min_tls_version: Option<TlsVersion>,
+    cipher_list: Option<String>,
+    enforce_http2_tls_requirements: bool
 }
 
 impl SecuritySettings {
     pub fn default() -> Self {
+        SecuritySettings::pkcs12(String::from("tests/cert.pfx"), String::from("asdf"))
+    }
+
+    pub fn pkcs12(ssl_cert_path: String, ssl_cert_pass: String) -> Self {
+        SecuritySettings {
+            certificate_format: CertificateFormat::Pkcs12,
+            ssl_cert_path: ssl_cert_path,
+            ssl_cert_pass: ssl_cert_pass,
+            ssl_key_path: None,
+            server_name_certificates: Vec::new(),
+            client_verification: ClientVerification::None,
+            client_ca_path: None,
+            tls_policy: TlsPolicy::Intermediate,
+            min_tls_version: None,
+            cipher_list: None,
+            enforce_http2_tls_requirements: true
+        }
+    }
+
+    /// The certificate file may contain the rest of the chain after the server's certificate. If the
+    /// private key is encrypted, its passphrase can be given with `set_ssl_cert_pass`.
+    pub fn pem(ssl_cert_path: String, ssl_key_path: String) -> Self {
+        SecuritySettings {
+            certificate_format: CertificateFormat::Pem,
+            ssl_cert_path: ssl_cert_path,
+            ssl_cert_pass: String::new(),
+            ssl_key_path: Some(ssl_key_path),
+            server_name_certificates: Vec::new(),
+            client_verification: ClientVerification::None,
+            client_ca_path: None,
+            tls_policy: TlsPolicy::Intermediate,
+            min_tls_version: None,
+            cipher_list: None,
+            enforce_http2_tls_requirements: true
+        }
+    }
+
+    pub fn der(ssl_cert_path: String, ssl_key_path: String) -> Self {
         SecuritySettings {
-            ssl_cert_path: String::from("tests/cert.pfx"),
-            ssl_cert_pass: String::from("asdf")
+            certificate_format: CertificateFormat::Der,
+            ssl_cert_path: ssl_cert_path,
+            ssl_cert_pass: String::new(),
+            ssl_key_path: Some(ssl_key_path),
+            server_name_certificates: Vec::new(),
+            client_verification: ClientVerification::None,
+            client_ca_path: None,
+            tls_policy: TlsPolicy::Intermediate,
+            min_tls_version: None,
+            cipher_list: None,
+            enforce_http2_tls_requirements: true
         }
     }
 
+    pub fn get_certificate_format(&self) -> &CertificateFormat {
+        &self.certificate_format
+    }
+
     pub fn get_ssl_cert_path(&self) -> &str {
         self.ssl_cert_path.as_ref()
     }
@@ -43,32 +280,218 @@ impl SecuritySettings {
         self.ssl_cert_path = ssl_cert_path;
     }
 
+    /// The password of a PKCS #12 archive, or the passphrase of an encrypted PEM private key.
     pub fn get_ssl_cert_pass(&self) -> &str {
         self.ssl_cert_pass.as_ref()
     }
+
+    pub fn set_ssl_cert_pass(&mut self, ssl_cert_pass: String) {
+        self.ssl_cert_pass = ssl_cert_pass;
+    }
+
+    /// The private key file, which PEM and DER certificates are stored separately from.
+    pub fn get_ssl_key_path(&self) -> Option<&str> {
+        self.ssl_key_path.as_ref().map(|path| path.as_ref())
+    }
+
+    pub fn set_ssl_key_path(&mut self, ssl_key_path: String) {
+        self.ssl_key_path = Some(ssl_key_path);
+    }
+
+    /// Use a different certificate for clients which ask for the server name using SNI. The server name
+    /// may start with a `*.` wildcard, which matches a single label. Clients asking for a server name
+    /// without its own certificate, or not sending one at all, are given this certificate.
+    ///
+    /// Only the certificate and private key are used from the settings for the server name.
+    pub fn add_server_name_certificate(&mut self, server_name: String, certificate: SecuritySettings) {
+        self.server_name_certificates.push((server_name, certificate));
+    }
+
+    pub fn get_server_name_certificates(&self) -> &[(String, SecuritySettings)] {
+        &self.server_name_certificates
+    }
+
+    /// Whether clients are asked for a certificate. Clients which send one are verified using the client CAs,
+    /// whichever certificate the server uses for them.
+    pub fn get_client_verification(&self) -> &ClientVerification {
+        &self.client_verification
+    }
+
+    pub fn set_client_verification(&mut self, client_verification: ClientVerification) {
+        self.client_verification = client_verification;
+    }
+
+    /// A PEM file with the certificates of the CAs which client certificates must be issued by.
+    pub fn get_client_ca_path(&self) -> Option<&str> {
+        self.client_ca_path.as_ref().map(|path| path.as_ref())
+    }
+
+    pub fn set_client_ca_path(&mut self, client_ca_path: String) {
+        self.client_ca_path = Some(client_ca_path);
+    }
+
+    /// The preset which the protocol versions and cipher suites start from. By default this is the intermediate
+    /// policy, so that clients which can't use HTTP/2 can still connect using HTTP/1.1.
+    pub fn get_tls_policy(&self) -> &TlsPolicy {
+        &self.tls_policy
+    }
+
+    pub fn set_tls_policy(&mut self, tls_policy: TlsPolicy) {
+        self.tls_policy = tls_policy;
+    }
+
+    /// The lowest TLS version which clients may connect with, if it should be higher than the policy allows.
+    pub fn get_min_tls_version(&self) -> Option<TlsVersion> {
+        self.min_tls_version
+    }
+
+    pub fn set_min_tls_version(&mut self, min_tls_version: TlsVersion) {
+        self.min_tls_version = Some(min_tls_version);
+    }
+
+    /// An OpenSSL cipher list, for example `ECDHE+AESGCM:ECDHE+CHACHA20`, which replaces the cipher suites from the policy.
+    pub fn get_cipher_list(&self) -> Option<&str> {
+        self.cipher_list.as_ref().map(|cipher_list| cipher_list.as_ref())
+    }
+
+    pub fn set_cipher_list(&mut self, cipher_list: String) {
+        self.cipher_list = Some(cipher_list);
+    }
+
+    /// Whether HTTP/2 connections must use TLS 1.2 or above and a cipher suite which isn't prohibited by RFC 7540
+    /// (9.2). A connection which doesn't is sent a GOAWAY with INADEQUATE_SECURITY. HTTP/1.1 connections aren't
+    /// affected. This is on by default.
+    pub fn is_enforce_http2_tls_requirements(&self) -> bool {
+        self.enforce_http2_tls_requirements
+    }
+
+    pub fn set_enforce_http2_tls_requirements(&mut self, enforce_http2_tls_requirements: bool) {
+        self.enforce_http2_tls_requirements = enforce_http2_tls_requirements;
+    }
 }
 
 impl ServerSettings {
     /// Create a default settings
     ///
-    /// By default the settings are to connect to localhost:8080 with no security.
+    /// By default the settings are to connect to localhost:8080 with no security. When no security 
+    /// settings are provided the server speaks cleartext HTTP/2 to clients with prior knowledge or
+    /// which upgrade, and HTTP/1.1 to everyone else.
+    ///
+    /// There is one worker for each CPU, each connection queues up to 5 items in each direction and
+    /// the listener queues up to 1024 connections. HTTP/1.1 request bodies can be up to 10 MiB.
+    ///
+    /// Clients have 10 seconds each to finish the TLS handshake, to send the connection preface and to
+    /// acknowledge the server's settings. Idle connections are kept open.
     pub fn default() -> Self {
         ServerSettings {
             host: String::from("0.0.0.0"),
             port: 8080,
             security: None,
-            http2_settings: None
+            tls_acceptor: None,
+            http2_settings: None,
+
+            worker_count: None,
+            read_queue_depth: 5,
+            write_queue_depth: 5,
+            max_request_body_size: 10 * 1024 * 1024,
+            listen_backlog: 1024,
+            tcp_nodelay: false,
+            reuse_port: false,
+
+            additional_listeners: Vec::new(),
+            certificate_watch_interval: None,
+
+            tls_handshake_timeout: Some(time::Duration::from_secs(10)),
+            preface_timeout: Some(time::Duration::from_secs(10)),
+            settings_acknowledge_timeout: Some(time::Duration::from_secs(10)),
+            idle_timeout: None,
+            ping_interval: None,
+            max_missed_pings: 3
+        }
+    }
+
+    /// Check that the settings can be used to start a server.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        for listener in self.get_listeners() {
+            listener.validate()?;
+        }
+
+        if self.worker_count == Some(0) {
+            return Err(SettingsError::ZeroWorkerCount);
+        }
+
+        if self.read_queue_depth == 0 || self.write_queue_depth == 0 {
+            return Err(SettingsError::ZeroQueueDepth);
         }
+
+        if self.listen_backlog == 0 {
+            return Err(SettingsError::ZeroListenBacklog);
+        }
+
+        if self.reuse_port && !cfg!(unix) {
+            return Err(SettingsError::ReusePortNotSupported);
+        }
+
+        if self.certificate_watch_interval == Some(time::Duration::from_secs(0)) {
+            return Err(SettingsError::ZeroCertificateWatchInterval);
+        }
+
+        let timeouts = [self.tls_handshake_timeout, self.preface_timeout, self.settings_acknowledge_timeout, self.idle_timeout];
+        if timeouts.iter().any(|timeout| *timeout == Some(time::Duration::from_secs(0))) {
+            return Err(SettingsError::ZeroTimeout);
+        }
+
+        if self.ping_interval == Some(time::Duration::from_secs(0)) {
+            return Err(SettingsError::ZeroPingInterval);
+        }
+
+        if self.max_missed_pings == 0 {
+            return Err(SettingsError::ZeroMaxMissedPings);
+        }
+
+        Ok(())
+    }
+
+    /// The address to listen on, built from the host and port.
+    pub fn get_bind_address(&self) -> Result<net::SocketAddr, SettingsError> {
+        to_socket_address(&self.host, self.port)
+    }
+
+    /// Accept connections on another address as well as the server's host and port. Every listener
+    /// serves the same application.
+    pub fn add_listener(&mut self, listener: ListenerSettings) {
+        self.additional_listeners.push(listener);
+    }
+
+    /// All of the listeners, starting with the one on the server's host and port.
+    pub fn get_listeners(&self) -> Vec<ListenerSettings> {
+        let mut listeners = vec![ListenerSettings {
+            address: ListenAddress::Tcp(self.host.clone(), self.port),
+            security: self.security.clone(),
+            tls_acceptor: self.tls_acceptor.clone()
+        }];
+
+        listeners.extend(self.additional_listeners.iter().cloned());
+
+        listeners
     }
 
     pub fn get_host(&self) -> &str {
         self.host.as_ref()
     }
 
+    pub fn set_host(&mut self, host: String) {
+        self.host = host;
+    }
+
     pub fn get_port(&self) -> u16 {
         self.port
     }
 
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
     pub fn get_security(&self) -> Option<SecuritySettings> {
         self.security.clone()
     }
@@ -77,6 +500,21 @@ impl ServerSettings {
         self.security = Some(security);
     }
 
+    /// Remove any security settings so that the server accepts cleartext connections.
+    pub fn clear_security(&mut self) {
+        self.security = None;
+        self.tls_acceptor = None;
+    }
+
+    pub fn get_tls_acceptor(&self) -> Option<Arc<TlsAcceptor + Send + Sync>> {
+        self.tls_acceptor.clone()
+    }
+
+    /// Accept TLS connections with another TLS implementation. This is used instead of the security settings.
+    pub fn set_tls_acceptor(&mut self, tls_acceptor: Arc<TlsAcceptor + Send + Sync>) {
+        self.tls_acceptor = Some(tls_acceptor);
+    }
+
     pub fn get_http2_settings(&self) -> &[http2_settings::SettingsParameter] {
         if let Some(ref http2_settings) = self.http2_settings {
             return http2_settings;
@@ -88,4 +526,295 @@ impl ServerSettings {
     pub fn set_http2_settings(&mut self, http2_settings: Vec<http2_settings::SettingsParameter>) {
         self.http2_settings = Some(http2_settings);
     }
+
+    /// The number of threads which process requests. If this isn't set there is one for each CPU.
+    pub fn get_worker_count(&self) -> Option<usize> {
+        self.worker_count
+    }
+
+    pub fn set_worker_count(&mut self, worker_count: usize) {
+        self.worker_count = Some(worker_count);
+    }
+
+    /// The number of items which have been read from a connection that can wait to be processed before
+    /// the server stops reading from it. For HTTP/2 these are frames, and for HTTP/1.1 requests.
+    pub fn get_read_queue_depth(&self) -> usize {
+        self.read_queue_depth
+    }
+
+    pub fn set_read_queue_depth(&mut self, read_queue_depth: usize) {
+        self.read_queue_depth = read_queue_depth;
+    }
+
+    /// The number of items which can wait to be written to a connection before the server stops
+    /// processing it.
+    pub fn get_write_queue_depth(&self) -> usize {
+        self.write_queue_depth
+    }
+
+    pub fn set_write_queue_depth(&mut self, write_queue_depth: usize) {
+        self.write_queue_depth = write_queue_depth;
+    }
+
+    /// The largest request body which the server will read. An HTTP/1.1 request with a larger body is answered
+    /// with 413 (Payload Too Large) and the connection is closed. An HTTP/2 stream is reset with CANCEL instead.
+    pub fn get_max_request_body_size(&self) -> usize {
+        self.max_request_body_size
+    }
+
+    pub fn set_max_request_body_size(&mut self, max_request_body_size: usize) {
+        self.max_request_body_size = max_request_body_size;
+    }
+
+    /// The number of connections which the operating system will queue waiting to be accepted.
+    pub fn get_listen_backlog(&self) -> u32 {
+        self.listen_backlog
+    }
+
+    pub fn set_listen_backlog(&mut self, listen_backlog: u32) {
+        self.listen_backlog = listen_backlog;
+    }
+
+    /// Whether TCP_NODELAY is set on accepted connections, so that small writes aren't delayed.
+    pub fn is_tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub fn set_tcp_nodelay(&mut self, tcp_nodelay: bool) {
+        self.tcp_nodelay = tcp_nodelay;
+    }
+
+    /// Whether SO_REUSEPORT is set on the listener, so that several processes can listen on the same port.
+    pub fn is_reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+
+    pub fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
+    }
+
+    /// How often the certificate files are checked for changes. Modified certificates are reloaded, and used
+    /// for new connections. If this isn't set the certificates are only reloaded when asked to.
+    pub fn get_certificate_watch_interval(&self) -> Option<time::Duration> {
+        self.certificate_watch_interval
+    }
+
+    pub fn set_certificate_watch_interval(&mut self, certificate_watch_interval: Option<time::Duration>) {
+        self.certificate_watch_interval = certificate_watch_interval;
+    }
+
+    /// How long a client has to finish the TLS handshake once its connection has been accepted. If this
+    /// isn't set the server waits as long as it takes.
+    pub fn get_tls_handshake_timeout(&self) -> Option<time::Duration> {
+        self.tls_handshake_timeout
+    }
+
+    pub fn set_tls_handshake_timeout(&mut self, tls_handshake_timeout: Option<time::Duration>) {
+        self.tls_handshake_timeout = tls_handshake_timeout;
+    }
+
+    /// How long a client has to start speaking once its connection is ready, either by sending the connection
+    /// preface and its settings or by sending the head of an HTTP/1.1 request. If this isn't set the server
+    /// waits as long as it takes.
+    pub fn get_preface_timeout(&self) -> Option<time::Duration> {
+        self.preface_timeout
+    }
+
+    pub fn set_preface_timeout(&mut self, preface_timeout: Option<time::Duration>) {
+        self.preface_timeout = preface_timeout;
+    }
+
+    /// How long an HTTP/2 client has to acknowledge the server's settings before the connection is closed
+    /// with SETTINGS_TIMEOUT (6.5.3). If this isn't set the server waits as long as it takes.
+    pub fn get_settings_acknowledge_timeout(&self) -> Option<time::Duration> {
+        self.settings_acknowledge_timeout
+    }
+
+    pub fn set_settings_acknowledge_timeout(&mut self, settings_acknowledge_timeout: Option<time::Duration>) {
+        self.settings_acknowledge_timeout = settings_acknowledge_timeout;
+    }
+
+    /// How long a connection may go without receiving anything while no requests are being processed. An HTTP/2
+    /// connection is then sent a GOAWAY and closed, and an HTTP/1.1 connection is closed. If this isn't set idle
+    /// connections are kept open.
+    pub fn get_idle_timeout(&self) -> Option<time::Duration> {
+        self.idle_timeout
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<time::Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// How often HTTP/2 connections which haven't received a frame since the last ping are pinged, to check that
+    /// the client is still there and to measure the round trip time. If this isn't set connections aren't pinged.
+    pub fn get_ping_interval(&self) -> Option<time::Duration> {
+        self.ping_interval
+    }
+
+    pub fn set_ping_interval(&mut self, ping_interval: Option<time::Duration>) {
+        self.ping_interval = ping_interval;
+    }
+
+    /// How many pings may be waiting for an acknowledgement when the next one is due. The connection is closed
+    /// with a GOAWAY instead of sending another. The default is 3.
+    pub fn get_max_missed_pings(&self) -> usize {
+        self.max_missed_pings
+    }
+
+    pub fn set_max_missed_pings(&mut self, max_missed_pings: usize) {
+        self.max_missed_pings = max_missed_pings;
+    }
+}
+
+// Accepts IPv6 hosts with or without brackets, so that "::" and "[::]" both work.
+fn to_socket_address(host: &str, port: u16) -> Result<net::SocketAddr, SettingsError> {
+    if let Ok(ip) = host.parse::<net::IpAddr>() {
+        return Ok(net::SocketAddr::new(ip, port));
+    }
+
+    format!("{}:{}", host, port).parse().map_err(|_| SettingsError::InvalidBindAddress)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{ServerSettings, ListenerSettings, SettingsError};
+
+    #[test]
+    pub fn default_settings_are_valid() {
+        assert!(ServerSettings::default().validate().is_ok());
+    }
+
+    #[test]
+    pub fn reject_invalid_host() {
+        let mut settings = ServerSettings::default();
+        settings.set_host(String::from("not a host"));
+
+        match settings.validate() {
+            Err(SettingsError::InvalidBindAddress) => {},
+            r => panic!("Expected an invalid bind address but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_worker_count() {
+        let mut settings = ServerSettings::default();
+        settings.set_worker_count(0);
+
+        match settings.validate() {
+            Err(SettingsError::ZeroWorkerCount) => {},
+            r => panic!("Expected zero worker count but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_queue_depth() {
+        let mut settings = ServerSettings::default();
+        settings.set_write_queue_depth(0);
+
+        match settings.validate() {
+            Err(SettingsError::ZeroQueueDepth) => {},
+            r => panic!("Expected zero queue depth but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_listen_backlog() {
+        let mut settings = ServerSettings::default();
+        settings.set_listen_backlog(0);
+
+        match settings.validate() {
+            Err(SettingsError::ZeroListenBacklog) => {},
+            r => panic!("Expected zero listen backlog but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_certificate_watch_interval() {
+        let mut settings = ServerSettings::default();
+        settings.set_certificate_watch_interval(Some(time::Duration::from_secs(0)));
+
+        match settings.validate() {
+            Err(SettingsError::ZeroCertificateWatchInterval) => {},
+            r => panic!("Expected zero certificate watch interval but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_timeout() {
+        let mut settings = ServerSettings::default();
+        settings.set_idle_timeout(Some(time::Duration::from_secs(0)));
+
+        match settings.validate() {
+            Err(SettingsError::ZeroTimeout) => {},
+            r => panic!("Expected zero timeout but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_ping_interval() {
+        let mut settings = ServerSettings::default();
+        settings.set_ping_interval(Some(time::Duration::from_secs(0)));
+
+        match settings.validate() {
+            Err(SettingsError::ZeroPingInterval) => {},
+            r => panic!("Expected zero ping interval but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn reject_zero_max_missed_pings() {
+        let mut settings = ServerSettings::default();
+        settings.set_ping_interval(Some(time::Duration::from_secs(30)));
+        settings.set_max_missed_pings(0);
+
+        match settings.validate() {
+            Err(SettingsError::ZeroMaxMissedPings) => {},
+            r => panic!("Expected zero max missed pings but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn accept_disabled_timeouts() {
+        let mut settings = ServerSettings::default();
+        settings.set_tls_handshake_timeout(None);
+        settings.set_preface_timeout(None);
+        settings.set_settings_acknowledge_timeout(None);
+        settings.set_ping_interval(None);
+        settings.set_certificate_watch_interval(None);
+
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    pub fn accept_ipv6_listener() {
+        let mut settings = ServerSettings::default();
+        settings.add_listener(ListenerSettings::tcp(String::from("::1"), 8443));
+        settings.add_listener(ListenerSettings::tcp(String::from("[::1]"), 8444));
+
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    pub fn reject_invalid_additional_listener() {
+        let mut settings = ServerSettings::default();
+        settings.add_listener(ListenerSettings::tcp(String::from("not a host"), 8443));
+
+        match settings.validate() {
+            Err(SettingsError::InvalidBindAddress) => {},
+            r => panic!("Expected an invalid bind address but got {:?}", r)
+        }
+    }
+
+    #[test]
+    pub fn listeners_start_with_host_and_port() {
+        let mut settings = ServerSettings::default();
+        settings.add_listener(ListenerSettings::unix("/tmp/osmium.sock"));
+
+        let listeners = settings.get_listeners();
+        assert_eq!(2, listeners.len());
+        assert_eq!("Tcp(\"0.0.0.0\", 8080)", format!("{:?}", listeners[0].get_address()));
+        assert_eq!("Unix(\"/tmp/osmium.sock\")", format!("{:?}", listeners[1].get_address()));
+    }
 }
@@ -15,6 +15,13 @@
 // You should have received a copy of the GNU General Public License
 // along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
 
+// std
+use std::marker;
+
+// tokio
+use futures::future::{self, Future};
+
+// osmium
 // TODO move this trait
 use shared::connection_handle::ConnectionHandle;
 
@@ -24,3 +31,78 @@ pub trait OsmiumServer {
 
     fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> Self::Response;
 }
+
+/// The future which an `AsyncOsmiumServer` produces a response with.
+///
+/// A future which fails is answered with an internal error, so applications which want to tell the client
+/// what went wrong should resolve to an error response instead.
+pub type ResponseFuture<S> = Box<Future<Item=S, Error=()> + marker::Send>;
+
+/// An application which produces its responses asynchronously.
+///
+/// `process` is called on one of the server's workers, and the returned future is driven on the same pool of
+/// workers. Work which waits on other services should be done in the future rather than by blocking, so that
+/// the workers stay free to process other requests while it waits.
+///
+/// The connection handle can only be used during the call to `process`. Any push promises must be created 
+/// before the future is returned.
+pub trait AsyncOsmiumServer {
+    type Request;
+    type Response;
+
+    fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> ResponseFuture<Self::Response>;
+}
+
+// Every synchronous application is also an asynchronous one whose responses are ready immediately.
+impl<T> AsyncOsmiumServer for T
+    where T: OsmiumServer,
+          T::Response: 'static + marker::Send
+{
+    type Request = T::Request;
+    type Response = T::Response;
+
+    fn process(&self, request: Self::Request, handle: Box<&mut ConnectionHandle>) -> ResponseFuture<Self::Response> {
+        Box::new(future::ok(OsmiumServer::process(self, request, handle)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::{OsmiumServer, AsyncOsmiumServer};
+    use shared::connection_handle::ConnectionHandle;
+    use shared::push_error;
+    use shared::request::Request;
+
+    struct EchoServer;
+
+    impl OsmiumServer for EchoServer {
+        type Request = String;
+        type Response = String;
+
+        fn process(&self, request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
+            request
+        }
+    }
+
+    struct NoPushHandle;
+
+    impl ConnectionHandle for NoPushHandle {
+        fn is_push_enabled(&self) -> bool {
+            false
+        }
+
+        fn push_promise(&mut self, _request: Request) -> Option<push_error::PushError> {
+            None
+        }
+    }
+
+    #[test]
+    pub fn sync_server_responds_immediately() {
+        let mut handle = NoPushHandle;
+        let response_future = AsyncOsmiumServer::process(&EchoServer, String::from("hello"), Box::new(&mut handle));
+
+        assert_eq!(Ok(String::from("hello")), response_future.wait());
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium. If not, see <http://www.gnu.org/licenses/>.
+
+// osmium
+use http2::error;
+
+/// Error enumeration for relaying errors which occur when the application tries to
+/// change the HTTP/2 settings of its connection.
+#[derive(Debug)]
+pub enum SettingsUpdateError {
+    /// One of the changes has a value which the client would have to treat as a connection error (6.5.2).
+    InvalidSettings(error::HttpError),
+    /// The connection doesn't use HTTP/2, so it doesn't have settings which can be changed.
+    NotSupported,
+    /// The connection closed before it could send the changes to the client.
+    ConnectionClosed
+}
@@ -0,0 +1,280 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::cell::RefCell;
+use std::io;
+use std::time;
+
+// tokio
+use futures::{Future, Stream};
+use futures::future;
+use futures::sync::mpsc as futures_mpsc;
+use tokio_core::reactor;
+
+/// Asks a running server to shut down gracefully. The handle can be cloned and sent to other threads, for
+/// example to a signal handler.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown_tx: futures_mpsc::UnboundedSender<time::Duration>
+}
+
+impl ShutdownHandle {
+    /// Stop accepting connections and ask every open connection to close once it has responded to the
+    /// requests it has already received. HTTP/2 clients are sent a GOAWAY. The server stops once every
+    /// connection has closed, or once the deadline has passed, whichever comes first.
+    pub fn shutdown(&self, deadline: time::Duration) {
+        if self.shutdown_tx.unbounded_send(deadline).is_err() {
+            debug!("Shutdown requested but the server has already stopped");
+        }
+    }
+}
+
+/// Receives the shutdown request for a server.
+pub struct ShutdownReceiver {
+    shutdown_rx: futures_mpsc::UnboundedReceiver<time::Duration>
+}
+
+pub fn new_shutdown_channel() -> (ShutdownHandle, ShutdownReceiver) {
+    let (shutdown_tx, shutdown_rx) = futures_mpsc::unbounded();
+
+    (
+        ShutdownHandle {
+            shutdown_tx: shutdown_tx
+        },
+        ShutdownReceiver {
+            shutdown_rx: shutdown_rx
+        }
+    )
+}
+
+/// A connection which the server is keeping track of.
+pub struct TrackedConnection {
+    /// Receives a message when the connection should start to close.
+    pub close_rx: futures_mpsc::UnboundedReceiver<()>,
+    /// Must be held until the connection has been completely closed.
+    pub guard: ConnectionGuard
+}
+
+pub struct ConnectionGuard {
+    _closed_tx: futures_mpsc::Sender<()>
+}
+
+/// Keeps track of the connections which are open on the event loop, so that they can be asked to close
+/// and waited for when the server shuts down.
+pub struct OpenConnections {
+    close_signals: RefCell<Vec<futures_mpsc::UnboundedSender<()>>>,
+    closed_tx: RefCell<Option<futures_mpsc::Sender<()>>>,
+    closed_rx: RefCell<Option<futures_mpsc::Receiver<()>>>
+}
+
+impl OpenConnections {
+    pub fn new() -> Self {
+        // Nothing is ever sent on this channel. The receiver ends once every guard has been dropped.
+        let (closed_tx, closed_rx) = futures_mpsc::channel(0);
+
+        OpenConnections {
+            close_signals: RefCell::new(Vec::new()),
+            closed_tx: RefCell::new(Some(closed_tx)),
+            closed_rx: RefCell::new(Some(closed_rx))
+        }
+    }
+
+    pub fn track(&self) -> TrackedConnection {
+        let (close_tx, close_rx) = futures_mpsc::unbounded();
+
+        let mut close_signals = self.close_signals.borrow_mut();
+        // Forget the connections which have already closed.
+        close_signals.retain(|close_tx| !close_tx.is_closed());
+        close_signals.push(close_tx);
+
+        TrackedConnection {
+            close_rx: close_rx,
+            guard: ConnectionGuard {
+                _closed_tx: self.closed_tx.borrow().as_ref().expect("connections can't be tracked after shutdown").clone()
+            }
+        }
+    }
+
+    fn close_all(&self) {
+        for close_tx in self.close_signals.borrow_mut().drain(..) {
+            // The connection may have closed since it was last checked.
+            let _ = close_tx.unbounded_send(());
+        }
+
+        self.closed_tx.borrow_mut().take();
+    }
+}
+
+/// Run the event loop, accepting connections, until the server is asked to shut down. On shutdown, the accept future
+/// is dropped so that no more connections are accepted. Then the open connections are asked to close and the event
+/// loop runs until they have, or until the deadline passes. If the accept future ends first, the event loop runs
+/// until the open connections have closed by themselves, unless the server is shut down in the meantime.
+pub fn run_until_shutdown<F>(event_loop: &mut reactor::Core, accept: F, shutdown_receiver: ShutdownReceiver, open_connections: &OpenConnections)
+    where F: Future<Item=(), Error=io::Error>
+{
+    let closed_rx = open_connections.closed_rx.borrow_mut().take().expect("the server can only be run once");
+    let all_closed = closed_rx.for_each(|_| Ok(()));
+
+    let shutdown_rx = match event_loop.run(accept.select2(shutdown_receiver.shutdown_rx.into_future())) {
+        Ok(future::Either::A((_, shutdown_rx))) => {
+            info!("The server has stopped accepting connections");
+            shutdown_rx
+        },
+        Ok(future::Either::B(((Some(deadline), _), _))) => {
+            close_connections(event_loop, all_closed, deadline, open_connections);
+            return;
+        },
+        Ok(future::Either::B(((None, _), _))) => {
+            // Every shutdown handle has been dropped, so the server can only stop when the accept future does.
+            unreachable!("the server holds a shutdown handle")
+        },
+        Err(future::Either::A((e, shutdown_rx))) => {
+            error!("The server has stopped accepting connections [{}]", e);
+            shutdown_rx
+        },
+        Err(future::Either::B(_)) => {
+            unreachable!("an unbounded receiver can't fail")
+        }
+    };
+
+    // No more connections will be tracked, so the receiver ends once the guards of the open connections have been dropped.
+    open_connections.closed_tx.borrow_mut().take();
+
+    match event_loop.run(all_closed.select2(shutdown_rx)) {
+        Ok(future::Either::A(_)) => {
+            info!("All connections have closed");
+        },
+        Ok(future::Either::B(((Some(deadline), _), all_closed))) => {
+            close_connections(event_loop, all_closed, deadline, open_connections);
+        },
+        Ok(future::Either::B(((None, _), _))) => {
+            unreachable!("the server holds a shutdown handle")
+        },
+        Err(_) => {
+            error!("Error while waiting for connections to close, they will be dropped");
+        }
+    }
+}
+
+// Ask the open connections to close and run the event loop until they have, or until the deadline passes.
+fn close_connections<C>(event_loop: &mut reactor::Core, all_closed: C, deadline: time::Duration, open_connections: &OpenConnections)
+    where C: Future<Item=(), Error=()>
+{
+    info!("Shutting down, waiting up to {:?} for the open connections to close", deadline);
+    open_connections.close_all();
+
+    let timeout = match reactor::Timeout::new(deadline, &event_loop.handle()) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            error!("Failed to start the shutdown deadline, connections will not be waited for [{}]", e);
+            return;
+        }
+    };
+
+    match event_loop.run(all_closed.select2(timeout)) {
+        Ok(future::Either::A(_)) => {
+            info!("All connections have closed");
+        },
+        Ok(future::Either::B(_)) => {
+            warn!("The shutdown deadline passed with connections still open, they will be dropped");
+        },
+        Err(_) => {
+            error!("Error while waiting for connections to close, they will be dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time;
+
+    use futures::{future, Future, Stream};
+    use tokio_core::reactor;
+
+    use super::{new_shutdown_channel, run_until_shutdown, OpenConnections};
+
+    #[test]
+    pub fn shutdown_waits_for_connections_to_close() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let (shutdown_handle, shutdown_receiver) = new_shutdown_channel();
+        let open_connections = OpenConnections::new();
+
+        // The connection closes as soon as it's asked to.
+        let tracked_connection = open_connections.track();
+        let guard = tracked_connection.guard;
+        event_loop.handle().spawn(tracked_connection.close_rx.into_future().then(move |_| {
+            drop(guard);
+            Ok(())
+        }));
+
+        shutdown_handle.shutdown(time::Duration::from_secs(60));
+
+        let start = time::Instant::now();
+        run_until_shutdown(&mut event_loop, future::empty::<(), io::Error>(), shutdown_receiver, &open_connections);
+        assert!(start.elapsed() < time::Duration::from_secs(60));
+    }
+
+    #[test]
+    pub fn shutdown_stops_at_deadline() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let (shutdown_handle, shutdown_receiver) = new_shutdown_channel();
+        let open_connections = OpenConnections::new();
+
+        // This connection never closes.
+        let tracked_connection = open_connections.track();
+
+        shutdown_handle.shutdown(time::Duration::from_millis(50));
+
+        let start = time::Instant::now();
+        run_until_shutdown(&mut event_loop, future::empty::<(), io::Error>(), shutdown_receiver, &open_connections);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= time::Duration::from_millis(50));
+        assert!(elapsed < time::Duration::from_secs(10));
+
+        // The connection was asked to close, but it's still open because its guard hasn't been dropped.
+        let close_requests: Vec<_> = tracked_connection.close_rx.wait().collect();
+        assert_eq!(vec![Ok(())], close_requests);
+        drop(tracked_connection.guard);
+    }
+
+    #[test]
+    pub fn stopped_accepting_waits_for_connections_to_close() {
+        let mut event_loop = reactor::Core::new().unwrap();
+        let (_shutdown_handle, shutdown_receiver) = new_shutdown_channel();
+        let open_connections = OpenConnections::new();
+
+        // The connection closes by itself a little while after the server has stopped accepting.
+        let tracked_connection = open_connections.track();
+        let guard = tracked_connection.guard;
+        let timeout = reactor::Timeout::new(time::Duration::from_millis(50), &event_loop.handle()).unwrap();
+        event_loop.handle().spawn(timeout.then(move |_| {
+            drop(guard);
+            Ok(())
+        }));
+
+        let start = time::Instant::now();
+        run_until_shutdown(&mut event_loop, future::ok::<(), io::Error>(()), shutdown_receiver, &open_connections);
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+
+        // The connection wasn't asked to close.
+        drop(open_connections);
+        let close_requests: Vec<_> = tracked_connection.close_rx.wait().collect();
+        assert!(close_requests.is_empty());
+    }
+}
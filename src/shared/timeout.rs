@@ -0,0 +1,107 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::io;
+use std::time::Duration;
+
+// tokio
+use futures::future::{self, Future, Either};
+use tokio_core::reactor;
+
+/// A limit on how long something may take, which is enforced by the event loop.
+#[derive(Clone)]
+pub struct Deadline {
+    timeout: Duration,
+    handle: reactor::Handle
+}
+
+impl Deadline {
+    pub fn new(timeout: Duration, handle: reactor::Handle) -> Self {
+        Deadline {
+            timeout: timeout,
+            handle: handle
+        }
+    }
+
+    /// Start a timeout which fires when the deadline passes, for limits which aren't on a single future.
+    pub fn start(&self) -> io::Result<reactor::Timeout> {
+        reactor::Timeout::new(self.timeout, &self.handle)
+    }
+
+    /// Fail with `TimedOut` if the future hasn't finished in time. The future is dropped when that happens,
+    /// which closes the connection it was using.
+    pub fn limit<F>(&self, future: F, description: &'static str) -> Box<Future<Item = F::Item, Error = io::Error>>
+        where F: 'static + Future<Error = io::Error>
+    {
+        let timeout = match self.start() {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(e))
+        };
+
+        Box::new(
+            future.select2(timeout).then(move |result| {
+                match result {
+                    Ok(Either::A((item, _))) => Ok(item),
+                    Ok(Either::B(_)) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for the {}", description))),
+                    Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e)
+                }
+            })
+        )
+    }
+}
+
+/// Apply a deadline if there is one, otherwise the future may take as long as it needs.
+pub fn limit<F>(deadline: Option<&Deadline>, future: F, description: &'static str) -> Box<Future<Item = F::Item, Error = io::Error>>
+    where F: 'static + Future<Error = io::Error>
+{
+    match deadline {
+        Some(deadline) => deadline.limit(future, description),
+        None => Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadline;
+
+    use std::io;
+    use std::time::Duration;
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    pub fn future_finishes_before_deadline() {
+        let mut core = Core::new().unwrap();
+        let deadline = Deadline::new(Duration::from_secs(10), core.handle());
+
+        let result = core.run(deadline.limit(future::ok::<_, io::Error>(5), "test"));
+
+        assert_eq!(5, result.unwrap());
+    }
+
+    #[test]
+    pub fn future_misses_deadline() {
+        let mut core = Core::new().unwrap();
+        let deadline = Deadline::new(Duration::from_millis(10), core.handle());
+
+        let result = core.run(deadline.limit(future::empty::<(), io::Error>(), "test"));
+
+        assert_eq!(io::ErrorKind::TimedOut, result.unwrap_err().kind());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2017 ThetaSinner
+//
+// This file is part of Osmium.
+
+// Osmium is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Osmium is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Osmium.  If not, see <http://www.gnu.org/licenses/>.
+
+// std
+use std::io;
+
+// tokio
+use futures::Future;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+// osmium
+use http2::error;
+use shared::connection_info::ConnectionInfo;
+use shared::listener;
+
+/// A connection which the handshakes and the connections can read from and write to.
+///
+/// The connections only need to read and write, so anything which can do that will work. That includes
+/// the sockets accepted by the listeners, the streams a `TlsAcceptor` produces from them and in-memory
+/// streams for testing. The defaults describe a cleartext connection.
+pub trait Transport: AsyncRead + AsyncWrite {
+    /// Whether the connection is encrypted, which decides the scheme of HTTP/1.1 requests.
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    /// The protocol which was negotiated with ALPN, if any.
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Describe the connection once the handshake has completed.
+    fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo::new()
+    }
+
+    /// Whether the connection fails the TLS requirements for HTTP/2 (9.2). Cleartext connections don't have any.
+    fn http2_security_error(&self) -> Option<error::ErrorName> {
+        None
+    }
+}
+
+impl Transport for listener::Socket {}
+
+/// Performs the server side of a TLS handshake, so that a TLS implementation can be chosen for a listener.
+///
+/// The ALPN protocols `h2` and `http/1.1` should be offered to clients, so that they can choose which
+/// one to speak. If neither is negotiated the server looks for the connection preface instead.
+pub trait TlsAcceptor {
+    fn accept(&self, transport: Box<Transport>) -> Box<Future<Item = Box<Transport>, Error = io::Error>>;
+}
+
+/// A transport which reads from a buffer and records what is written to it, for testing a server without
+/// a network. See `Server::serve_transport`.
+pub mod memory {
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
+
+    use futures::Poll;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    use super::Transport;
+
+    /// Once everything in the input has been read, the transport reads as if the client has closed its side of
+    /// the connection. The server then finishes responding to the requests it has received and closes the connection.
+    pub struct MemoryTransport {
+        input: Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+        alpn_protocol: Option<Vec<u8>>
+    }
+
+    impl MemoryTransport {
+        /// Yields the transport and the bytes which have been written to it.
+        pub fn new(input: Vec<u8>) -> (Self, Rc<RefCell<Vec<u8>>>) {
+            let output = Rc::new(RefCell::new(Vec::new()));
+
+            (MemoryTransport { input: Cursor::new(input), output: output.clone(), alpn_protocol: None }, output)
+        }
+
+        /// Pretend that a TLS handshake negotiated a protocol.
+        pub fn set_alpn_protocol(&mut self, alpn_protocol: &[u8]) {
+            self.alpn_protocol = Some(alpn_protocol.to_vec());
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn is_secure(&self) -> bool {
+            self.alpn_protocol.is_some()
+        }
+
+        fn alpn_protocol(&self) -> Option<Vec<u8>> {
+            self.alpn_protocol.clone()
+        }
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MemoryTransport {}
+
+    impl AsyncWrite for MemoryTransport {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+}
@@ -18,114 +18,116 @@
 extern crate osmium;
 #[macro_use] extern crate log;
 extern crate curl;
-extern crate rustc_version;
 
 // std
-// use std::{thread, time};
-use std::env;
+use std::net;
+use std::time;
 
 // curl
-// use curl::easy::Easy;
-
-#[allow(dead_code)]
-fn get_server_port_range_start() -> i32 {
-    match env::var("SERVER_PORT_RANGE_START") {
-        Ok(val) => val.parse::<i32>().unwrap(),
-        Err(e) => {
-            warn!("port range env var not set, falling back. {}", e);
-            match rustc_version::version_meta() {
-                Ok(meta) => {
-                    match meta.channel {
-                        rustc_version::Channel::Stable => {
-                            5000
-                        },
-                        rustc_version::Channel::Beta => {
-                            6000
-                        },
-                        rustc_version::Channel::Nightly => {
-                            7000
-                        },
-                        _ => {
-                            info!("Unhandled rust channel, using default port");
-                            8000
-                        }
-                    }
-                },
-                Err(_) => {
-                    warn!("Server port range start is not set and cannot be determined from build channel. Using default");
-                    8000
-                }
-            }
-        }
+use curl::easy::Easy;
+
+// osmium
+use osmium::http::server;
+use osmium::http2::header;
+use osmium::shared::{self, server_trait, server_settings};
+use osmium::shared::connection_handle::ConnectionHandle;
+
+// Makes a GET request for / with curl, and yields the status line, headers and body of the response.
+fn get(port: u16) -> Vec<u8> {
+    let mut response = Vec::new();
+    {
+        let mut handle = Easy::new();
+
+        handle.url(format!("http://127.0.0.1:{}", port).as_str()).unwrap();
+        handle.show_header(true).unwrap();
+        let mut transfer = handle.transfer();
+        transfer.write_function(|new_data| {
+            trace!("Reading response line: [{:?}]", new_data);
+            response.extend_from_slice(new_data);
+            Ok(new_data.len())
+        }).unwrap();
+        debug!("Making a request to the server");
+
+        // The server is already listening, so the request doesn't need to be retried.
+        transfer.perform().unwrap();
     }
+
+    response
+}
+
+struct MyServer;
+
+impl server_trait::OsmiumServer for MyServer {
+    type Request = shared::Request;
+    type Response = shared::Response;
+
+    fn process(&self, request: Self::Request, _handle: Box<&mut ConnectionHandle>) -> Self::Response {
+        debug!("Responding to request: {:?}", request);
+
+        let mut response = shared::Response::new(200);
+        response.get_headers_mut().push(header::HeaderName::CustomHeader("Server".to_owned()), header::HeaderValue::Str("Osmium".to_owned()));
+        response.get_headers_mut().push(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
+
+        response
+    }
+}
+
+fn local_settings() -> server_settings::ServerSettings {
+    let mut settings = server_settings::ServerSettings::default();
+    settings.set_host(String::from("127.0.0.1"));
+    // Let the operating system choose a free port.
+    settings.set_port(0);
+    settings
+}
+
+#[test]
+fn empty_request() {
+    debug!("Starting a server");
+
+    let server_handle = server::Server::new(MyServer, local_settings()).unwrap().spawn_server().unwrap();
+    let port = server_handle.local_addr().port();
+
+    let response = get(port);
+
+    server_handle.stop(time::Duration::from_secs(5)).unwrap();
+
+    assert_eq!(response.len(), 54);
+
+    let response_text = String::from_utf8(response).unwrap();
+    assert!(response_text.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response_text.contains("\r\nServer: Osmium"));
+    assert!(response_text.contains("\r\nContent-Length: 0"));
+    assert!(response_text.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn idle_connections_do_not_hold_workers() {
+    let mut settings = local_settings();
+    settings.set_worker_count(1);
+
+    let server_handle = server::Server::new(MyServer, settings).unwrap().spawn_server().unwrap();
+    let port = server_handle.local_addr().port();
+
+    // More connections than there are workers, none of which send anything.
+    let idle_connections: Vec<_> = (0..3).map(|_| net::TcpStream::connect(("127.0.0.1", port)).unwrap()).collect();
+
+    let response = get(port);
+
+    drop(idle_connections);
+    server_handle.stop(time::Duration::from_secs(5)).unwrap();
+
+    assert!(String::from_utf8(response).unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
 }
 
-// #[test]
-// fn empty_request() {
-//     let port = get_server_port_range_start() + 1;
-
-//     thread::spawn(move || {
-//         debug!("Starting a server");
-
-//         struct MyHandler;
-
-//         impl handler::Handler for MyHandler {
-//             fn process(&self, req: request::Request) -> response::Response {
-//                 debug!("Responding to request: {:?}", req);
-
-//                 let mut headers = header::Headers::new();
-//                 headers.add(header::HeaderName::CustomHeader("Server".to_owned()), header::HeaderValue::Str("Osmium".to_owned()));
-//                 headers.add(header::HeaderName::ContentLength, header::HeaderValue::Num(0));
-
-//                 response::Response {
-//                     version: req.version,
-//                     status: status::HttpStatus::Ok,
-//                     headers: headers,
-//                     body: None
-//                 }
-//             }
-//         }
-
-//         let settings = server::Settings {
-//             host: Some("0.0.0.0".to_owned()),
-//             port: Some(port)
-//         };
-
-//         server::run(MyHandler, Some(settings));
-//     });
-
-//     let mut response = Vec::new();
-//     {
-//         let mut handle = Easy::new();   
-
-//         handle.url(format!("http://localhost:{}", port).as_str()).unwrap();
-//         handle.show_header(true).unwrap();
-//         let mut transfer = handle.transfer();
-//         transfer.write_function(|new_data| {
-//             trace!("Reading response line: [{:?}]", new_data);
-//             response.extend_from_slice(new_data);
-//             Ok(new_data.len())
-//         }).unwrap();
-//         debug!("Making a request to the server");
-
-//         for _ in 1..3 {
-//             match transfer.perform() {
-//                 Ok(()) => {
-//                     break;
-//                 },
-//                 Err(e) => {
-//                     error!("Oops, the request failed. [{}]", e);
-//                     thread::sleep(time::Duration::from_secs(3));
-//                 }
-//             }
-//         }
-//     }
-
-//     assert_eq!(response.len(), 54);
-
-//     let response_text = String::from_utf8(response).unwrap();
-//     assert!(response_text.starts_with("HTTP/1.1 200 OK\r\n"));
-//     assert!(response_text.contains("\r\nServer: Osmium"));
-//     assert!(response_text.contains("\r\nContent-Length: 0"));
-//     assert!(response_text.ends_with("\r\n\r\n"));
-// }
+#[test]
+fn start_on_port_in_use() {
+    let in_use = net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut settings = local_settings();
+    settings.set_port(in_use.local_addr().unwrap().port());
+
+    match server::Server::new(MyServer, settings).unwrap().start_server() {
+        Err(server::ServerError::StartFailed(_)) => {},
+        _ => panic!("expected the server to fail to start")
+    }
+}